@@ -1,5 +1,10 @@
 use nalgebra::DMatrix;
 use serde::{Serialize, Deserialize};
+use std::fmt;
+use std::str::FromStr;
+use crate::layer::{Layer, LayerGradients};
+use crate::scalar::Real;
+use crate::serialization::SerializableLayer;
 
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum ActivationFunction {
@@ -7,14 +12,22 @@ pub enum ActivationFunction {
     Sigmoid,
     ReLU,
     Softmax,
+    // z for z > 0, alpha * (exp(z) - 1) otherwise, ELU { alpha: DEFAULT_ELU_ALPHA } is the usual choice
+    ELU { alpha: f32 },
 }
 
+pub const DEFAULT_ELU_ALPHA: f32 = 1.0;
+
 impl ActivationFunction {
-    pub fn activate(&self, z: &DMatrix<f32>) -> DMatrix<f32> {
+    pub fn activate<T: Real>(&self, z: &DMatrix<T>) -> DMatrix<T> {
         match self {
             ActivationFunction::Linear => z.clone(),
-            ActivationFunction::Sigmoid => z.map(|val| 1.0 / (1.0 + (-val).exp())),
-            ActivationFunction::ReLU => z.map(|val| val.max(0.0)),
+            ActivationFunction::Sigmoid => z.map(|val| T::one() / (T::one() + (-val).exp())),
+            ActivationFunction::ReLU => z.map(|val| val.max(T::zero())),
+            ActivationFunction::ELU { alpha } => {
+                let alpha = T::cast(*alpha as f64);
+                z.map(|val| if val > T::zero() { val } else { alpha * (val.exp() - T::one()) })
+            }
             ActivationFunction::Softmax => {
                 let max_val = z.max();
                 let exp_z = z.map(|val| (val - max_val).exp());
@@ -37,19 +50,227 @@ impl ActivationFunction {
     }
 
     // Derivative of activation function w.r.t. its input z
-    pub fn derivative(&self, z: &DMatrix<f32>) -> DMatrix<f32> {
+    pub fn derivative<T: Real>(&self, z: &DMatrix<T>) -> DMatrix<T> {
         match self {
-            ActivationFunction::Linear => DMatrix::from_element(z.nrows(), z.ncols(), 1.0),
+            ActivationFunction::Linear => DMatrix::from_element(z.nrows(), z.ncols(), T::one()),
             ActivationFunction::Sigmoid => {
                 let s = self.activate(z);
-                s.component_mul(&s.map(|val| 1.0 - val))
+                s.component_mul(&s.map(|val| T::one() - val))
+            }
+            ActivationFunction::ReLU => z.map(|val| if val > T::zero() { T::one() } else { T::zero() }),
+            // alpha * exp(z) = activate(z) + alpha below zero, so both sides meet at 1 when alpha = 1
+            ActivationFunction::ELU { alpha } => {
+                let alpha = T::cast(*alpha as f64);
+                z.map(|val| if val > T::zero() { T::one() } else { alpha * val.exp() })
             }
-            ActivationFunction::ReLU => z.map(|val| if val > 0.0 { 1.0 } else { 0.0 }),
             ActivationFunction::Softmax => {
-                // This is simplified: derivative of softmax_i w.r.t z_i is p_i * (1 - p_i)
+                // This is simplified: derivative of softmax_i w.r.t z_i is p_i * (1 - p_i).
+                // Only the diagonal of the Jacobian, backprop goes through jacobian_vector_product instead.
+                let p = self.activate(z);
+                p.component_mul(&p.map(|val| T::one() - val))
+            }
+        }
+    }
+
+    // dError/dZ from the upstream gradient dError/dA, one sample per row.
+    // Elementwise activations just multiply by the derivative. Softmax mixes the whole row, so its full
+    // Jacobian is applied per row instead: (diag(p) - p p^T) g = p * (g - p.g)
+    pub fn jacobian_vector_product<T: Real>(&self, upstream_gradient: &DMatrix<T>, z: &DMatrix<T>) -> DMatrix<T> {
+        match self {
+            ActivationFunction::Softmax => {
                 let p = self.activate(z);
-                p.component_mul(&p.map(|val| 1.0 - val))
+                let mut product = p.component_mul(upstream_gradient);
+                for (mut product_row, p_row) in product.row_iter_mut().zip(p.row_iter()) {
+                    let p_dot_g = product_row.sum();
+                    product_row -= p_row * p_dot_g;
+                }
+                product
             }
+            _ => upstream_gradient.component_mul(&self.derivative(z)),
         }
     }
-}
\ No newline at end of file
+
+    // Full Jacobian of Softmax for a single sample: J[i][j] = dp_i/dz_j = diag(p) - p p^T
+    // z_row holds the n logits of one sample (1xn or nx1), the result is nxn.
+    pub fn softmax_jacobian<T: Real>(z_row: &DMatrix<T>) -> DMatrix<T> {
+        assert!(z_row.nrows() == 1 || z_row.ncols() == 1, "softmax_jacobian expects a single sample, got {}x{}", z_row.nrows(), z_row.ncols());
+        let p = ActivationFunction::Softmax.activate(z_row);
+        let p_col = DMatrix::from_column_slice(p.len(), 1, p.as_slice());
+        DMatrix::from_diagonal(&p_col.column(0)) - &p_col * p_col.transpose()
+    }
+}
+
+// Lowercase names, the same ones FromStr accepts
+impl fmt::Display for ActivationFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ActivationFunction::Linear => "linear",
+            ActivationFunction::Sigmoid => "sigmoid",
+            ActivationFunction::ReLU => "relu",
+            ActivationFunction::Softmax => "softmax",
+            ActivationFunction::ELU { alpha } if *alpha == DEFAULT_ELU_ALPHA => "elu",
+            ActivationFunction::ELU { alpha } => return write!(f, "elu({})", alpha),
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Case-insensitive parsing for config driven construction, e.g. "ReLU".parse::<ActivationFunction>().
+// "elu" uses DEFAULT_ELU_ALPHA, "elu(0.5)" sets alpha.
+impl FromStr for ActivationFunction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "linear" => Ok(ActivationFunction::Linear),
+            "sigmoid" => Ok(ActivationFunction::Sigmoid),
+            "relu" => Ok(ActivationFunction::ReLU),
+            "softmax" => Ok(ActivationFunction::Softmax),
+            "elu" => Ok(ActivationFunction::ELU { alpha: DEFAULT_ELU_ALPHA }),
+            name => match name.strip_prefix("elu(").and_then(|rest| rest.strip_suffix(')')) {
+                Some(alpha) => alpha.trim().parse().map(|alpha| ActivationFunction::ELU { alpha })
+                    .map_err(|_| format!("Invalid ELU alpha '{}' in '{}'", alpha, s)),
+                None => Err(format!("Unknown activation function '{}', expected one of: linear, sigmoid, relu, softmax, elu, elu(<alpha>)", s)),
+            },
+        }
+    }
+}
+
+// Activation-only layer that blends two activation functions: (1 - t) * from(z) + t * to(z).
+// `t` is scheduled from outside (e.g. raised from 0 to 1 over the epochs) to anneal one activation
+// into the other, such as a smooth Sigmoid-ish start towards a hard ReLU. Use it after a Linear DenseLayer.
+pub struct BlendedActivation {
+    pub from: ActivationFunction,
+    pub to: ActivationFunction,
+    pub t: f32,
+    num_features: usize,
+    z_cache: DMatrix<f32>, // Input of the last forward pass
+}
+
+impl BlendedActivation {
+    // Starts fully at `from` (t = 0)
+    pub fn new(num_features: usize, from: ActivationFunction, to: ActivationFunction) -> Self {
+        BlendedActivation { from, to, t: 0.0, num_features, z_cache: DMatrix::zeros(0, 0) }
+    }
+
+    pub fn activate(&self, z: &DMatrix<f32>) -> DMatrix<f32> {
+        (1.0 - self.t) * self.from.activate(z) + self.t * self.to.activate(z)
+    }
+
+    // The blend is linear, so its derivative is the same blend of the two derivatives
+    pub fn derivative(&self, z: &DMatrix<f32>) -> DMatrix<f32> {
+        (1.0 - self.t) * self.from.derivative(z) + self.t * self.to.derivative(z)
+    }
+}
+
+impl Layer for BlendedActivation {
+    fn forward(&mut self, input: &DMatrix<f32>) -> DMatrix<f32> {
+        self.z_cache = input.clone();
+        self.activate(input)
+    }
+
+    fn infer(&self, input: &DMatrix<f32>) -> DMatrix<f32> {
+        self.activate(input)
+    }
+
+    fn compute_gradients(&self, gradient_wrt_output: &DMatrix<f32>) -> (LayerGradients<f32>, DMatrix<f32>) {
+        // Same blend of the two Jacobian-vector products, so a Softmax on either side is handled exactly
+        let gradient_wrt_input = (1.0 - self.t) * self.from.jacobian_vector_product(gradient_wrt_output, &self.z_cache)
+            + self.t * self.to.jacobian_vector_product(gradient_wrt_output, &self.z_cache);
+        (LayerGradients::empty(), gradient_wrt_input)
+    }
+
+    fn clear_cache(&mut self) {
+        self.z_cache = DMatrix::zeros(0, 0);
+    }
+
+    fn input_size(&self) -> usize {
+        self.num_features
+    }
+
+    fn output_size(&self) -> usize {
+        self.num_features
+    }
+
+    fn layer_type(&self) -> &'static str {
+        "blended_activation"
+    }
+
+    fn to_serializable(&self) -> SerializableLayer {
+        SerializableLayer::BlendedActivation { num_features: self.num_features, from: self.from, to: self.to, t: self.t }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn softmax_jacobian_rows_sum_to_zero_and_match_finite_differences() {
+        let z = DMatrix::from_row_slice(1, 4, &[0.5f64, -1.0, 2.0, 0.1]);
+        let jacobian = ActivationFunction::softmax_jacobian(&z);
+        assert_eq!(jacobian.shape(), (4, 4));
+
+        let epsilon = 1e-6;
+        for j in 0..4 {
+            let mut z_plus = z.clone();
+            z_plus[j] += epsilon;
+            let mut z_minus = z.clone();
+            z_minus[j] -= epsilon;
+            let numeric = (ActivationFunction::Softmax.activate(&z_plus) - ActivationFunction::Softmax.activate(&z_minus)) / (2.0 * epsilon);
+            for i in 0..4 {
+                assert!((jacobian[(i, j)] - numeric[i]).abs() < 1e-8, "J[{}][{}] = {}, finite difference {}", i, j, jacobian[(i, j)], numeric[i]);
+            }
+        }
+        for row in jacobian.row_iter() {
+            assert!(row.sum().abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn every_activation_round_trips_through_its_name() {
+        let activations = [
+            ActivationFunction::Linear,
+            ActivationFunction::Sigmoid,
+            ActivationFunction::ReLU,
+            ActivationFunction::Softmax,
+            ActivationFunction::ELU { alpha: DEFAULT_ELU_ALPHA },
+            ActivationFunction::ELU { alpha: 0.5 },
+        ];
+        for activation_fn in activations {
+            assert_eq!(activation_fn.to_string().parse::<ActivationFunction>(), Ok(activation_fn));
+        }
+        assert_eq!("ReLU".parse::<ActivationFunction>(), Ok(ActivationFunction::ReLU));
+        assert!("tanh".parse::<ActivationFunction>().is_err());
+    }
+
+    #[test]
+    fn blend_is_from_at_zero_and_to_at_one() {
+        let z = DMatrix::from_row_slice(2, 3, &[-2.0, -0.5, 0.0, 0.3, 1.0, 4.0]);
+        let mut blended = BlendedActivation::new(3, ActivationFunction::Sigmoid, ActivationFunction::ReLU);
+        assert_eq!(blended.forward(&z), ActivationFunction::Sigmoid.activate(&z));
+        blended.t = 1.0;
+        assert_eq!(blended.forward(&z), ActivationFunction::ReLU.activate(&z));
+        blended.t = 0.5;
+        let halfway = (ActivationFunction::Sigmoid.activate(&z) + ActivationFunction::ReLU.activate(&z)) * 0.5;
+        assert!((blended.forward(&z) - halfway).amax() < 1e-6);
+    }
+
+    #[test]
+    fn elu_is_continuous_at_zero_and_saturates_at_minus_alpha() {
+        let elu = ActivationFunction::ELU { alpha: 0.5 };
+        let around_zero = DMatrix::from_row_slice(1, 3, &[-1e-6f64, 0.0, 1e-6]);
+        let values = elu.activate(&around_zero);
+        assert!(values.amax() <= 1e-6, "ELU jumps at 0: {}", values);
+        assert_eq!(values[1], 0.0);
+
+        // The slope is alpha just below 0 and 1 above, equal on both sides only for alpha = 1
+        let slopes = elu.derivative(&around_zero);
+        assert!((slopes[0] - 0.5).abs() < 1e-6 && slopes[2] == 1.0, "Slopes {}", slopes);
+        let default_slopes = ActivationFunction::ELU { alpha: DEFAULT_ELU_ALPHA }.derivative(&around_zero);
+        assert!((default_slopes[0] - default_slopes[2]).abs() < 1e-5);
+
+        let far_negative = elu.activate(&DMatrix::from_element(1, 1, -50.0f64));
+        assert!((far_negative[0] + 0.5).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,64 @@
+// Inference cache for interactive use, where the same input is queried over and over
+// (e.g. redrawing a visualization with different settings)
+
+use nalgebra::DMatrix;
+use crate::network::NeuralNetwork;
+
+// Remembers the last input and every layer's output for it, and only runs the network again when a
+// query comes with a different input. The network is borrowed, so its weights can't change under the cache.
+pub struct CachedInference<'a> {
+    network: &'a NeuralNetwork,
+    last_input: Option<DMatrix<f32>>,
+    layer_outputs: Vec<DMatrix<f32>>,
+    recompute_count: usize,
+}
+
+impl<'a> CachedInference<'a> {
+    pub fn new(network: &'a NeuralNetwork) -> Self {
+        CachedInference { network, last_input: None, layer_outputs: Vec::new(), recompute_count: 0 }
+    }
+
+    // Every layer's output for this input, layer_outputs[i] belongs to layer i
+    pub fn layer_outputs(&mut self, input: &DMatrix<f32>) -> &[DMatrix<f32>] {
+        if self.last_input.as_ref() != Some(input) {
+            self.layer_outputs = self.network.layer_outputs(input);
+            self.last_input = Some(input.clone());
+            self.recompute_count += 1;
+        }
+        &self.layer_outputs
+    }
+
+    // The network output for this input, same as NeuralNetwork::infer
+    pub fn output(&mut self, input: &DMatrix<f32>) -> &DMatrix<f32> {
+        self.layer_outputs(input).last().expect("Network has no layers")
+    }
+
+    // How many times the network actually ran, i.e. the number of cache misses
+    pub fn recompute_count(&self) -> usize {
+        self.recompute_count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activation::ActivationFunction;
+    use crate::loss::LossFunction;
+
+    #[test]
+    fn only_a_new_input_runs_the_network_again() {
+        let mut network = NeuralNetwork::new_seeded(LossFunction::CrossEntropy, 11);
+        network.add_dense_layer(3, 4, ActivationFunction::ReLU);
+        network.add_dense_layer(4, 2, ActivationFunction::Softmax);
+        let first = DMatrix::from_row_slice(1, 3, &[0.1, 0.2, 0.3]);
+        let second = DMatrix::from_row_slice(1, 3, &[-0.3, 0.2, 0.1]);
+
+        let mut cache = CachedInference::new(&network);
+        assert_eq!(cache.output(&first), &network.infer(&first));
+        cache.output(&first);
+        cache.layer_outputs(&first);
+        assert_eq!(cache.recompute_count(), 1);
+        assert_eq!(cache.output(&second), &network.infer(&second));
+        assert_eq!(cache.recompute_count(), 2);
+    }
+}
@@ -0,0 +1,99 @@
+// Post-hoc calibration: making the predicted probabilities match how often the network is actually right
+
+use nalgebra::DMatrix;
+use crate::activation::ActivationFunction;
+use crate::metrics::expected_calibration_error;
+use crate::network::NeuralNetwork;
+
+// Search range for the inverse temperature 1/T, i.e. T between 0.05 and 20
+const MIN_INVERSE_TEMPERATURE: f64 = 0.05;
+const MAX_INVERSE_TEMPERATURE: f64 = 20.0;
+const SEARCH_ITERATIONS: usize = 100;
+// Confidence bins of the ECE in TemperatureReport, the 15 of the paper
+const ECE_BINS: usize = 15;
+
+// Result of NeuralNetwork::fit_temperature
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TemperatureReport {
+    pub temperature: f32, // Relative to the weights before the call
+    pub ece_before: f32,  // Expected calibration error on the validation set before scaling
+    pub ece_after: f32,   // Same, after scaling
+}
+
+impl NeuralNetwork {
+    // Temperature scaling (Guo et al., 2017): finds the T that minimizes the negative log-likelihood of
+    // softmax(logits / T) on a validation set, and folds it into the output layer by dividing its
+    // weights and biases by T, so every later prediction is calibrated. T > 1 softens an overconfident model.
+    // Accuracy is unchanged since dividing by T > 0 doesn't change the argmax.
+    // Returns T relative to the current weights (so a second call on the same data finds ~1) and the
+    // expected calibration error on the validation set before and after. val_labels holds one class index per row.
+    pub fn fit_temperature(&mut self, val_inputs: &DMatrix<f32>, val_labels: &DMatrix<f32>) -> TemperatureReport {
+        let output_layer = self.dense_output_layer();
+        assert_eq!(output_layer.activation_fn, ActivationFunction::Softmax, "Temperature scaling needs a Softmax output layer");
+        assert_eq!(val_inputs.nrows(), val_labels.nrows(), "Got {} inputs but {} labels", val_inputs.nrows(), val_labels.nrows());
+        if val_inputs.nrows() == 0 {
+            return TemperatureReport { temperature: 1.0, ece_before: 0.0, ece_after: 0.0 };
+        }
+
+        let ece_before = expected_calibration_error(&self.infer(val_inputs), val_labels, ECE_BINS);
+        let logits = self.logits(val_inputs);
+        let labels: Vec<usize> = val_labels.column(0).iter().map(|&label| label as usize).collect();
+
+        // The NLL is convex in the inverse temperature, so a golden-section search finds the global minimum
+        let inverse_phi = (5f64.sqrt() - 1.0) / 2.0;
+        let (mut low, mut high) = (MIN_INVERSE_TEMPERATURE, MAX_INVERSE_TEMPERATURE);
+        for _ in 0..SEARCH_ITERATIONS {
+            let mid_low = high - inverse_phi * (high - low);
+            let mid_high = low + inverse_phi * (high - low);
+            if scaled_nll(&logits, &labels, mid_low) < scaled_nll(&logits, &labels, mid_high) {
+                high = mid_high;
+            } else {
+                low = mid_low;
+            }
+        }
+        let temperature = (2.0 / (low + high)) as f32;
+
+        let output_layer_idx = self.get_layers().len() - 1;
+        let output_layer = self.dense_layer_mut(output_layer_idx).expect("Checked above that the output layer is dense");
+        output_layer.weights.unscale_mut(temperature);
+        output_layer.biases.unscale_mut(temperature);
+        let ece_after = expected_calibration_error(&self.infer(val_inputs), val_labels, ECE_BINS);
+        TemperatureReport { temperature, ece_before, ece_after }
+    }
+}
+
+// Mean negative log-likelihood of softmax(logits * inverse_temperature), in f64 to keep the search precise
+fn scaled_nll(logits: &DMatrix<f32>, labels: &[usize], inverse_temperature: f64) -> f64 {
+    let total: f64 = logits.row_iter().zip(labels.iter()).map(|(row, &label)| {
+        let scaled: Vec<f64> = row.iter().map(|&logit| logit as f64 * inverse_temperature).collect();
+        let max = scaled.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        let log_sum_exp = max + scaled.iter().map(|&z| (z - max).exp()).sum::<f64>().ln();
+        log_sum_exp - scaled[label]
+    }).sum();
+    total / labels.len() as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer::DenseLayer;
+    use crate::loss::LossFunction;
+    use nalgebra::DVector;
+
+    #[test]
+    fn softens_an_overconfident_model() {
+        // Nearly certain of the sign of x, but every third label is the other class
+        let mut network = NeuralNetwork::new(LossFunction::CrossEntropy);
+        let weights = DMatrix::from_row_slice(1, 2, &[-10.0, 10.0]);
+        network.add_layer(DenseLayer::from_parameters(weights, DVector::zeros(2), ActivationFunction::Softmax));
+        let inputs = DMatrix::from_fn(60, 1, |r, _| if r % 2 == 0 { 1.0 } else { -1.0 } * (1.0 + r as f32 / 60.0));
+        let labels = DMatrix::from_fn(60, 1, |r, _| {
+            let class = if inputs[(r, 0)] > 0.0 { 1.0 } else { 0.0 };
+            if r % 3 == 0 { 1.0 - class } else { class }
+        });
+
+        let report = network.fit_temperature(&inputs, &labels);
+        assert!(report.temperature > 1.0, "Got T = {}", report.temperature);
+        assert!(report.ece_after < report.ece_before, "ECE went from {} to {}", report.ece_before, report.ece_after);
+    }
+}
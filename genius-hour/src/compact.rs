@@ -0,0 +1,146 @@
+// Compact binary model format, smaller than bincode for the WASM bundle.
+//
+// Layout (all integers and floats little-endian):
+//   magic        4 bytes  "GHNN"
+//   version      u8
+//   num_layers   u32
+//   per layer:   input_size u32, output_size u32, activation u8 (ELU: followed by alpha as f32)
+//   per layer:   weights as f32 (input_size * output_size, column-major), then biases as f32 (output_size)
+
+use std::io::{Cursor, Error, ErrorKind, Read};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use nalgebra::{DMatrix, DVector};
+use crate::activation::ActivationFunction;
+use crate::layer::DenseLayer;
+use crate::loss::LossFunction;
+use crate::network::NeuralNetwork;
+
+const COMPACT_MAGIC: &[u8; 4] = b"GHNN";
+const COMPACT_VERSION: u8 = 1;
+
+fn write_activation(bytes: &mut Vec<u8>, activation_fn: ActivationFunction) {
+    let tag = match activation_fn {
+        ActivationFunction::Linear => 0,
+        ActivationFunction::Sigmoid => 1,
+        ActivationFunction::ReLU => 2,
+        ActivationFunction::Softmax => 3,
+        ActivationFunction::ELU { .. } => 4,
+    };
+    bytes.write_u8(tag).unwrap();
+    if let ActivationFunction::ELU { alpha } = activation_fn {
+        bytes.write_f32::<LittleEndian>(alpha).unwrap();
+    }
+}
+
+fn read_activation(cursor: &mut Cursor<&[u8]>) -> Result<ActivationFunction, Error> {
+    match cursor.read_u8()? {
+        0 => Ok(ActivationFunction::Linear),
+        1 => Ok(ActivationFunction::Sigmoid),
+        2 => Ok(ActivationFunction::ReLU),
+        3 => Ok(ActivationFunction::Softmax),
+        4 => Ok(ActivationFunction::ELU { alpha: cursor.read_f32::<LittleEndian>()? }),
+        tag => Err(Error::new(ErrorKind::InvalidData, format!("Unknown activation tag {}", tag))),
+    }
+}
+
+// input_size, output_size and the activation tag
+const COMPACT_MIN_LAYER_HEADER_SIZE: usize = 4 + 4 + 1;
+
+fn remaining(cursor: &Cursor<&[u8]>) -> usize {
+    cursor.get_ref().len().saturating_sub(cursor.position() as usize)
+}
+
+impl NeuralNetwork {
+    // Only dense networks fit the format
+    pub fn to_compact_bytes(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let layers = self.dense_layers()?;
+        let num_params: usize = layers.iter().map(|layer| layer.weights.len() + layer.biases.len()).sum();
+        let mut bytes = Vec::with_capacity(4 + 1 + 4 + layers.len() * COMPACT_MIN_LAYER_HEADER_SIZE + num_params * 4);
+
+        // Writing into a Vec can't fail, so the io::Results below are safe to unwrap
+        bytes.extend_from_slice(COMPACT_MAGIC);
+        bytes.write_u8(COMPACT_VERSION).unwrap();
+        bytes.write_u32::<LittleEndian>(layers.len() as u32).unwrap();
+        for layer in layers.iter() {
+            bytes.write_u32::<LittleEndian>(layer.input_size() as u32).unwrap();
+            bytes.write_u32::<LittleEndian>(layer.output_size() as u32).unwrap();
+            write_activation(&mut bytes, layer.activation_fn);
+        }
+        for layer in layers.iter() {
+            for &value in layer.weights.iter().chain(layer.biases.iter()) {
+                bytes.write_f32::<LittleEndian>(value).unwrap();
+            }
+        }
+        Ok(bytes)
+    }
+
+    pub fn from_compact_bytes(bytes: &[u8], loss_fn: LossFunction) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut cursor = Cursor::new(bytes);
+
+        let mut magic = [0u8; 4];
+        cursor.read_exact(&mut magic)?;
+        if &magic != COMPACT_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a compact model file (bad magic)").into());
+        }
+        let version = cursor.read_u8()?;
+        if version != COMPACT_VERSION {
+            return Err(Error::new(ErrorKind::InvalidData, format!("Unsupported compact format version {}, expected {}", version, COMPACT_VERSION)).into());
+        }
+
+        // Every count below comes from the file, so nothing is allocated before checking that the
+        // remaining bytes can actually hold it
+        let num_layers = cursor.read_u32::<LittleEndian>()? as usize;
+        if num_layers.checked_mul(COMPACT_MIN_LAYER_HEADER_SIZE).is_none_or(|size| size > remaining(&cursor)) {
+            return Err(Error::new(ErrorKind::InvalidData, format!("File too short for {} layers", num_layers)).into());
+        }
+        let mut shapes: Vec<(usize, usize, ActivationFunction)> = Vec::with_capacity(num_layers);
+        for i in 0..num_layers {
+            let input_size = cursor.read_u32::<LittleEndian>()? as usize;
+            let output_size = cursor.read_u32::<LittleEndian>()? as usize;
+            let activation_fn = read_activation(&mut cursor)?;
+            if let Some(&(_, previous_output_size, _)) = shapes.last().filter(|previous| previous.1 != input_size) {
+                return Err(Error::new(ErrorKind::InvalidData, format!(
+                    "Layer {} takes {} inputs but layer {} has {} outputs", i, input_size, i - 1, previous_output_size
+                )).into());
+            }
+            shapes.push((input_size, output_size, activation_fn));
+        }
+
+        let mut nn = NeuralNetwork::new(loss_fn);
+        for (i, (input_size, output_size, activation_fn)) in shapes.into_iter().enumerate() {
+            let num_weights = input_size.checked_mul(output_size);
+            let num_bytes = num_weights.and_then(|num_weights| num_weights.checked_add(output_size)).and_then(|num_values| num_values.checked_mul(4));
+            if num_bytes.is_none_or(|num_bytes| num_bytes > remaining(&cursor)) {
+                return Err(Error::new(ErrorKind::InvalidData, format!("File too short for the parameters of layer {} ({}x{})", i, input_size, output_size)).into());
+            }
+            let mut weights_data = vec![0.0; input_size * output_size];
+            cursor.read_f32_into::<LittleEndian>(&mut weights_data)?;
+            let mut biases_data = vec![0.0; output_size];
+            cursor.read_f32_into::<LittleEndian>(&mut biases_data)?;
+
+            let weights = DMatrix::from_vec(input_size, output_size, weights_data);
+            nn.add_layer(DenseLayer::from_parameters(weights, DVector::from_vec(biases_data), activation_fn));
+        }
+        Ok(nn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip_keeps_predictions_and_beats_bincode_on_size() {
+        let mut network = NeuralNetwork::new_seeded(LossFunction::CrossEntropy, 5);
+        network.add_dense_layer(6, 5, ActivationFunction::ELU { alpha: 0.5 });
+        network.add_dense_layer(5, 3, ActivationFunction::Softmax);
+        let inputs = DMatrix::from_fn(4, 6, |r, c| (r as f32 - c as f32) * 0.3);
+
+        let bytes = network.to_compact_bytes().unwrap();
+        let loaded = NeuralNetwork::from_compact_bytes(&bytes, LossFunction::CrossEntropy).unwrap();
+        assert_eq!(loaded.infer(&inputs), network.infer(&inputs));
+        assert!(bytes.len() < network.to_bytes().unwrap().len(), "Compact {} bytes, bincode {}", bytes.len(), network.to_bytes().unwrap().len());
+
+        assert!(NeuralNetwork::from_compact_bytes(&bytes[..bytes.len() - 1], LossFunction::CrossEntropy).is_err());
+    }
+}
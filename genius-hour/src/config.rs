@@ -0,0 +1,109 @@
+// Building networks from a config file instead of Rust code.
+//
+// Example spec (JSON):
+// {
+//     "loss": "cross_entropy",
+//     "layers": [
+//         { "input": 784, "output": 128, "activation": "relu" },
+//         { "input": 128, "output": 10, "activation": "softmax" }
+//     ]
+// }
+
+use serde::{Serialize, Deserialize};
+use crate::activation::ActivationFunction;
+use crate::layer::DenseLayer;
+use crate::loss::LossFunction;
+use crate::network::NeuralNetwork;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LayerConfig {
+    pub input: usize,
+    pub output: usize,
+    pub activation: String, // Parsed with ActivationFunction::from_str
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NetworkConfig {
+    pub loss: String, // Parsed with LossFunction::from_str
+    pub layers: Vec<LayerConfig>,
+}
+
+impl NetworkConfig {
+    // Checks the names and that every layer's input matches the previous layer's output,
+    // then builds a freshly initialized network
+    pub fn build(&self) -> Result<NeuralNetwork, Box<dyn std::error::Error>> {
+        if self.layers.is_empty() {
+            return Err("Network config has no layers".into());
+        }
+        let loss_fn: LossFunction = self.loss.parse()?;
+
+        let mut nn = NeuralNetwork::new(loss_fn);
+        for (i, layer_config) in self.layers.iter().enumerate() {
+            if i > 0 && self.layers[i - 1].output != layer_config.input {
+                return Err(format!(
+                    "Layer {} expects {} inputs but layer {} outputs {}",
+                    i, layer_config.input, i - 1, self.layers[i - 1].output
+                ).into());
+            }
+            let activation_fn: ActivationFunction = layer_config.activation.parse()
+                .map_err(|e| format!("Layer {}: {}", i, e))?;
+            nn.add_layer(DenseLayer::new(layer_config.input, layer_config.output, activation_fn));
+        }
+        Ok(nn)
+    }
+}
+
+// The architecture of an existing network, e.g. to log it next to training metrics.
+// Configs only describe dense layers, so this fails for networks with any other kind.
+impl TryFrom<&NeuralNetwork> for NetworkConfig {
+    type Error = String;
+
+    fn try_from(network: &NeuralNetwork) -> Result<Self, Self::Error> {
+        Ok(NetworkConfig {
+            loss: network.get_loss_fn().to_string(),
+            layers: network.dense_layers()?.into_iter().map(|layer| LayerConfig {
+                input: layer.input_size(),
+                output: layer.output_size(),
+                activation: layer.activation_fn.to_string(),
+            }).collect(),
+        })
+    }
+}
+
+impl NeuralNetwork {
+    // Reads a JSON network spec (see the top of config.rs) and builds it
+    pub fn from_config(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let contents = std::fs::read_to_string(path)?;
+        Self::from_config_str(&contents)
+    }
+
+    pub fn from_config_str(json: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let config: NetworkConfig = serde_json::from_str(json)?;
+        config.build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_the_layers_of_a_json_spec() {
+        let spec = r#"{
+            "loss": "cross_entropy",
+            "layers": [
+                { "input": 4, "output": 8, "activation": "relu" },
+                { "input": 8, "output": 3, "activation": "softmax" }
+            ]
+        }"#;
+        let network = NeuralNetwork::from_config_str(spec).unwrap();
+        assert_eq!(network.get_loss_fn(), LossFunction::CrossEntropy);
+        let layers = network.dense_layers().unwrap();
+        assert_eq!(layers.len(), 2);
+        assert_eq!((layers[0].input_size(), layers[0].output_size(), layers[0].activation_fn), (4, 8, ActivationFunction::ReLU));
+        assert_eq!((layers[1].input_size(), layers[1].output_size(), layers[1].activation_fn), (8, 3, ActivationFunction::Softmax));
+
+        let mismatched = spec.replace(r#""input": 8"#, r#""input": 7"#);
+        assert!(NeuralNetwork::from_config_str(&mismatched).is_err());
+    }
+}
@@ -0,0 +1,482 @@
+// Image layers (2D convolution, max pooling and flattening) to put in front of the dense layers of a NeuralNetwork.
+// Every row of the input is one flattened (channels, height, width) image, i.e. pixel (c, y, x) is column
+// c * height * width + y * width + x. MNIST's 784 pixels are the input shape (1, 28, 28). The output
+// is flattened the same way with shape (out_channels, output_height, output_width).
+
+use nalgebra::{DMatrix, DVector};
+use rand_distr::{Distribution, Normal};
+use serde::{Deserialize, Serialize};
+use crate::layer::{Layer, LayerGradients};
+use crate::serialization::SerializableLayer;
+
+// Cross-correlation (like every deep learning library's "convolution") of the input with out_channels
+// kernels of in_channels x kernel_size x kernel_size, plus one bias per output channel. There is no
+// activation, add one with a BlendedActivation (t = 0) after it.
+pub struct Conv2DLayer {
+    // Row o is output channel o's kernel, flattened in (in_channel, ky, kx) order
+    pub kernels: DMatrix<f32>,
+    pub biases: DVector<f32>,
+    input_shape: (usize, usize, usize), // (channels, height, width)
+    kernel_size: usize,
+    stride: usize,
+    padding: usize, // Zeros added on every side
+
+    // Cache for backpropagation
+    input_cache: DMatrix<f32>,
+}
+
+impl Conv2DLayer {
+    // He initialization of the kernels (fan_in = in_channels * kernel_size^2), zero biases
+    pub fn new(input_shape: (usize, usize, usize), out_channels: usize, kernel_size: usize, stride: usize, padding: usize) -> Self {
+        let (in_channels, height, width) = input_shape;
+        assert!(kernel_size > 0 && stride > 0, "kernel_size and stride must be at least 1");
+        assert!(
+            kernel_size <= height + 2 * padding && kernel_size <= width + 2 * padding,
+            "A {}x{} kernel doesn't fit a {}x{} input with padding {}", kernel_size, kernel_size, height, width, padding
+        );
+        let fan_in = in_channels * kernel_size * kernel_size;
+        let normal = Normal::new(0.0, (2.0 / fan_in as f32).sqrt()).unwrap();
+        let mut rng = rand::rng();
+        Conv2DLayer {
+            kernels: DMatrix::from_fn(out_channels, fan_in, |_, _| normal.sample(&mut rng)),
+            biases: DVector::zeros(out_channels),
+            input_shape,
+            kernel_size,
+            stride,
+            padding,
+            input_cache: DMatrix::zeros(0, 0),
+        }
+    }
+
+    pub fn input_shape(&self) -> (usize, usize, usize) {
+        self.input_shape
+    }
+
+    // (out_channels, output_height, output_width)
+    pub fn output_shape(&self) -> (usize, usize, usize) {
+        let (_, height, width) = self.input_shape;
+        let output_height = (height + 2 * self.padding - self.kernel_size) / self.stride + 1;
+        let output_width = (width + 2 * self.padding - self.kernel_size) / self.stride + 1;
+        (self.kernels.nrows(), output_height, output_width)
+    }
+
+    // For every output position (row, in y then x order) the input values its kernel window covers
+    // (columns, in the kernel's (in_channel, ky, kx) order), zeros where the window overlaps the padding
+    fn patches(&self, image: &[f32]) -> DMatrix<f32> {
+        let (in_channels, height, width) = self.input_shape;
+        let (_, output_height, output_width) = self.output_shape();
+        let k = self.kernel_size;
+        let mut patches = DMatrix::zeros(output_height * output_width, in_channels * k * k);
+        for (position, (out_y, out_x)) in output_positions(output_height, output_width).enumerate() {
+            for (column, (channel, ky, kx)) in kernel_offsets(in_channels, k).enumerate() {
+                if let Some(pixel) = self.input_index(channel, out_y * self.stride + ky, out_x * self.stride + kx, height, width) {
+                    patches[(position, column)] = image[pixel];
+                }
+            }
+        }
+        patches
+    }
+
+    // Flat index of the input pixel at a padded (y, x), None inside the padding
+    fn input_index(&self, channel: usize, padded_y: usize, padded_x: usize, height: usize, width: usize) -> Option<usize> {
+        let y = padded_y.checked_sub(self.padding).filter(|&y| y < height)?;
+        let x = padded_x.checked_sub(self.padding).filter(|&x| x < width)?;
+        Some(channel * height * width + y * width + x)
+    }
+
+    fn convolve(&self, input: &DMatrix<f32>) -> DMatrix<f32> {
+        assert_eq!(input.ncols(), self.input_size(), "Conv2DLayer expects {} input columns ({:?}), got {}", self.input_size(), self.input_shape, input.ncols());
+        let (out_channels, output_height, output_width) = self.output_shape();
+        let num_positions = output_height * output_width;
+        let mut output = DMatrix::zeros(input.nrows(), self.output_size());
+        for (sample, row) in input.row_iter().enumerate() {
+            let image: Vec<f32> = row.iter().copied().collect();
+            // (positions, out_channels): every kernel applied at every position
+            let responses = self.patches(&image) * self.kernels.transpose();
+            for channel in 0..out_channels {
+                for position in 0..num_positions {
+                    output[(sample, channel * num_positions + position)] = responses[(position, channel)] + self.biases[channel];
+                }
+            }
+        }
+        output
+    }
+}
+
+impl Layer for Conv2DLayer {
+    fn forward(&mut self, input: &DMatrix<f32>) -> DMatrix<f32> {
+        self.input_cache = input.clone();
+        self.convolve(input)
+    }
+
+    fn infer(&self, input: &DMatrix<f32>) -> DMatrix<f32> {
+        self.convolve(input)
+    }
+
+    // Kernel and bias gradients are averaged over the batch like DenseLayer's, the input gradient is per sample
+    fn compute_gradients(&self, gradient_wrt_output: &DMatrix<f32>) -> (LayerGradients<f32>, DMatrix<f32>) {
+        assert_eq!(gradient_wrt_output.nrows(), self.input_cache.nrows(), "BACKWARD: gradient rows must match the cached batch size");
+        let (in_channels, height, width) = self.input_shape;
+        let (out_channels, output_height, output_width) = self.output_shape();
+        let num_positions = output_height * output_width;
+        let k = self.kernel_size;
+
+        let mut kernel_gradients = DMatrix::zeros(self.kernels.nrows(), self.kernels.ncols());
+        let mut bias_gradients = DVector::zeros(out_channels);
+        let mut gradient_wrt_input = DMatrix::zeros(self.input_cache.nrows(), self.input_size());
+        for sample in 0..self.input_cache.nrows() {
+            let image: Vec<f32> = self.input_cache.row(sample).iter().copied().collect();
+            // (positions, out_channels), the same layout as the responses in the forward pass
+            let output_gradient = DMatrix::from_fn(num_positions, out_channels, |position, channel| {
+                gradient_wrt_output[(sample, channel * num_positions + position)]
+            });
+            kernel_gradients += output_gradient.transpose() * self.patches(&image);
+            bias_gradients += output_gradient.row_sum().transpose();
+
+            // Every patch value came from one input pixel (or the padding), so its gradient goes back there
+            let patch_gradients = &output_gradient * &self.kernels;
+            for (position, (out_y, out_x)) in output_positions(output_height, output_width).enumerate() {
+                for (column, (channel, ky, kx)) in kernel_offsets(in_channels, k).enumerate() {
+                    if let Some(pixel) = self.input_index(channel, out_y * self.stride + ky, out_x * self.stride + kx, height, width) {
+                        gradient_wrt_input[(sample, pixel)] += patch_gradients[(position, column)];
+                    }
+                }
+            }
+        }
+
+        let batch_size = self.input_cache.nrows().max(1) as f32;
+        let gradients = LayerGradients { weights: kernel_gradients / batch_size, biases: bias_gradients / batch_size };
+        (gradients, gradient_wrt_input)
+    }
+
+    // The kernels take the place of a DenseLayer's weights
+    fn parameters(&self) -> Option<(&DMatrix<f32>, &DVector<f32>)> {
+        Some((&self.kernels, &self.biases))
+    }
+
+    fn parameters_mut(&mut self) -> Option<(&mut DMatrix<f32>, &mut DVector<f32>)> {
+        Some((&mut self.kernels, &mut self.biases))
+    }
+
+    fn clear_cache(&mut self) {
+        self.input_cache = DMatrix::zeros(0, 0);
+    }
+
+    fn input_size(&self) -> usize {
+        let (channels, height, width) = self.input_shape;
+        channels * height * width
+    }
+
+    fn output_size(&self) -> usize {
+        let (channels, height, width) = self.output_shape();
+        channels * height * width
+    }
+
+    fn layer_type(&self) -> &'static str {
+        "conv2d"
+    }
+
+    fn to_serializable(&self) -> SerializableLayer {
+        SerializableLayer::Conv2D(SerializableConv2DLayer {
+            kernels_data: self.kernels.as_slice().to_vec(),
+            biases_data: self.biases.as_slice().to_vec(),
+            input_shape: self.input_shape,
+            out_channels: self.kernels.nrows(),
+            kernel_size: self.kernel_size,
+            stride: self.stride,
+            padding: self.padding,
+        })
+    }
+}
+
+// Max pooling: every channel is split into pool_size x pool_size windows, stride apart, and each window is
+// replaced by its largest value. Windows never hang over the edge: the output is
+// floor((size - pool_size) / stride) + 1 along each side, so trailing rows and columns that don't fill a
+// whole window are dropped (e.g. a 5x5 input with 2x2 pooling and stride 2 gives 2x2).
+pub struct MaxPool2DLayer {
+    input_shape: (usize, usize, usize), // (channels, height, width)
+    pool_size: usize,
+    stride: usize,
+
+    // Cache for backpropagation: per sample, the input column every output took its max from
+    argmax_cache: Vec<Vec<usize>>,
+}
+
+impl MaxPool2DLayer {
+    pub fn new(input_shape: (usize, usize, usize), pool_size: usize, stride: usize) -> Self {
+        let (_, height, width) = input_shape;
+        assert!(pool_size > 0 && stride > 0, "pool_size and stride must be at least 1");
+        assert!(pool_size <= height && pool_size <= width, "A {}x{} pool doesn't fit a {}x{} input", pool_size, pool_size, height, width);
+        MaxPool2DLayer { input_shape, pool_size, stride, argmax_cache: Vec::new() }
+    }
+
+    pub fn input_shape(&self) -> (usize, usize, usize) {
+        self.input_shape
+    }
+
+    // (channels, output_height, output_width)
+    pub fn output_shape(&self) -> (usize, usize, usize) {
+        let (channels, height, width) = self.input_shape;
+        (channels, (height - self.pool_size) / self.stride + 1, (width - self.pool_size) / self.stride + 1)
+    }
+
+    // The pooled output and, for every output column, the input column of its max (the first one on ties)
+    fn pool(&self, input: &DMatrix<f32>) -> (DMatrix<f32>, Vec<Vec<usize>>) {
+        assert_eq!(input.ncols(), self.input_size(), "MaxPool2DLayer expects {} input columns ({:?}), got {}", self.input_size(), self.input_shape, input.ncols());
+        let (channels, height, width) = self.input_shape;
+        let (_, output_height, output_width) = self.output_shape();
+        let mut output = DMatrix::zeros(input.nrows(), self.output_size());
+        let mut argmaxes = Vec::with_capacity(input.nrows());
+        for (sample, row) in input.row_iter().enumerate() {
+            let mut sample_argmaxes = Vec::with_capacity(self.output_size());
+            for channel in 0..channels {
+                for (out_y, out_x) in output_positions(output_height, output_width) {
+                    let window = (0..self.pool_size).flat_map(|dy| (0..self.pool_size).map(move |dx| (dy, dx)));
+                    let argmax = window
+                        .map(|(dy, dx)| channel * height * width + (out_y * self.stride + dy) * width + out_x * self.stride + dx)
+                        .fold(None, |best: Option<usize>, pixel| match best {
+                            Some(best) if row[best] >= row[pixel] => Some(best),
+                            _ => Some(pixel),
+                        })
+                        .unwrap();
+                    output[(sample, sample_argmaxes.len())] = row[argmax];
+                    sample_argmaxes.push(argmax);
+                }
+            }
+            argmaxes.push(sample_argmaxes);
+        }
+        (output, argmaxes)
+    }
+}
+
+impl Layer for MaxPool2DLayer {
+    fn forward(&mut self, input: &DMatrix<f32>) -> DMatrix<f32> {
+        let (output, argmaxes) = self.pool(input);
+        self.argmax_cache = argmaxes;
+        output
+    }
+
+    fn infer(&self, input: &DMatrix<f32>) -> DMatrix<f32> {
+        self.pool(input).0
+    }
+
+    // Only the input that was the max of a window gets that window's gradient, everything else gets 0
+    fn compute_gradients(&self, gradient_wrt_output: &DMatrix<f32>) -> (LayerGradients<f32>, DMatrix<f32>) {
+        assert_eq!(gradient_wrt_output.nrows(), self.argmax_cache.len(), "BACKWARD: gradient rows must match the cached batch size");
+        let mut gradient_wrt_input = DMatrix::zeros(gradient_wrt_output.nrows(), self.input_size());
+        for (sample, argmaxes) in self.argmax_cache.iter().enumerate() {
+            for (output_idx, &input_idx) in argmaxes.iter().enumerate() {
+                gradient_wrt_input[(sample, input_idx)] += gradient_wrt_output[(sample, output_idx)];
+            }
+        }
+        (LayerGradients::empty(), gradient_wrt_input)
+    }
+
+    fn clear_cache(&mut self) {
+        self.argmax_cache = Vec::new();
+    }
+
+    fn input_size(&self) -> usize {
+        let (channels, height, width) = self.input_shape;
+        channels * height * width
+    }
+
+    fn output_size(&self) -> usize {
+        let (channels, height, width) = self.output_shape();
+        channels * height * width
+    }
+
+    fn layer_type(&self) -> &'static str {
+        "max_pool2d"
+    }
+
+    fn to_serializable(&self) -> SerializableLayer {
+        SerializableLayer::MaxPool2D { input_shape: self.input_shape, pool_size: self.pool_size, stride: self.stride }
+    }
+}
+
+// Marks where the image layers end and the dense ones begin: (channels, height, width) images go in,
+// plain feature vectors of channels * height * width come out. Images are already stored as flattened
+// rows in (channel, y, x) order, so the values pass through untouched both ways, the layer only checks
+// that the shapes line up with what it saw in the forward pass.
+pub struct FlattenLayer {
+    input_shape: (usize, usize, usize), // (channels, height, width)
+
+    // Cache for backpropagation: (batch size, features) of the last forward pass
+    shape_cache: (usize, usize),
+}
+
+impl FlattenLayer {
+    pub fn new(input_shape: (usize, usize, usize)) -> Self {
+        FlattenLayer { input_shape, shape_cache: (0, 0) }
+    }
+
+    pub fn input_shape(&self) -> (usize, usize, usize) {
+        self.input_shape
+    }
+}
+
+impl Layer for FlattenLayer {
+    fn forward(&mut self, input: &DMatrix<f32>) -> DMatrix<f32> {
+        let output = self.infer(input);
+        self.shape_cache = output.shape();
+        output
+    }
+
+    fn infer(&self, input: &DMatrix<f32>) -> DMatrix<f32> {
+        assert_eq!(input.ncols(), self.input_size(), "FlattenLayer expects {} input columns ({:?}), got {}", self.input_size(), self.input_shape, input.ncols());
+        input.clone()
+    }
+
+    fn compute_gradients(&self, gradient_wrt_output: &DMatrix<f32>) -> (LayerGradients<f32>, DMatrix<f32>) {
+        assert_eq!(gradient_wrt_output.shape(), self.shape_cache, "BACKWARD: gradient shape must match the cached forward pass");
+        (LayerGradients::empty(), gradient_wrt_output.clone())
+    }
+
+    fn input_size(&self) -> usize {
+        let (channels, height, width) = self.input_shape;
+        channels * height * width
+    }
+
+    fn output_size(&self) -> usize {
+        self.input_size()
+    }
+
+    fn layer_type(&self) -> &'static str {
+        "flatten"
+    }
+
+    fn to_serializable(&self) -> SerializableLayer {
+        SerializableLayer::Flatten { input_shape: self.input_shape }
+    }
+}
+
+// Flat column-major kernels plus the shapes, like SerializableDenseLayer's binary layout
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SerializableConv2DLayer {
+    kernels_data: Vec<f32>,
+    biases_data: Vec<f32>,
+    input_shape: (usize, usize, usize),
+    out_channels: usize,
+    kernel_size: usize,
+    stride: usize,
+    padding: usize,
+}
+
+impl SerializableConv2DLayer {
+    // Fails instead of panicking on shapes Conv2DLayer::new would reject or data that doesn't fit them
+    pub fn into_conv2d_layer(self) -> Result<Conv2DLayer, Box<dyn std::error::Error>> {
+        let (in_channels, height, width) = self.input_shape;
+        let k = self.kernel_size;
+        if k == 0 || self.stride == 0 {
+            return Err("Conv2D kernel_size and stride must be at least 1".into());
+        }
+        if k > height + 2 * self.padding || k > width + 2 * self.padding {
+            return Err(format!("A {}x{} kernel doesn't fit a {}x{} input with padding {}", k, k, height, width, self.padding).into());
+        }
+        let fan_in = in_channels * k * k;
+        if self.kernels_data.len() != self.out_channels * fan_in {
+            return Err(format!(
+                "Expected {} kernel values ({} kernels of {}x{}x{}), got {}",
+                self.out_channels * fan_in, self.out_channels, in_channels, k, k, self.kernels_data.len()
+            ).into());
+        }
+        if self.biases_data.len() != self.out_channels {
+            return Err(format!("Expected {} biases, got {}", self.out_channels, self.biases_data.len()).into());
+        }
+        Ok(Conv2DLayer {
+            kernels: DMatrix::from_vec(self.out_channels, fan_in, self.kernels_data),
+            biases: DVector::from_vec(self.biases_data),
+            input_shape: self.input_shape,
+            kernel_size: k,
+            stride: self.stride,
+            padding: self.padding,
+            input_cache: DMatrix::zeros(0, 0),
+        })
+    }
+}
+
+// (y, x) of every output position, row by row
+fn output_positions(output_height: usize, output_width: usize) -> impl Iterator<Item = (usize, usize)> {
+    (0..output_height).flat_map(move |y| (0..output_width).map(move |x| (y, x)))
+}
+
+// (in_channel, ky, kx) of every kernel weight, in the order of a kernel row
+fn kernel_offsets(in_channels: usize, kernel_size: usize) -> impl Iterator<Item = (usize, usize, usize)> {
+    (0..in_channels).flat_map(move |channel| {
+        (0..kernel_size).flat_map(move |ky| (0..kernel_size).map(move |kx| (channel, ky, kx)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn serialized_conv(kernels_data: Vec<f32>, biases_data: Vec<f32>) -> SerializableConv2DLayer {
+        SerializableConv2DLayer { kernels_data, biases_data, input_shape: (1, 3, 3), out_channels: 1, kernel_size: 2, stride: 1, padding: 0 }
+    }
+
+    #[test]
+    fn a_loaded_kernel_convolves_a_known_image() {
+        // Kernel [1 0; 0 -1] takes every pixel minus the one diagonally below it, 4 less on a 1..9 image
+        let layer = serialized_conv(vec![1.0, 0.0, 0.0, -1.0], vec![0.0]).into_conv2d_layer().unwrap();
+        let image = DMatrix::from_row_slice(1, 9, &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0]);
+        assert_eq!(layer.output_shape(), (1, 2, 2));
+        assert_eq!(layer.infer(&image).as_slice(), &[-4.0, -4.0, -4.0, -4.0]);
+
+        let restored = match layer.to_serializable() {
+            SerializableLayer::Conv2D(serialized) => serialized.into_conv2d_layer().unwrap(),
+            _ => panic!("A Conv2DLayer should serialize as Conv2D"),
+        };
+        assert_eq!(restored.infer(&image), layer.infer(&image));
+    }
+
+    #[test]
+    fn mismatched_kernel_data_fails_to_load() {
+        assert!(serialized_conv(vec![1.0, 0.0, 0.0], vec![0.0]).into_conv2d_layer().is_err());
+        assert!(serialized_conv(vec![1.0, 0.0, 0.0, -1.0], vec![0.0, 1.0]).into_conv2d_layer().is_err());
+        let mut zero_stride = serialized_conv(vec![1.0, 0.0, 0.0, -1.0], vec![0.0]);
+        zero_stride.stride = 0;
+        assert!(zero_stride.into_conv2d_layer().is_err());
+    }
+
+    #[test]
+    fn max_pooling_keeps_each_window_max_and_routes_its_gradient_back() {
+        let mut layer = MaxPool2DLayer::new((1, 4, 4), 2, 2);
+        let image = DMatrix::from_fn(1, 16, |_, c| c as f32 + 1.0);
+        assert_eq!(layer.forward(&image).as_slice(), &[6.0, 8.0, 14.0, 16.0]);
+
+        let gradient_wrt_output = DMatrix::from_row_slice(1, 4, &[0.1, 0.2, 0.3, 0.4]);
+        let (_, gradient_wrt_input) = layer.compute_gradients(&gradient_wrt_output);
+        for (pixel, &gradient) in gradient_wrt_input.iter().enumerate() {
+            let expected = match pixel {
+                5 => 0.1,
+                7 => 0.2,
+                13 => 0.3,
+                15 => 0.4,
+                _ => 0.0,
+            };
+            assert_eq!(gradient, expected, "Pixel {}", pixel);
+        }
+    }
+
+    #[test]
+    fn flatten_passes_values_through_both_ways() {
+        let mut layer = FlattenLayer::new((2, 3, 3));
+        assert_eq!((layer.input_size(), layer.output_size()), (18, 18));
+        let images = DMatrix::from_fn(3, 18, |r, c| (r * 18 + c) as f32 * 0.5);
+        let output = layer.forward(&images);
+        assert_eq!(output, images);
+
+        let gradient_wrt_output = output.map(|value| value - 1.0);
+        let (gradients, gradient_wrt_input) = layer.compute_gradients(&gradient_wrt_output);
+        assert_eq!(gradient_wrt_input, gradient_wrt_output);
+        assert!(gradients.weights.is_empty() && gradients.biases.is_empty());
+
+        let restored = match layer.to_serializable() {
+            SerializableLayer::Flatten { input_shape } => FlattenLayer::new(input_shape),
+            _ => panic!("A FlattenLayer should serialize as Flatten"),
+        };
+        assert_eq!(restored.input_shape(), (2, 3, 3));
+    }
+}
@@ -0,0 +1,172 @@
+use nalgebra::DMatrix;
+use crate::rng::StableRng;
+
+// Builds a new matrix out of the given rows of `data`, in the order of `indices` (e.g. for a mini-batch)
+pub fn select_rows(data: &DMatrix<f32>, indices: &[usize]) -> DMatrix<f32> {
+    let num_cols = data.ncols();
+    let mut rows_data = Vec::with_capacity(indices.len() * num_cols);
+    for &idx in indices {
+        rows_data.extend(data.row(idx).iter().copied());
+    }
+    DMatrix::from_row_slice(indices.len(), num_cols, &rows_data)
+}
+
+// A transformation of one flattened sample, e.g. a shift for NeuralNetwork::predict_tta
+pub type Augmentation<'a> = &'a dyn Fn(&[f32]) -> Vec<f32>;
+
+// Moves a row-major image (e.g. a flattened 28x28 MNIST digit) by dx pixels to the right and dy pixels down.
+// Pixels shifted in from outside the image are 0, pixels shifted past the border are dropped.
+pub fn shift_image(pixels: &[f32], width: usize, dx: i32, dy: i32) -> Vec<f32> {
+    assert!(width > 0 && pixels.len().is_multiple_of(width), "{} pixels don't form rows of width {}", pixels.len(), width);
+    let height = pixels.len() / width;
+    let mut shifted = vec![0.0; pixels.len()];
+    for y in 0..height {
+        for x in 0..width {
+            let (source_x, source_y) = (x as i64 - dx as i64, y as i64 - dy as i64);
+            if (0..width as i64).contains(&source_x) && (0..height as i64).contains(&source_y) {
+                shifted[y * width + x] = pixels[source_y as usize * width + source_x as usize];
+            }
+        }
+    }
+    shifted
+}
+
+// Label noise for robustness experiments: flips round(noise_fraction * n) randomly chosen labels to a
+// different, uniformly random class. labels_raw holds one class index per row, the result has the same shape.
+// The same seed always corrupts the same labels in the same way.
+pub fn corrupt_labels(labels_raw: &DMatrix<f32>, noise_fraction: f32, num_classes: usize, seed: u64) -> DMatrix<f32> {
+    assert!((0.0..=1.0).contains(&noise_fraction), "noise_fraction must be in [0, 1], got {}", noise_fraction);
+    assert!(num_classes >= 2, "Need at least 2 classes to flip a label to a different one");
+
+    let num_labels = labels_raw.nrows();
+    let num_corrupted = (noise_fraction * num_labels as f32).round() as usize;
+    let mut rng = StableRng::new(seed);
+    let mut indices: Vec<usize> = (0..num_labels).collect();
+    rng.shuffle(&mut indices);
+
+    let mut corrupted = labels_raw.clone();
+    for &idx in &indices[..num_corrupted] {
+        let original = labels_raw[(idx, 0)] as usize;
+        // Offset by 1..num_classes-1 so the new class is never the original one
+        let offset = 1 + (rng.next_u64() % (num_classes as u64 - 1)) as usize;
+        corrupted[(idx, 0)] = ((original + offset) % num_classes) as f32;
+    }
+    corrupted
+}
+
+// Batches of sample indices drawn with replacement, each sample with probability proportional to its weight
+// (e.g. inverse class frequency to oversample rare classes). Yields num_batches batches of batch_size indices,
+// to be used with select_rows or Dataset::get_batch. The counterpart of the shuffled batches in fit,
+// where every sample appears exactly once per epoch.
+pub struct WeightedBatchIterator {
+    // cumulative_weights[i] = weights[0] + ... + weights[i]
+    cumulative_weights: Vec<f64>,
+    batch_size: usize,
+    remaining_batches: usize,
+    rng: StableRng,
+}
+
+impl WeightedBatchIterator {
+    pub fn new(weights: &[f32], batch_size: usize, num_batches: usize, seed: u64) -> Self {
+        assert!(weights.iter().all(|&weight| weight >= 0.0 && weight.is_finite()), "Sample weights must be finite and non-negative");
+        let mut total = 0.0;
+        let cumulative_weights: Vec<f64> = weights.iter().map(|&weight| {
+            total += weight as f64;
+            total
+        }).collect();
+        assert!(total > 0.0, "At least one sample weight must be positive");
+
+        WeightedBatchIterator { cumulative_weights, batch_size, remaining_batches: num_batches, rng: StableRng::new(seed) }
+    }
+
+    fn sample_index(&mut self) -> usize {
+        let total = *self.cumulative_weights.last().expect("Checked in new that there are weights");
+        let target = self.rng.next_f64() * total;
+        // First sample whose cumulative weight exceeds the target, zero-weight samples are never picked
+        self.cumulative_weights.partition_point(|&cumulative| cumulative <= target)
+            .min(self.cumulative_weights.len() - 1)
+    }
+}
+
+impl Iterator for WeightedBatchIterator {
+    type Item = Vec<usize>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining_batches == 0 {
+            return None;
+        }
+        self.remaining_batches -= 1;
+        Some((0..self.batch_size).map(|_| self.sample_index()).collect())
+    }
+}
+
+// Anything that can hand out (inputs, targets) mini-batches by sample index, e.g. an in-memory
+// matrix pair or a loader that reads samples from disk on demand
+pub trait Dataset {
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // One sample per row, in the order of `indices`
+    fn get_batch(&self, indices: &[usize]) -> (DMatrix<f32>, DMatrix<f32>);
+}
+
+// Dataset backed by two matrices that are already fully loaded
+pub struct InMemoryDataset {
+    pub inputs: DMatrix<f32>,
+    pub targets: DMatrix<f32>,
+}
+
+impl InMemoryDataset {
+    pub fn new(inputs: DMatrix<f32>, targets: DMatrix<f32>) -> Self {
+        assert_eq!(inputs.nrows(), targets.nrows(), "Inputs ({}) and targets ({}) must have the same number of samples", inputs.nrows(), targets.nrows());
+        InMemoryDataset { inputs, targets }
+    }
+}
+
+impl Dataset for InMemoryDataset {
+    fn len(&self) -> usize {
+        self.inputs.nrows()
+    }
+
+    fn get_batch(&self, indices: &[usize]) -> (DMatrix<f32>, DMatrix<f32>) {
+        (select_rows(&self.inputs, indices), select_rows(&self.targets, indices))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn corrupts_exactly_the_requested_fraction_to_different_classes() {
+        let labels = DMatrix::from_fn(200, 1, |r, _| (r % 4) as f32);
+        let corrupted = corrupt_labels(&labels, 0.25, 4, 7);
+
+        let changed: Vec<usize> = (0..200).filter(|&r| corrupted[(r, 0)] != labels[(r, 0)]).collect();
+        assert_eq!(changed.len(), 50);
+        for r in 0..200 {
+            assert!(corrupted[(r, 0)] < 4.0);
+        }
+        assert_eq!(corrupted, corrupt_labels(&labels, 0.25, 4, 7));
+    }
+
+    #[test]
+    fn samples_are_drawn_in_proportion_to_their_weights() {
+        let weights = [8.0, 1.0, 1.0, 0.0];
+        let batches: Vec<Vec<usize>> = WeightedBatchIterator::new(&weights, 10, 200, 3).collect();
+        assert_eq!(batches.len(), 200);
+        assert!(batches.iter().all(|batch| batch.len() == 10));
+
+        let mut counts = [0usize; 4];
+        for &idx in batches.iter().flatten() {
+            counts[idx] += 1;
+        }
+        assert_eq!(counts[3], 0);
+        let heavy_fraction = counts[0] as f32 / 2000.0;
+        assert!((heavy_fraction - 0.8).abs() < 0.03, "Heavy sample drawn {} of the time", heavy_fraction);
+        assert!(counts[0] > 4 * counts[1] && counts[0] > 4 * counts[2]);
+    }
+}
@@ -0,0 +1,123 @@
+// Knowledge distillation (Hinton et al., 2015): training a small student network to match the
+// softened output distribution of a larger teacher
+
+use nalgebra::DMatrix;
+use crate::activation::ActivationFunction;
+use crate::loss::LossFunction;
+use crate::network::{NeuralNetwork, OutputGradient};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DistillationConfig {
+    // Both logit sets are divided by this before the softmax, > 1 exposes the teacher's "dark knowledge"
+    // about which wrong classes are almost right
+    pub temperature: f32,
+    // Weight of the soft (teacher) loss, the hard-label cross-entropy gets 1 - alpha.
+    // Ignored (treated as 1) when no hard targets are given.
+    pub alpha: f32,
+}
+
+impl DistillationConfig {
+    pub fn new(temperature: f32, alpha: f32) -> Self {
+        assert!(temperature > 0.0, "Distillation temperature must be positive, got {}", temperature);
+        assert!((0.0..=1.0).contains(&alpha), "alpha must be in [0, 1], got {}", alpha);
+        DistillationConfig { temperature, alpha }
+    }
+}
+
+// Row-wise softmax(logits / temperature)
+pub fn softmax_with_temperature(logits: &DMatrix<f32>, temperature: f32) -> DMatrix<f32> {
+    ActivationFunction::Softmax.activate(&(logits / temperature))
+}
+
+// Mean over the rows of KL(target || predicted) = sum target * ln(target / predicted).
+// Zero target probabilities contribute nothing, predicted ones are clipped like in the cross-entropy loss.
+pub fn kl_divergence(target_probabilities: &DMatrix<f32>, predicted_probabilities: &DMatrix<f32>) -> f32 {
+    assert_eq!(target_probabilities.shape(), predicted_probabilities.shape(), "Target and predicted distributions shape mismatch for KL divergence.");
+    if target_probabilities.nrows() == 0 {
+        return 0.0;
+    }
+    let total: f32 = target_probabilities.iter().zip(predicted_probabilities.iter())
+        .filter(|&(&t, _)| t > 0.0)
+        .map(|(&t, &p)| t * (t / p.max(f32::EPSILON)).ln())
+        .sum();
+    total / target_probabilities.nrows() as f32
+}
+
+impl NeuralNetwork {
+    // One distillation step on a Softmax student. teacher_logits are the teacher's pre-softmax outputs for
+    // the same inputs (teacher.logits(inputs) for a NeuralNetwork teacher). The loss is
+    //   alpha * T^2 * KL(softmax(teacher / T) || softmax(student / T)) + (1 - alpha) * CE(softmax(student), hard_targets)
+    // where the T^2 keeps the soft gradients on the same scale for any temperature. Returns that loss.
+    // The hard-label term is always the cross-entropy, whatever loss the student was built with.
+    pub fn train_batch_distill(
+        &mut self,
+        inputs: &DMatrix<f32>,
+        teacher_logits: &DMatrix<f32>,
+        hard_targets: Option<&DMatrix<f32>>,
+        config: &DistillationConfig,
+        learning_rate: f32
+    ) -> f32 {
+        let output_layer = self.dense_output_layer();
+        assert_eq!(output_layer.activation_fn, ActivationFunction::Softmax, "Distillation needs a Softmax output layer on the student");
+        let predictions = self.predict(inputs);
+        assert_eq!(teacher_logits.shape(), predictions.shape(), "Teacher logits and student outputs shape mismatch");
+        if predictions.nrows() == 0 {
+            return 0.0;
+        }
+
+        let temperature = config.temperature;
+        let alpha = if hard_targets.is_some() { config.alpha } else { 1.0 };
+        let batch_size = predictions.nrows() as f32;
+        let student_logits = &self.dense_output_layer().z_cache;
+        let soft_teacher = softmax_with_temperature(teacher_logits, temperature);
+        let soft_student = softmax_with_temperature(student_logits, temperature);
+
+        // d(T^2 * KL)/dz = T * (soft_student - soft_teacher), d(CE)/dz = predictions - targets, both averaged over the batch
+        let mut loss = alpha * temperature * temperature * kl_divergence(&soft_teacher, &soft_student);
+        let mut d_loss_dz = (alpha * temperature / batch_size) * (&soft_student - &soft_teacher);
+        if let Some(hard_targets) = hard_targets {
+            loss += (1.0 - alpha) * LossFunction::CrossEntropy.calculate(&predictions, hard_targets);
+            d_loss_dz += ((1.0 - alpha) / batch_size) * (&predictions - hard_targets);
+        }
+
+        let (gradients, _) = self.gradients_through_layers(OutputGradient::PreActivation(d_loss_dz));
+        self.apply_gradients(&gradients, learning_rate);
+        loss
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_student_moves_towards_the_teacher() {
+        let mut student = NeuralNetwork::new_seeded(LossFunction::CrossEntropy, 8);
+        student.add_dense_layer(3, 4, ActivationFunction::ReLU);
+        student.add_dense_layer(4, 3, ActivationFunction::Softmax);
+        let inputs = DMatrix::from_fn(12, 3, |r, c| ((r * 3 + c) as f32 * 0.41).sin());
+        // A linear teacher the student can match exactly
+        let teacher_logits = &inputs * DMatrix::from_row_slice(3, 3, &[3.0, -1.0, 0.0, 0.0, 2.0, -2.0, -1.0, 0.0, 3.0]);
+        let teacher_probabilities = ActivationFunction::Softmax.activate(&teacher_logits);
+
+        let divergence_before = kl_divergence(&teacher_probabilities, &student.infer(&inputs));
+        let config = DistillationConfig::new(2.0, 1.0);
+        for _ in 0..300 {
+            student.train_batch_distill(&inputs, &teacher_logits, None, &config, 0.5);
+        }
+        let divergence_after = kl_divergence(&teacher_probabilities, &student.infer(&inputs));
+        assert!(divergence_after < 0.5 * divergence_before, "KL went from {} to {}", divergence_before, divergence_after);
+    }
+
+    #[test]
+    fn the_hard_label_loss_is_the_cross_entropy_even_for_an_mse_student() {
+        let mut student = NeuralNetwork::new_seeded(LossFunction::MeanSquaredError, 9);
+        student.add_dense_layer(2, 3, ActivationFunction::Softmax);
+        let inputs = DMatrix::from_row_slice(2, 2, &[0.5, -1.0, 1.5, 0.2]);
+        let hard_targets = DMatrix::from_row_slice(2, 3, &[1.0, 0.0, 0.0, 0.0, 0.0, 1.0]);
+
+        let predictions = student.infer(&inputs);
+        let loss = student.train_batch_distill(&inputs, &DMatrix::zeros(2, 3), Some(&hard_targets), &DistillationConfig::new(1.0, 0.0), 0.1);
+        assert!((loss - LossFunction::CrossEntropy.calculate(&predictions, &hard_targets)).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,49 @@
+// GraphViz export of the architecture, render the output with e.g. `dot -Tsvg net.dot -o net.svg`
+
+use std::fmt::Write;
+use crate::network::NeuralNetwork;
+
+impl NeuralNetwork {
+    // One box per layer labelled "Dense input -> output" plus its activation (other layers get their
+    // layer_type instead of "Dense"), with an edge between consecutive layers (labelled with the number
+    // of values passed along), top to bottom
+    pub fn to_dot(&self) -> String {
+        let layers = self.get_layers();
+        // Writing into a String can't fail, so the fmt::Results below are safe to unwrap
+        let mut dot = String::new();
+        writeln!(dot, "digraph NeuralNetwork {{").unwrap();
+        writeln!(dot, "    rankdir=TB;").unwrap();
+        writeln!(dot, "    node [shape=box, style=rounded];").unwrap();
+        for (i, layer) in layers.iter().enumerate() {
+            match layer.as_dense() {
+                Some(dense) => writeln!(dot, "    layer{} [label=\"Dense {} -> {}\\n{}\"];", i, layer.input_size(), layer.output_size(), dense.activation_fn).unwrap(),
+                None => writeln!(dot, "    layer{} [label=\"{} {} -> {}\"];", i, layer.layer_type(), layer.input_size(), layer.output_size()).unwrap(),
+            }
+        }
+        for (i, layer) in layers.iter().enumerate().skip(1) {
+            writeln!(dot, "    layer{} -> layer{} [label=\"{}\"];", i - 1, i, layer.input_size()).unwrap();
+        }
+        writeln!(dot, "}}").unwrap();
+        dot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::activation::ActivationFunction;
+    use crate::loss::LossFunction;
+    use crate::network::NeuralNetwork;
+
+    #[test]
+    fn has_one_labelled_node_per_layer() {
+        let mut network = NeuralNetwork::new_seeded(LossFunction::CrossEntropy, 1);
+        network.add_dense_layer(4, 8, ActivationFunction::ReLU);
+        network.add_dense_layer(8, 3, ActivationFunction::Softmax);
+
+        let dot = network.to_dot();
+        assert_eq!(dot.matches("[label=\"Dense").count(), 2);
+        assert!(dot.contains("layer0 [label=\"Dense 4 -> 8\\nrelu\"];"));
+        assert!(dot.contains("layer1 [label=\"Dense 8 -> 3\\nsoftmax\"];"));
+        assert!(dot.contains("layer0 -> layer1 [label=\"8\"];"));
+    }
+}
@@ -0,0 +1,143 @@
+// Elastic Weight Consolidation (Kirkpatrick et al., 2017) for continual learning:
+// measuring which weights matter for a task, so training on the next task can leave them alone
+
+use nalgebra::DMatrix;
+use crate::layer::{Layer, LayerGradients};
+use crate::network::{output_layer_gradient, NeuralNetwork};
+use crate::scalar::Real;
+
+// lambda * sum_i F_i * (w_i - w_i_old)^2 over all weights, where w_old are the weights after the old task and
+// F its Fisher diagonal: weights that mattered for the old task are anchored, the others stay free to move.
+// Biases aren't penalized. Register it with NeuralNetwork::set_ewc_penalty before training on the new task.
+#[derive(Debug, Clone)]
+pub struct EwcPenalty<T: Real = f32> {
+    pub lambda: T,
+    anchor_weights: Vec<DMatrix<T>>,
+    fisher: Vec<DMatrix<T>>,
+}
+
+impl<T: Real> EwcPenalty<T> {
+    // Anchors the network's current weights, fisher usually comes from network.fisher_diagonal on the old task's data
+    pub fn new(lambda: T, network: &NeuralNetwork<T>, fisher: Vec<DMatrix<T>>) -> Self {
+        let penalty = EwcPenalty {
+            lambda,
+            anchor_weights: network.weight_matrices(),
+            fisher,
+        };
+        penalty.check_shapes(network.get_layers());
+        penalty
+    }
+
+    // Layers without parameters have empty anchors and Fisher matrices and add nothing
+    pub fn penalty(&self, layers: &[Box<dyn Layer<T>>]) -> T {
+        let weighted_sum: T = layers.iter().zip(self.anchor_weights.iter()).zip(self.fisher.iter())
+            .filter_map(|((layer, anchor), fisher)| layer.parameters().map(|(weights, _)| fisher.component_mul(&(weights - anchor).map(|d| d * d)).sum()))
+            .fold(T::zero(), |total, layer_sum| total + layer_sum);
+        self.lambda * weighted_sum
+    }
+
+    // dPenalty/dW = 2 * lambda * F * (W - W_old), added onto the loss gradients
+    pub(crate) fn add_gradients(&self, layers: &[Box<dyn Layer<T>>], gradients: &mut [LayerGradients<T>]) {
+        for (((layer, anchor), fisher), layer_gradients) in layers.iter().zip(self.anchor_weights.iter()).zip(self.fisher.iter()).zip(gradients.iter_mut()) {
+            if let Some((weights, _)) = layer.parameters() {
+                layer_gradients.weights += fisher.component_mul(&(weights - anchor)) * (T::cast(2.0) * self.lambda);
+            }
+        }
+    }
+
+    pub(crate) fn check_shapes(&self, layers: &[Box<dyn Layer<T>>]) {
+        assert_eq!(self.fisher.len(), layers.len(), "Expected one Fisher matrix per layer ({}), got {}", layers.len(), self.fisher.len());
+        assert_eq!(self.anchor_weights.len(), layers.len(), "EWC penalty was anchored on {} layers, the network has {}", self.anchor_weights.len(), layers.len());
+        for (i, ((layer, anchor), fisher)) in layers.iter().zip(self.anchor_weights.iter()).zip(self.fisher.iter()).enumerate() {
+            let weights_shape = layer.parameters().map_or((0, 0), |(weights, _)| weights.shape());
+            assert_eq!(fisher.shape(), weights_shape, "Fisher matrix of layer {} doesn't match its weights", i);
+            assert_eq!(anchor.shape(), weights_shape, "Anchor weights of layer {} don't match its weights", i);
+        }
+    }
+}
+
+impl NeuralNetwork {
+    // Diagonal of the (empirical) Fisher information matrix for the weights: the square of every
+    // per-sample gradient of the log-likelihood, averaged over the samples. One matrix per layer,
+    // shaped like that layer's weights (empty for layers without parameters). Large values mark weights the predictions on this data depend on.
+    // labels_raw holds one class index per row. The network's own loss is used as the negative
+    // log-likelihood (exact for CrossEntropy, Gaussian up to a constant for MeanSquaredError). Weight decay and
+    // an already registered EWC penalty aren't part of the likelihood, so they're left out of the gradients.
+    pub fn fisher_diagonal(&mut self, inputs: &DMatrix<f32>, labels_raw: &DMatrix<f32>) -> Vec<DMatrix<f32>> {
+        assert_eq!(inputs.nrows(), labels_raw.nrows(), "Got {} inputs but {} labels", inputs.nrows(), labels_raw.nrows());
+        let num_outputs = self.get_layers().last().expect("Network has no layers").output_size();
+        let mut fisher: Vec<DMatrix<f32>> = self.get_layers().iter()
+            .map(|layer| LayerGradients::zeros_like(layer.as_ref()).weights)
+            .collect();
+        if inputs.nrows() == 0 {
+            return fisher;
+        }
+
+        // Batch gradients are averaged over the samples before they could be squared, so this has to go one sample at a time
+        for (i, &label) in labels_raw.column(0).iter().enumerate() {
+            assert!((label as usize) < num_outputs, "Label {} of sample {} is out of range for {} outputs", label, i, num_outputs);
+            let mut target = DMatrix::zeros(1, num_outputs);
+            target[(0, label as usize)] = 1.0;
+            let predictions = self.predict(&inputs.rows(i, 1).into_owned());
+            let output_gradient = output_layer_gradient(self.get_layers(), self.get_loss_fn(), &predictions, &target);
+            let (gradients, _) = self.gradients_through_layers(output_gradient);
+            for (layer_fisher, layer_gradients) in fisher.iter_mut().zip(gradients.iter()) {
+                *layer_fisher += layer_gradients.weights.map(|g| g * g);
+            }
+        }
+        let num_samples = inputs.nrows() as f32;
+        for layer_fisher in fisher.iter_mut() {
+            layer_fisher.unscale_mut(num_samples);
+        }
+        fisher
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activation::ActivationFunction;
+    use crate::loss::LossFunction;
+
+    #[test]
+    fn fisher_is_non_negative_and_largest_for_weights_with_large_gradients() {
+        let mut network = NeuralNetwork::new_seeded(LossFunction::CrossEntropy, 3);
+        network.add_dense_layer(2, 2, ActivationFunction::Softmax);
+        // Feature 0 is 50 times larger, and so are the gradients of the weights it feeds
+        let inputs = DMatrix::from_row_slice(4, 2, &[5.0, 0.1, -5.0, 0.1, 5.0, -0.1, -5.0, -0.1]);
+        let labels = DMatrix::from_column_slice(4, 1, &[0.0, 1.0, 1.0, 0.0]);
+
+        let fisher = network.fisher_diagonal(&inputs, &labels);
+        assert!(fisher[0].iter().all(|&f| f >= 0.0));
+        for output in 0..2 {
+            assert!(fisher[0][(0, output)] > 100.0 * fisher[0][(1, output)]);
+        }
+
+        // Weight decay isn't part of the likelihood
+        network.set_weight_decay(10.0);
+        assert_eq!(network.fisher_diagonal(&inputs, &labels), fisher);
+    }
+
+    #[test]
+    fn a_strong_penalty_anchors_only_the_important_weights() {
+        let mut network = NeuralNetwork::new_seeded(LossFunction::MeanSquaredError, 4);
+        network.add_dense_layer(2, 2, ActivationFunction::Linear);
+        let anchor = network.weight_matrices();
+        // Only weight (0, 0) mattered for the old task
+        let mut fisher = DMatrix::zeros(2, 2);
+        fisher[(0, 0)] = 1.0;
+        network.set_ewc_penalty(Some(EwcPenalty::new(2.0, &network, vec![fisher])));
+
+        // The new task pulls every weight away from the anchor
+        let inputs = DMatrix::from_row_slice(2, 2, &[1.0, 0.5, 0.5, 1.0]);
+        let targets = DMatrix::from_element(2, 2, 5.0);
+        for _ in 0..50 {
+            network.train_batch(&inputs, &targets, 0.1);
+        }
+
+        let moved = (&network.weight_matrices()[0] - &anchor[0]).abs();
+        for (row, col) in [(0, 1), (1, 0), (1, 1)] {
+            assert!(moved[(0, 0)] * 5.0 < moved[(row, col)], "Anchored weight moved {}, weight ({}, {}) {}", moved[(0, 0)], row, col, moved[(row, col)]);
+        }
+    }
+}
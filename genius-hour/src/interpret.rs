@@ -0,0 +1,718 @@
+// Interpretability tools: looking at what a trained network responds to
+
+use nalgebra::{DMatrix, DVector};
+use rand::Rng;
+use rand::seq::SliceRandom;
+use crate::layer::init_std_dev;
+use crate::metrics::argmax;
+use crate::network::{input_gradient_through, NeuralNetwork, OutputGradient};
+use crate::rng::StableRng;
+
+// Step size of the gradient descent in counterfactual
+const COUNTERFACTUAL_STEP_SIZE: f32 = 0.1;
+
+impl NeuralNetwork {
+    // Activation maximization ("what does the network think a 7 looks like").
+    // Starts from uniform noise in [0, 1) and does gradient ascent on the input to maximize
+    // the logit of `class`, clamping every pixel back into [0, 1] after each step.
+    // Returns the optimized input as a 1 x input_size row (784 pixels for MNIST).
+    pub fn maximize_activation(&mut self, class: usize, steps: usize, step_size: f32) -> DMatrix<f32> {
+        let input_size = self.get_layers().first().expect("Network has no layers").input_size();
+        let mut rng = rand::rng();
+        let mut input = DMatrix::from_fn(1, input_size, |_, _| rng.random::<f32>());
+
+        for _ in 0..steps {
+            let gradient = self.logit_input_gradient(&input, class);
+            // Ascend instead of descend
+            input += step_size * gradient;
+            input.apply(|pixel| *pixel = pixel.clamp(0.0, 1.0));
+        }
+        input
+    }
+
+    // Sensitivity of one network output (after the last activation) to every weight: dOutput_k/dW per layer.
+    // Backpropagates a one-hot upstream gradient instead of a loss gradient and doesn't update anything.
+    // With several input rows the gradients are averaged over the rows, like the loss gradients are.
+    pub fn output_weight_gradient(&mut self, input: &DMatrix<f32>, output_idx: usize) -> Vec<DMatrix<f32>> {
+        let outputs = self.predict(input);
+        let (gradients, _) = self.gradients_through_layers(select_output(&outputs, output_idx));
+        gradients.into_iter().map(|layer_gradients| layer_gradients.weights).collect()
+    }
+
+    // Gradient of one network output (after the last activation, e.g. a class probability)
+    // w.r.t. every input feature, one row per input row
+    pub fn output_input_gradient(&mut self, inputs: &DMatrix<f32>, output_idx: usize) -> DMatrix<f32> {
+        let outputs = self.predict(inputs);
+        self.input_gradient_through_layers(select_output(&outputs, output_idx))
+    }
+
+    // Integrated gradients: (input - baseline) times the average gradient of the class output along
+    // the straight path from baseline to input (midpoint rule over `steps` points).
+    // The attributions sum to roughly output(input) - output(baseline) for that class.
+    pub fn integrated_gradients(&mut self, input: &DMatrix<f32>, baseline: &DMatrix<f32>, class: usize, steps: usize) -> DMatrix<f32> {
+        assert_eq!(input.shape(), baseline.shape(), "Input and baseline shape mismatch");
+        assert_eq!(input.nrows(), 1, "integrated_gradients expects a single sample");
+        let steps = steps.max(1);
+        let difference = input - baseline;
+
+        // All interpolation points as the rows of a single batch
+        let mut path = DMatrix::zeros(steps, input.ncols());
+        for k in 0..steps {
+            let alpha = (k as f32 + 0.5) / steps as f32;
+            path.set_row(k, &(baseline + alpha * &difference).row(0));
+        }
+        let gradients = self.output_input_gradient(&path, class);
+        let average_gradient = DMatrix::from_iterator(1, input.ncols(), gradients.row_mean().iter().copied());
+
+        difference.component_mul(&average_gradient)
+    }
+
+    // Numerical rank of each layer's weight matrix, i.e. how much of the layer's capacity is used.
+    // Singular values below max(rows, cols) * f32::EPSILON * largest singular value count as zero (same rule as NumPy).
+    // Layers without parameters get 0.
+    pub fn rank_analysis(&self) -> Vec<usize> {
+        self.get_layers().iter().map(|layer| match layer.parameters() {
+            Some((weights, _)) => {
+                let singular_values = weights.clone().svd(false, false).singular_values;
+                let tolerance = weights.nrows().max(weights.ncols()) as f32 * f32::EPSILON * singular_values.max();
+                singular_values.iter().filter(|&&sigma| sigma > tolerance).count()
+            }
+            None => 0,
+        }).collect()
+    }
+
+    // Intrinsic dimensionality of layer layer_idx's representation of inputs: the number of principal
+    // components needed to explain variance_threshold (e.g. 0.95) of the variance of its activations.
+    // Much lower than the layer width means the layer's output lives in a small subspace. 0 when the
+    // activations don't vary at all.
+    pub fn representation_dimensionality(&self, inputs: &DMatrix<f32>, layer_idx: usize, variance_threshold: f32) -> usize {
+        assert!(layer_idx < self.get_layers().len(), "Layer index {} out of range for {} layers", layer_idx, self.get_layers().len());
+        let mut activations = self.layer_outputs(inputs).swap_remove(layer_idx);
+        for mut column in activations.column_iter_mut() {
+            // Shifting by the first value first keeps a constant neuron at exactly 0
+            let first_value = column.get(0).copied().unwrap_or(0.0);
+            column.add_scalar_mut(-first_value);
+            let mean = column.mean();
+            column.add_scalar_mut(-mean);
+        }
+
+        // The variance along every principal component is proportional to its squared singular value,
+        // and svd returns them sorted from largest to smallest
+        let component_variances: Vec<f32> = activations.svd(false, false).singular_values.iter().map(|sigma| sigma * sigma).collect();
+        let total_variance: f32 = component_variances.iter().sum();
+        if total_variance == 0.0 {
+            return 0;
+        }
+        let mut explained_variance = 0.0;
+        for (i, variance) in component_variances.iter().enumerate() {
+            explained_variance += variance;
+            if explained_variance >= variance_threshold * total_variance {
+                return i + 1;
+            }
+        }
+        component_variances.len()
+    }
+
+    // Approximate Shapley values of every input feature for the class predicted for `input`,
+    // by permutation sampling: for each sample, features are switched from `baseline` to their real
+    // value in a random order and each one is credited with the change in that class's probability.
+    // input and baseline are 1 x input_size rows. Only uses the inference path.
+    pub fn shap_attributions(&self, input: &DMatrix<f32>, baseline: &DMatrix<f32>, samples: usize) -> DVector<f32> {
+        assert_eq!(input.shape(), baseline.shape(), "Input and baseline shape mismatch");
+        assert_eq!(input.nrows(), 1, "shap_attributions expects a single sample");
+        let num_features = input.ncols();
+        let class = argmax(self.infer(input).iter());
+
+        let mut rng = rand::rng();
+        let mut order: Vec<usize> = (0..num_features).collect();
+        let mut attributions = DVector::zeros(num_features);
+        for _ in 0..samples {
+            order.shuffle(&mut rng);
+
+            // Row k holds the baseline with the first k features of `order` switched to the input,
+            // so a single batched inference covers the whole permutation
+            let mut path = DMatrix::zeros(num_features + 1, num_features);
+            let mut current = baseline.clone();
+            path.set_row(0, &current.row(0));
+            for (k, &feature) in order.iter().enumerate() {
+                current[(0, feature)] = input[(0, feature)];
+                path.set_row(k + 1, &current.row(0));
+            }
+
+            let class_probabilities = self.infer(&path).column(class).clone_owned();
+            for (k, &feature) in order.iter().enumerate() {
+                attributions[feature] += class_probabilities[k + 1] - class_probabilities[k];
+            }
+        }
+        if samples > 0 {
+            attributions /= samples as f32;
+        }
+        attributions
+    }
+
+    // Mean activation of every neuron in layer layer_idx, computed separately for each class:
+    // row c is the average layer output over the inputs labelled c (num_classes x layer width).
+    // Classes without any samples get a row of zeros. labels_raw holds one class index per row.
+    pub fn class_conditional_activations(
+        &mut self,
+        inputs: &DMatrix<f32>,
+        labels_raw: &DMatrix<f32>,
+        num_classes: usize,
+        layer_idx: usize
+    ) -> DMatrix<f32> {
+        assert!(layer_idx < self.get_layers().len(), "Layer index {} out of range for {} layers", layer_idx, self.get_layers().len());
+        assert_eq!(inputs.nrows(), labels_raw.nrows(), "Got {} inputs but {} labels", inputs.nrows(), labels_raw.nrows());
+
+        let activations = self.layer_outputs(inputs).swap_remove(layer_idx);
+        let mut sums = DMatrix::<f32>::zeros(num_classes, activations.ncols());
+        let mut class_counts = vec![0usize; num_classes];
+        for (row, &label) in activations.row_iter().zip(labels_raw.column(0).iter()) {
+            let class = label as usize;
+            let mut class_sum = sums.row_mut(class);
+            class_sum += row;
+            class_counts[class] += 1;
+        }
+        for (class, &count) in class_counts.iter().enumerate() {
+            if count > 0 {
+                sums.row_mut(class).unscale_mut(count as f32);
+            }
+        }
+        sums
+    }
+
+    // Pairs (i, j), i < j, of neurons in layer layer_idx whose activations over inputs have a Pearson
+    // correlation above threshold, i.e. neurons that mostly duplicate each other and could be merged or pruned.
+    // Neurons with a constant activation (e.g. dead ones) have no correlation and are never reported.
+    pub fn correlated_neurons(&self, inputs: &DMatrix<f32>, layer_idx: usize, threshold: f32) -> Vec<(usize, usize)> {
+        assert!(layer_idx < self.get_layers().len(), "Layer index {} out of range for {} layers", layer_idx, self.get_layers().len());
+        let mut activations = self.layer_outputs(inputs).swap_remove(layer_idx);
+
+        // Centered and scaled to unit norm, the correlation of two columns is then their dot product
+        let is_constant: Vec<bool> = activations.column_iter_mut().map(|mut column| {
+            let mean = column.mean();
+            column.add_scalar_mut(-mean);
+            let norm = column.norm();
+            if norm > 0.0 {
+                column.unscale_mut(norm);
+            }
+            norm == 0.0
+        }).collect();
+
+        let mut pairs = Vec::new();
+        for i in 0..activations.ncols() {
+            for j in (i + 1)..activations.ncols() {
+                if !is_constant[i] && !is_constant[j] && activations.column(i).dot(&activations.column(j)) > threshold {
+                    pairs.push((i, j));
+                }
+            }
+        }
+        pairs
+    }
+
+    // Compresses layer layer_idx by merging redundant neurons, e.g. the pairs found by correlated_neurons.
+    // For every (keep, remove) pair, remove's outgoing weights (its row in the next layer) are added to keep's,
+    // then remove is deleted from the layer. Exact duplicates keep the network's output unchanged.
+    // Pairs are applied in order, a neuron that was already merged away stands for the neuron it went into.
+    // Optimizer state and EWC penalties still have the old shapes, so set them again before training.
+    // Both layers have to be DenseLayers.
+    pub fn merge_neurons(&mut self, layer_idx: usize, pairs: &[(usize, usize)]) {
+        let num_layers = self.get_layers().len();
+        assert!(layer_idx + 1 < num_layers, "Layer {} has no next layer to merge into ({} layers)", layer_idx, num_layers);
+        assert!(self.dense_layer(layer_idx).is_some() && self.dense_layer(layer_idx + 1).is_some(), "Layers {} and {} must both be dense to merge neurons", layer_idx, layer_idx + 1);
+        let width = self.get_layers()[layer_idx].output_size();
+
+        // merged_into[n] is the neuron n was merged into, following the chain gives its representative
+        let mut merged_into: Vec<Option<usize>> = vec![None; width];
+        let representative = |merged_into: &[Option<usize>], mut neuron: usize| {
+            while let Some(target) = merged_into[neuron] {
+                neuron = target;
+            }
+            neuron
+        };
+        let (layers_before, layers_after) = self.get_layers_mut().split_at_mut(layer_idx + 1);
+        let next_layer = layers_after[0].as_dense_mut().expect("Checked above that the next layer is dense");
+        for &(keep, remove) in pairs {
+            assert!(keep < width && remove < width, "Pair ({}, {}) out of range for a layer of width {}", keep, remove, width);
+            let (keep, remove) = (representative(&merged_into, keep), representative(&merged_into, remove));
+            if keep == remove {
+                continue;
+            }
+            let removed_row = next_layer.weights.row(remove).into_owned();
+            let mut kept_row = next_layer.weights.row_mut(keep);
+            kept_row += removed_row;
+            merged_into[remove] = Some(keep);
+        }
+
+        // Delete from the back so the remaining indices stay valid
+        let layer = layers_before[layer_idx].as_dense_mut().expect("Checked above that the layer is dense");
+        for neuron in (0..width).rev().filter(|&neuron| merged_into[neuron].is_some()) {
+            layer.weights = layer.weights.clone().remove_column(neuron);
+            layer.biases = layer.biases.clone().remove_row(neuron);
+            next_layer.weights = next_layer.weights.clone().remove_row(neuron);
+        }
+        layer.clear_cache();
+        next_layer.clear_cache();
+    }
+
+    // Which classes a hidden neuron pushes towards: starting from the activations for an all-zero input,
+    // the neuron's activation is raised by 1 and the rest of the network is run from there.
+    // Returns the resulting change of every output logit (the last layer's Z, before the activation),
+    // so positive entries are classes this neuron votes for.
+    pub fn neuron_class_influence(&mut self, layer_idx: usize, neuron_idx: usize) -> DVector<f32> {
+        let layers = self.get_layers();
+        assert!(layer_idx + 1 < layers.len(), "Layer {} is not a hidden layer of this {}-layer network", layer_idx, layers.len());
+        assert!(neuron_idx < layers[layer_idx].output_size(), "Neuron {} out of range for layer {} with {} neurons", neuron_idx, layer_idx, layers[layer_idx].output_size());
+
+        let representative_input = DMatrix::zeros(1, layers[0].input_size());
+        let baseline = self.layer_outputs(&representative_input).swap_remove(layer_idx);
+        // Row 0 is the baseline, row 1 the same activations with the neuron bumped, so one pass covers both
+        let mut activations = DMatrix::from_fn(2, baseline.ncols(), |_, j| baseline[(0, j)]);
+        activations[(1, neuron_idx)] += 1.0;
+
+        let (_, remaining_hidden) = layers[layer_idx + 1..].split_last().expect("Checked above that a layer follows");
+        for layer in remaining_hidden {
+            activations = layer.infer(&activations);
+        }
+        let logits = self.dense_output_layer().weighted_sum(&activations);
+        (logits.row(1) - logits.row(0)).transpose()
+    }
+
+    // Counterfactual explanation: a small change to `input` (1 x input_size) that makes the network predict
+    // target_class. Gradient descent on the input minimizing
+    //   loss(network(x), one_hot(target_class)) + proximity_weight * ||x - input||^2
+    // Returns the closest (in L2) iterate that was classified as target_class, or the last iterate if none was.
+    pub fn counterfactual(&mut self, input: &DMatrix<f32>, target_class: usize, steps: usize, proximity_weight: f32) -> DMatrix<f32> {
+        assert_eq!(input.nrows(), 1, "counterfactual takes a single input row, got {}", input.nrows());
+        let num_outputs = self.get_layers().last().expect("Network has no layers").output_size();
+        let mut target = DMatrix::zeros(1, num_outputs);
+        target[(0, target_class)] = 1.0;
+
+        let mut candidate = input.clone();
+        let mut best: Option<(f32, DMatrix<f32>)> = None;
+        for _ in 0..steps {
+            let loss_gradient = self.input_gradient(&candidate, &target);
+            let proximity_gradient = (2.0 * proximity_weight) * (&candidate - input);
+            candidate -= COUNTERFACTUAL_STEP_SIZE * (loss_gradient + proximity_gradient);
+
+            if argmax(self.infer(&candidate).iter()) == target_class {
+                let distance = (&candidate - input).norm();
+                if best.as_ref().is_none_or(|(best_distance, _)| distance < *best_distance) {
+                    best = Some((distance, candidate.clone()));
+                }
+            }
+        }
+        best.map_or(candidate, |(_, closest)| closest)
+    }
+
+    // Input reconstruction: an input whose layer_idx activation (after the activation function) matches
+    // target_activation, to see how much of the input a layer's representation still holds. Gradient descent
+    // on ||activation(x) - target_activation||^2 starting from an all-zero input, one input row per target row.
+    pub fn invert_activation(&mut self, target_activation: &DMatrix<f32>, layer_idx: usize, steps: usize, step_size: f32) -> DMatrix<f32> {
+        assert!(layer_idx < self.get_layers().len(), "Layer index {} out of range for {} layers", layer_idx, self.get_layers().len());
+        let layers = &mut self.get_layers_mut()[..=layer_idx];
+        let layer = &layers[layer_idx];
+        assert_eq!(target_activation.ncols(), layer.output_size(), "Layer {} has {} outputs, the target has {}", layer_idx, layer.output_size(), target_activation.ncols());
+
+        let mut input = DMatrix::zeros(target_activation.nrows(), layers[0].input_size());
+        for _ in 0..steps {
+            let mut activation = input.clone();
+            for layer in layers.iter_mut() {
+                activation = layer.forward(&activation);
+            }
+            let d_loss_da = (activation - target_activation) * 2.0;
+            input -= step_size * input_gradient_through(layers, OutputGradient::Output(d_loss_da));
+        }
+        input
+    }
+
+    // Gradient-based feature ranking: the mean |dLoss/dx_i| over all samples for every input feature,
+    // sorted from most to least important as (feature index, score). Features at the bottom barely
+    // affect the loss anywhere in the data and are candidates to drop.
+    pub fn rank_features(&mut self, inputs: &DMatrix<f32>, targets: &DMatrix<f32>) -> Vec<(usize, f32)> {
+        let gradient = self.input_gradient(inputs, targets);
+        let num_samples = gradient.nrows().max(1) as f32;
+        let mut ranking: Vec<(usize, f32)> = gradient.column_iter()
+            .map(|column| column.iter().map(|g| g.abs()).sum::<f32>() / num_samples)
+            .enumerate()
+            .collect();
+        ranking.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranking
+    }
+
+    // Occlusion sensitivity of a single flattened square image (e.g. 28x28 MNIST): a patch_size x patch_size
+    // square of zeros is slid over the image, stride pixels at a time, and map[(y, x)] is how much the
+    // probability of `class` drops when the patch's top-left corner is at (y * stride, x * stride).
+    // The map is floor((side - patch_size) / stride) + 1 on each side, like a pooling layer's output.
+    pub fn occlusion_map(&self, input: &[f32], class: usize, patch_size: usize, stride: usize) -> DMatrix<f32> {
+        let side = (input.len() as f64).sqrt().round() as usize;
+        assert_eq!(side * side, input.len(), "occlusion_map expects a square image, got {} pixels", input.len());
+        assert!(patch_size > 0 && patch_size <= side && stride > 0, "Invalid patch_size {} or stride {} for a {}x{} image", patch_size, stride, side, side);
+        let map_side = (side - patch_size) / stride + 1;
+
+        // Row 0 is the untouched image, then one occluded copy per map position
+        let mut batch = DMatrix::from_fn(map_side * map_side + 1, input.len(), |_, pixel| input[pixel]);
+        for map_y in 0..map_side {
+            for map_x in 0..map_side {
+                let row = 1 + map_y * map_side + map_x;
+                for y in map_y * stride..map_y * stride + patch_size {
+                    for x in map_x * stride..map_x * stride + patch_size {
+                        batch[(row, y * side + x)] = 0.0;
+                    }
+                }
+            }
+        }
+        let probabilities = self.infer(&batch);
+        let original_probability = probabilities[(0, class)];
+        DMatrix::from_fn(map_side, map_side, |map_y, map_x| original_probability - probabilities[(1 + map_y * map_side + map_x, class)])
+    }
+
+    // Hidden neurons whose output is exactly zero on every row of inputs (typically ReLUs stuck in the
+    // negative region). dead[i] lists the dead neurons of layer i, the output layer is never reported.
+    pub fn dead_neurons(&self, inputs: &DMatrix<f32>) -> Vec<Vec<usize>> {
+        let mut outputs = self.layer_outputs(inputs);
+        outputs.pop();
+        outputs.iter().map(|layer_output| {
+            (0..layer_output.ncols()).filter(|&neuron| layer_output.column(neuron).iter().all(|&a| a == 0.0)).collect()
+        }).collect()
+    }
+
+    // Gives the neurons found by dead_neurons a fresh start instead of retraining the whole network:
+    // their incoming weights are re-sampled like a new layer's (from `seed`) and their bias reset to 0.
+    // Live neurons and all outgoing weights are left alone. Returns how many neurons were revived.
+    pub fn revive_dead_neurons(&mut self, inputs: &DMatrix<f32>, seed: u64) -> usize {
+        let dead = self.dead_neurons(inputs);
+        let mut rng = StableRng::new(seed);
+        let mut num_revived = 0;
+        for (layer, dead_in_layer) in self.get_layers_mut().iter_mut().zip(dead.iter()) {
+            // Neurons only exist in DenseLayers, the dead outputs of other layers are left alone
+            if let Some(layer) = layer.as_dense_mut() {
+                let std_dev = init_std_dev(layer.input_size(), layer.activation_fn);
+                for &neuron in dead_in_layer {
+                    for weight in layer.weights.column_mut(neuron).iter_mut() {
+                        *weight = (rng.next_standard_normal() * std_dev) as f32;
+                    }
+                    layer.biases[neuron] = 0.0;
+                }
+                num_revived += dead_in_layer.len();
+            }
+        }
+        num_revived
+    }
+}
+
+// Upstream gradient that selects output output_idx: dOutput_k/dA is one-hot in column k for every row.
+// The last layer's activation turns it into dOutput_k/dZ (for Softmax a row of the jacobian).
+fn select_output(outputs: &DMatrix<f32>, output_idx: usize) -> OutputGradient<f32> {
+    let mut d_output_da = DMatrix::zeros(outputs.nrows(), outputs.ncols());
+    d_output_da.column_mut(output_idx).fill(1.0);
+    OutputGradient::Output(d_output_da)
+}
+
+// Partial dependence of one network output on one input feature: for every value, every sample of inputs
+// gets its feature_idx replaced by that value (the other features keep their real values) and the
+// output_idx predictions are averaged over the samples. For tabular data, the curve shows how the
+// prediction moves with that feature on average.
+pub fn partial_dependence(network: &NeuralNetwork, inputs: &DMatrix<f32>, feature_idx: usize, values: &[f32], output_idx: usize) -> Vec<f32> {
+    assert!(feature_idx < inputs.ncols(), "Feature {} out of range for {} input features", feature_idx, inputs.ncols());
+    let mut modified = inputs.clone();
+    values.iter().map(|&value| {
+        modified.column_mut(feature_idx).fill(value);
+        network.infer(&modified).column(output_idx).mean()
+    }).collect()
+}
+
+// Ablation study: for every group of input features, the accuracy once those features are set to 0 in every
+// sample. The bigger the drop from the unmasked accuracy, the more the network relies on the group.
+// labels_raw holds one class index per row.
+pub fn ablation_study(network: &NeuralNetwork, inputs: &DMatrix<f32>, labels_raw: &DMatrix<f32>, feature_groups: &[Vec<usize>]) -> Vec<f32> {
+    if inputs.nrows() == 0 {
+        return vec![0.0; feature_groups.len()];
+    }
+    feature_groups.iter().map(|group| {
+        let mut masked = inputs.clone();
+        for &feature_idx in group {
+            assert!(feature_idx < inputs.ncols(), "Feature {} out of range for {} input features", feature_idx, inputs.ncols());
+            masked.column_mut(feature_idx).fill(0.0);
+        }
+        let correct_predictions = network.predict_classes_batch(&masked).iter().zip(labels_raw.column(0).iter())
+            .filter(|&(&predicted, &label)| predicted == label as usize)
+            .count();
+        correct_predictions as f32 / inputs.nrows() as f32
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activation::ActivationFunction;
+    use crate::layer::DenseLayer;
+    use crate::loss::LossFunction;
+
+    // Single Softmax layer where feature c raises the logit of class c and lowers the others
+    fn feature_per_class_network(num_classes: usize) -> NeuralNetwork {
+        let weights = DMatrix::from_fn(num_classes, num_classes, |input, output| if input == output { 2.0 } else { -1.0 });
+        let mut network = NeuralNetwork::new(LossFunction::CrossEntropy);
+        network.add_layer(DenseLayer::from_parameters(weights, DVector::zeros(num_classes), ActivationFunction::Softmax));
+        network
+    }
+
+    #[test]
+    fn maximized_input_is_classified_as_the_target_class() {
+        let mut network = feature_per_class_network(3);
+        for class in 0..3 {
+            let input = network.maximize_activation(class, 50, 0.1);
+            assert!(input.iter().all(|pixel| (0.0..=1.0).contains(pixel)));
+            assert_eq!(network.predict_class(input.as_slice()).unwrap(), class);
+        }
+    }
+
+    #[test]
+    fn output_weight_gradient_matches_finite_differences() {
+        let mut network = NeuralNetwork::new_seeded(LossFunction::CrossEntropy, 4);
+        network.add_dense_layer(3, 4, ActivationFunction::Sigmoid);
+        network.add_dense_layer(4, 3, ActivationFunction::Softmax);
+        let input = DMatrix::from_row_slice(1, 3, &[0.5, -0.3, 0.8]);
+        let output_idx = 1;
+        let gradients = network.output_weight_gradient(&input, output_idx);
+
+        let epsilon = 1e-2;
+        for (layer_idx, layer_gradients) in gradients.iter().enumerate() {
+            for idx in 0..layer_gradients.len() {
+                let original = network.dense_layer(layer_idx).unwrap().weights[idx];
+                network.dense_layer_mut(layer_idx).unwrap().weights[idx] = original + epsilon;
+                let output_plus = network.infer(&input)[output_idx];
+                network.dense_layer_mut(layer_idx).unwrap().weights[idx] = original - epsilon;
+                let output_minus = network.infer(&input)[output_idx];
+                network.dense_layer_mut(layer_idx).unwrap().weights[idx] = original;
+                let numeric = (output_plus - output_minus) / (2.0 * epsilon);
+                assert!((layer_gradients[idx] - numeric).abs() < 1e-3, "Layer {} weight {}: {} vs {}", layer_idx, idx, layer_gradients[idx], numeric);
+            }
+        }
+    }
+
+    #[test]
+    fn rank_analysis_finds_a_rank_one_layer() {
+        let u = DVector::from_vec(vec![1.0, -2.0, 0.5, 3.0]);
+        let v = DVector::from_vec(vec![0.3, 1.0, -1.0]);
+        let mut network = feature_per_class_network(4);
+        network.add_layer(DenseLayer::from_parameters(&u * v.transpose(), DVector::zeros(3), ActivationFunction::Softmax));
+        assert_eq!(network.rank_analysis(), vec![4, 1]);
+    }
+
+    // Two classes, feature 0 pushes towards class 0 much harder than the other features do
+    fn dominant_feature_network(num_features: usize) -> NeuralNetwork {
+        let weights = DMatrix::from_fn(num_features, 2, |input, output| {
+            let strength = if input == 0 { 3.0 } else { 0.2 };
+            if output == 0 { strength } else { -strength }
+        });
+        let mut network = NeuralNetwork::new(LossFunction::CrossEntropy);
+        network.add_layer(DenseLayer::from_parameters(weights, DVector::zeros(2), ActivationFunction::Softmax));
+        network
+    }
+
+    #[test]
+    fn shap_attributes_most_to_the_dominant_feature() {
+        let network = dominant_feature_network(4);
+        let attributions = network.shap_attributions(&DMatrix::from_element(1, 4, 1.0), &DMatrix::zeros(1, 4), 20);
+        assert_eq!(attributions.argmax().0, 0, "Attributions {}", attributions);
+        assert!(attributions.iter().all(|&attribution| attribution > 0.0));
+    }
+
+    #[test]
+    fn integrated_gradients_add_up_to_the_output_difference() {
+        let mut network = NeuralNetwork::new_seeded(LossFunction::CrossEntropy, 6);
+        network.add_dense_layer(4, 6, ActivationFunction::Sigmoid);
+        network.add_dense_layer(6, 3, ActivationFunction::Softmax);
+        let input = DMatrix::from_row_slice(1, 4, &[1.0, -0.5, 2.0, 0.3]);
+        let baseline = DMatrix::zeros(1, 4);
+        let class = 2;
+
+        let attributions = network.integrated_gradients(&input, &baseline, class, 200);
+        let output_difference = network.infer(&input)[class] - network.infer(&baseline)[class];
+        assert!((attributions.sum() - output_difference).abs() < 1e-3, "Attributions sum to {}, outputs differ by {}", attributions.sum(), output_difference);
+    }
+
+    #[test]
+    fn a_class_specific_neuron_has_a_high_mean_only_for_its_class() {
+        // Neuron 1 only fires for the inputs of class 1
+        let weights = DMatrix::from_row_slice(2, 2, &[1.0, 0.0, 0.0, 1.0]);
+        let mut network = NeuralNetwork::new(LossFunction::CrossEntropy);
+        network.add_layer(DenseLayer::from_parameters(weights, DVector::from_vec(vec![0.0, -0.5]), ActivationFunction::ReLU));
+        network.add_layer(DenseLayer::from_parameters(DMatrix::identity(2, 2), DVector::zeros(2), ActivationFunction::Softmax));
+        let inputs = DMatrix::from_row_slice(4, 2, &[1.0, 0.0, 0.8, 0.2, 0.1, 1.5, 0.0, 2.5]);
+        let labels = DMatrix::from_column_slice(4, 1, &[0.0, 0.0, 1.0, 1.0]);
+
+        let means = network.class_conditional_activations(&inputs, &labels, 3, 0);
+        assert_eq!(means.shape(), (3, 2));
+        assert!((means[(1, 1)] - 1.5).abs() < 1e-6);
+        assert_eq!(means[(0, 1)], 0.0);
+        assert_eq!(means.row(2).sum(), 0.0);
+    }
+
+    #[test]
+    fn a_neuron_influences_the_class_it_is_wired_to() {
+        let mut network = NeuralNetwork::new(LossFunction::CrossEntropy);
+        network.add_layer(DenseLayer::from_parameters(DMatrix::identity(2, 2), DVector::zeros(2), ActivationFunction::ReLU));
+        // Neuron 0 votes for class 2, neuron 1 for class 0 and against class 1
+        let output_weights = DMatrix::from_row_slice(2, 3, &[0.0, 0.0, 3.0, 2.0, -1.0, 0.0]);
+        network.add_layer(DenseLayer::from_parameters(output_weights, DVector::zeros(3), ActivationFunction::Softmax));
+
+        assert_eq!(network.neuron_class_influence(0, 0), DVector::from_vec(vec![0.0, 0.0, 3.0]));
+        assert_eq!(network.neuron_class_influence(0, 1), DVector::from_vec(vec![2.0, -1.0, 0.0]));
+    }
+
+    #[test]
+    fn counterfactual_is_classified_as_the_target_and_stays_close() {
+        let mut network = feature_per_class_network(3);
+        let input = DMatrix::from_row_slice(1, 3, &[0.5, 0.0, 0.3]);
+        assert_eq!(argmax(network.infer(&input).iter()), 0);
+
+        let counterfactual = network.counterfactual(&input, 2, 100, 0.1);
+        assert_eq!(argmax(network.infer(&counterfactual).iter()), 2);
+        // Swapping the two features would already do, at a distance of ~0.28
+        assert!((&counterfactual - &input).norm() < 0.5, "Moved by {}", (&counterfactual - &input).norm());
+    }
+
+    #[test]
+    fn relevant_features_rank_above_irrelevant_ones() {
+        // Only features 1 and 3 reach the output
+        let weights = DMatrix::from_row_slice(4, 2, &[0.0, 0.0, 1.5, -1.5, 0.0, 0.0, -0.8, 0.8]);
+        let mut network = NeuralNetwork::new(LossFunction::CrossEntropy);
+        network.add_layer(DenseLayer::from_parameters(weights, DVector::zeros(2), ActivationFunction::Softmax));
+        let inputs = DMatrix::from_fn(10, 4, |r, c| ((r * 4 + c) as f32 * 0.53).sin());
+        let targets = DMatrix::from_fn(10, 2, |r, c| if r % 2 == c { 1.0 } else { 0.0 });
+
+        let ranking = network.rank_features(&inputs, &targets);
+        let order: Vec<usize> = ranking.iter().map(|&(feature, _)| feature).collect();
+        assert_eq!(&order[..2], &[1, 3]);
+        assert!(ranking[2..].iter().all(|&(_, score)| score == 0.0));
+    }
+
+    #[test]
+    fn reviving_only_changes_the_dead_neuron() {
+        let mut network = NeuralNetwork::new_seeded(LossFunction::CrossEntropy, 12);
+        network.add_dense_layer(3, 4, ActivationFunction::ReLU);
+        network.add_dense_layer(4, 2, ActivationFunction::Softmax);
+        // Neuron 1 can't fire for any input in [-1, 1]
+        network.dense_layer_mut(0).unwrap().biases[1] = -100.0;
+        let inputs = DMatrix::from_fn(10, 3, |r, c| ((r * 3 + c) as f32 * 0.37).sin());
+        let before: Vec<_> = (0..2).map(|i| network.dense_layer(i).map(|layer| (layer.weights.clone(), layer.biases.clone())).unwrap()).collect();
+
+        assert_eq!(network.revive_dead_neurons(&inputs, 5), 1);
+        let hidden = network.dense_layer(0).unwrap();
+        assert_eq!(hidden.biases[1], 0.0);
+        assert_ne!(hidden.weights.column(1), before[0].0.column(1));
+        for neuron in [0, 2, 3] {
+            assert_eq!(hidden.weights.column(neuron), before[0].0.column(neuron));
+            assert_eq!(hidden.biases[neuron], before[0].1[neuron]);
+        }
+        let output = network.dense_layer(1).unwrap();
+        assert_eq!((&output.weights, &output.biases), (&before[1].0, &before[1].1));
+    }
+
+    #[test]
+    fn partial_dependence_rises_with_a_feature_the_output_grows_with() {
+        // The class 0 probability only grows with feature 0
+        let network = dominant_feature_network(3);
+        let inputs = DMatrix::from_fn(8, 3, |r, c| ((r * 3 + c) as f32 * 0.53).sin());
+        let values = [-2.0, -1.0, 0.0, 1.0, 2.0];
+
+        let curve = partial_dependence(&network, &inputs, 0, &values, 0);
+        assert_eq!(curve.len(), values.len());
+        assert!(curve.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", curve);
+        // The class 1 output moves the other way
+        assert!(partial_dependence(&network, &inputs, 0, &values, 1).windows(2).all(|pair| pair[0] > pair[1]));
+    }
+
+    // Hidden neuron 2 is an exact copy of neuron 0, the others are unrelated
+    fn duplicated_neuron_network() -> NeuralNetwork {
+        let hidden_weights = DMatrix::from_row_slice(3, 4, &[
+            1.0, 0.0, 1.0, -0.5,
+            0.5, 1.0, 0.5, 0.3,
+            -0.2, 0.4, -0.2, 1.0,
+        ]);
+        let hidden_biases = DVector::from_vec(vec![0.1, 0.0, 0.1, -0.2]);
+        let output_weights = DMatrix::from_row_slice(4, 2, &[0.7, -0.3, 0.2, 0.9, -0.4, 0.6, 0.5, 0.1]);
+        let mut network = NeuralNetwork::new(LossFunction::CrossEntropy);
+        network.add_layer(DenseLayer::from_parameters(hidden_weights, hidden_biases, ActivationFunction::Sigmoid));
+        network.add_layer(DenseLayer::from_parameters(output_weights, DVector::zeros(2), ActivationFunction::Softmax));
+        network
+    }
+
+    #[test]
+    fn correlated_neurons_finds_the_duplicated_pair() {
+        let inputs = DMatrix::from_fn(20, 3, |r, c| ((r * 3 + c) as f32 * 0.71).sin());
+        assert_eq!(duplicated_neuron_network().correlated_neurons(&inputs, 0, 0.99), vec![(0, 2)]);
+    }
+
+    #[test]
+    fn merging_a_duplicate_narrows_the_layer_and_keeps_the_outputs() {
+        let mut network = duplicated_neuron_network();
+        let inputs = DMatrix::from_fn(20, 3, |r, c| ((r * 3 + c) as f32 * 0.71).sin());
+        let outputs_before = network.infer(&inputs);
+
+        network.merge_neurons(0, &[(0, 2)]);
+        assert_eq!(network.get_layers()[0].output_size(), 3);
+        assert_eq!(network.get_layers()[1].input_size(), 3);
+        assert!((network.infer(&inputs) - outputs_before).amax() < 1e-6);
+    }
+
+    #[test]
+    fn ablating_a_class_feature_drops_the_accuracy_of_that_class() {
+        // Sample c has feature c at 1 and feature c + 1 at 0.2, so masking feature c moves it to class c + 1
+        let network = feature_per_class_network(3);
+        let inputs = DMatrix::from_row_slice(3, 3, &[1.0, 0.2, 0.0, 0.0, 1.0, 0.2, 0.2, 0.0, 1.0]);
+        let labels = DMatrix::from_column_slice(3, 1, &[0.0, 1.0, 2.0]);
+        let groups = vec![vec![], vec![0], vec![1, 2], vec![0, 1, 2]];
+        let accuracies = ablation_study(&network, &inputs, &labels, &groups);
+
+        assert_eq!(accuracies[0], 1.0, "Masking nothing should keep every prediction");
+        assert!((accuracies[1] - 2.0 / 3.0).abs() < 1e-6, "Accuracies {:?}", accuracies);
+        assert!((accuracies[2] - 1.0 / 3.0).abs() < 1e-6, "Accuracies {:?}", accuracies);
+        // Every sample masked to zeros gets the same prediction
+        assert!((accuracies[3] - 1.0 / 3.0).abs() < 1e-6, "Accuracies {:?}", accuracies);
+    }
+
+    #[test]
+    fn occlusion_only_matters_over_the_pixel_the_class_depends_on() {
+        // 4x4 images, class 0's logit is 3 times the top-left pixel
+        let weights = DMatrix::from_fn(16, 2, |pixel, class| if pixel == 0 && class == 0 { 3.0 } else { 0.0 });
+        let mut network = NeuralNetwork::new(LossFunction::CrossEntropy);
+        network.add_layer(DenseLayer::from_parameters(weights, DVector::zeros(2), ActivationFunction::Softmax));
+
+        let map = network.occlusion_map(&[1.0; 16], 0, 2, 2);
+        assert_eq!(map.shape(), (2, 2));
+        let expected_drop = 1.0 / (1.0 + (-3.0f32).exp()) - 0.5;
+        assert!((map[(0, 0)] - expected_drop).abs() < 1e-6, "Drop {} at the top left", map[(0, 0)]);
+        assert_eq!((map[(0, 1)], map[(1, 0)], map[(1, 1)]), (0.0, 0.0, 0.0));
+        assert_eq!(network.occlusion_map(&[1.0; 16], 0, 3, 1).shape(), (2, 2));
+    }
+
+    #[test]
+    fn a_layer_spanning_a_plane_has_dimensionality_two() {
+        // Two independent input features mapped linearly into 5 dimensions, then squeezed onto a line
+        let mut network = NeuralNetwork::new(LossFunction::MeanSquaredError);
+        let spread = DMatrix::from_row_slice(2, 5, &[1.0, 0.5, -1.0, 2.0, 0.0, 0.0, 1.0, 1.0, -0.5, 3.0]);
+        network.add_layer(DenseLayer::from_parameters(spread, DVector::from_element(5, 0.3), ActivationFunction::Linear));
+        let line = DMatrix::from_fn(5, 4, |input, output| (input as f32 + 1.0) * (output as f32 - 1.5));
+        network.add_layer(DenseLayer::from_parameters(line, DVector::zeros(4), ActivationFunction::Linear));
+
+        let inputs = DMatrix::from_fn(50, 2, |r, c| if c == 0 { (r as f32 * 0.7).sin() } else { (r as f32 * 1.3).cos() });
+        assert_eq!(network.representation_dimensionality(&inputs, 0, 0.999), 2);
+        assert_eq!(network.representation_dimensionality(&inputs, 1, 0.999), 1);
+        assert_eq!(network.representation_dimensionality(&DMatrix::from_element(10, 2, 0.4), 0, 0.95), 0);
+    }
+
+    #[test]
+    fn inverting_an_invertible_layer_recovers_the_input() {
+        let mut network = NeuralNetwork::new(LossFunction::MeanSquaredError);
+        let weights = DMatrix::from_row_slice(2, 2, &[1.0, 0.5, -0.5, 1.0]);
+        network.add_layer(DenseLayer::from_parameters(weights, DVector::from_vec(vec![0.1, -0.2]), ActivationFunction::Sigmoid));
+        network.add_dense_layer(2, 3, ActivationFunction::Softmax);
+
+        let original = DMatrix::from_row_slice(1, 2, &[0.8, -0.4]);
+        let target_activation = network.layer_outputs(&original).swap_remove(0);
+        let reconstructed = network.invert_activation(&target_activation, 0, 2000, 2.0);
+        assert!((&reconstructed - &original).amax() < 1e-2, "Reconstructed {} from {}", reconstructed, original);
+    }
+}
@@ -1,30 +1,164 @@
 use nalgebra::{DMatrix, DVector};
-use rand_distr::{Normal, Distribution};
+use rand_distr::{Distribution, Normal, Uniform};
 use crate::activation::ActivationFunction;
+use crate::rng::StableRng;
+use crate::scalar::Real;
+use crate::serialization::{SerializableDenseLayer, SerializableLayer};
 
-pub struct DenseLayer {
-    pub weights: DMatrix<f32>,    // Shape: (input_size, output_size)
-    pub biases: DVector<f32>,     // Shape: (output_size, 1) -> DVector is a column vector
+// Common interface of everything that can be stacked in a network.
+// Unlike DenseLayer::backward and DenseLayer::compute_gradients, which take dError/dZ, the Layer methods take
+// the gradient w.r.t. the layer's output, so layers don't need to know anything about their neighbours.
+// Generic over the scalar type like DenseLayer, the image and normalization layers are f32 only.
+pub trait Layer<T: Real = f32>: Send + Sync {
+    // Forward pass that caches whatever backward needs
+    fn forward(&mut self, input: &DMatrix<T>) -> DMatrix<T>;
+
+    // Forward pass without caching, for inference
+    fn infer(&self, input: &DMatrix<T>) -> DMatrix<T>;
+
+    // Takes dError/dOutput of the last forward pass and returns the parameter gradients (empty for layers
+    // without parameters) and dError/dInput, without touching the parameters
+    fn compute_gradients(&self, gradient_wrt_output: &DMatrix<T>) -> (LayerGradients<T>, DMatrix<T>);
+
+    // Only the dError/dInput of compute_gradients, for when the parameter gradients aren't needed
+    fn input_gradient(&self, gradient_wrt_output: &DMatrix<T>) -> DMatrix<T> {
+        self.compute_gradients(gradient_wrt_output).1
+    }
+
+    // Takes dError/dOutput, updates the layer's parameters (if any) and returns dError/dInput
+    fn backward(&mut self, gradient_wrt_output: &DMatrix<T>, learning_rate: T) -> DMatrix<T> {
+        let (gradients, gradient_wrt_input) = self.compute_gradients(gradient_wrt_output);
+        self.apply_gradients(&gradients, learning_rate);
+        gradient_wrt_input
+    }
+
+    // Trainable weights and biases, shaped like the LayerGradients of compute_gradients. None for layers without any.
+    fn parameters(&self) -> Option<(&DMatrix<T>, &DVector<T>)> {
+        None
+    }
+
+    fn parameters_mut(&mut self) -> Option<(&mut DMatrix<T>, &mut DVector<T>)> {
+        None
+    }
+
+    // Plain SGD step on the parameters, other update rules are Optimizers in optimizer.rs
+    fn apply_gradients(&mut self, gradients: &LayerGradients<T>, learning_rate: T) {
+        if let Some((weights, biases)) = self.parameters_mut() {
+            *weights -= &gradients.weights * learning_rate;
+            *biases -= &gradients.biases * learning_rate;
+        }
+    }
+
+    // Frees the caches of the last forward pass
+    fn clear_cache(&mut self) {}
+
+    fn input_size(&self) -> usize;
+
+    fn output_size(&self) -> usize;
+
+    // Short name for summaries and error messages, e.g. "dense"
+    fn layer_type(&self) -> &'static str;
+
+    // Number of trainable parameters, 0 for layers without any
+    fn num_parameters(&self) -> usize {
+        self.parameters().map_or(0, |(weights, biases)| weights.len() + biases.len())
+    }
+
+    // Everything needed to rebuild the layer (not its caches), tagged with its type
+    fn to_serializable(&self) -> SerializableLayer;
+
+    // For the tools that only work on dense layers (ONNX export, neuron surgery, logits, ...)
+    fn as_dense(&self) -> Option<&DenseLayer<T>> {
+        None
+    }
+
+    fn as_dense_mut(&mut self) -> Option<&mut DenseLayer<T>> {
+        None
+    }
+}
+
+// Lets NeuralNetwork::add_layer take any layer by value. One impl per scalar type, a generic one would
+// overlap with From<T> for T.
+impl<L: Layer<f32> + 'static> From<L> for Box<dyn Layer<f32>> {
+    fn from(layer: L) -> Self {
+        Box::new(layer)
+    }
+}
+
+impl<L: Layer<f64> + 'static> From<L> for Box<dyn Layer<f64>> {
+    fn from(layer: L) -> Self {
+        Box::new(layer)
+    }
+}
+
+pub struct DenseLayer<T: Real = f32> {
+    pub weights: DMatrix<T>,    // Shape: (input_size, output_size)
+    pub biases: DVector<T>,     // Shape: (output_size, 1) -> DVector is a column vector
     pub activation_fn: ActivationFunction,
 
     // Cache for backpropagation
-    input_cache: DMatrix<f32>,    // Input to this layer (A from prev layer or X)
-    pub z_cache: DMatrix<f32>,    // Weighted sum + bias (input to activation function), made public
+    input_cache: DMatrix<T>,    // Input to this layer (A from prev layer or X)
+    pub z_cache: DMatrix<T>,    // Weighted sum + bias (input to activation function), made public
 }
 
-impl DenseLayer {
+impl<T: Real> DenseLayer<T> {
+    // Weights from Initializer::for_activation: He for ReLU and ELU, LeCun for everything else
     pub fn new(input_size: usize, output_size: usize, activation_fn: ActivationFunction) -> Self {
+        DenseLayer::with_initializer(input_size, output_size, activation_fn, Initializer::for_activation(activation_fn))
+    }
+
+    pub fn with_initializer(input_size: usize, output_size: usize, activation_fn: ActivationFunction, initializer: Initializer) -> Self {
         let mut rng = rand::rng();
-        
-        let std_dev = match activation_fn {
-            ActivationFunction::ReLU => (2.0 / input_size as f32).sqrt(),
-            _ => (1.0 / input_size as f32).sqrt(), 
+        let (fan_in, fan_out) = (input_size as f64, output_size as f64);
+
+        let weights_data: Vec<T> = match initializer {
+            Initializer::He | Initializer::XavierNormal | Initializer::LeCun => {
+                let std_dev = match initializer {
+                    Initializer::He => (2.0 / fan_in).sqrt(),
+                    Initializer::XavierNormal => (2.0 / (fan_in + fan_out)).sqrt(),
+                    _ => (1.0 / fan_in).sqrt(),
+                };
+                let normal = Normal::new(0.0, std_dev).unwrap();
+                (0..input_size * output_size).map(|_| T::cast(normal.sample(&mut rng))).collect()
+            }
+            Initializer::XavierUniform => {
+                let limit = (6.0 / (fan_in + fan_out)).sqrt();
+                let uniform = Uniform::new_inclusive(-limit, limit).unwrap();
+                (0..input_size * output_size).map(|_| T::cast(uniform.sample(&mut rng))).collect()
+            }
+            Initializer::Zeros => vec![T::zero(); input_size * output_size],
+            Initializer::Constant(value) => vec![T::cast(value as f64); input_size * output_size],
         };
-        let normal = Normal::new(0.0, std_dev).unwrap();
+        DenseLayer::from_weights_data(input_size, output_size, weights_data, activation_fn)
+    }
+
+    // Reproducible initialization: the same seed gives bit-identical weights on any machine and
+    // with any version of rand, because sampling uses the crate's own StableRng.
+    // Weights are drawn one standard normal sample at a time, scaled by the same std dev as `new`,
+    // and filled column by column (nalgebra's storage order), i.e. all input weights of output neuron 0 first.
+    pub fn new_deterministic(input_size: usize, output_size: usize, activation_fn: ActivationFunction, seed: u64) -> Self {
+        let mut rng = StableRng::new(seed);
+        let std_dev = init_std_dev(input_size, activation_fn);
 
         let weights_data = (0..input_size * output_size)
-            .map(|_| normal.sample(&mut rng))
-            .collect::<Vec<f32>>();
+            .map(|_| T::cast(rng.next_standard_normal() * std_dev))
+            .collect::<Vec<T>>();
+        DenseLayer::from_weights_data(input_size, output_size, weights_data, activation_fn)
+    }
+
+    // Layer with the given parameters, e.g. read from a file, weights shaped (input_size, output_size)
+    pub fn from_parameters(weights: DMatrix<T>, biases: DVector<T>, activation_fn: ActivationFunction) -> Self {
+        assert_eq!(biases.len(), weights.ncols(), "Expected {} biases for {} outputs, got {}", weights.ncols(), weights.ncols(), biases.len());
+        DenseLayer {
+            weights,
+            biases,
+            activation_fn,
+            input_cache: DMatrix::zeros(0, 0),
+            z_cache: DMatrix::zeros(0, 0),
+        }
+    }
+
+    fn from_weights_data(input_size: usize, output_size: usize, weights_data: Vec<T>, activation_fn: ActivationFunction) -> Self {
         let weights = DMatrix::from_vec(input_size, output_size, weights_data);
         
         let biases = DVector::zeros(output_size); // DVector is (output_size, 1)
@@ -38,19 +172,48 @@ impl DenseLayer {
         }
     }
 
-    pub fn forward(&mut self, input: &DMatrix<f32>) -> DMatrix<f32> {
+    // Number of features this layer expects per sample (weights are stored as input_size x output_size)
+    pub fn input_size(&self) -> usize {
+        self.weights.nrows()
+    }
+
+    // Number of neurons, i.e. features this layer outputs per sample
+    pub fn output_size(&self) -> usize {
+        self.weights.ncols()
+    }
+
+    pub fn forward(&mut self, input: &DMatrix<T>) -> DMatrix<T> {
+        // Bad: Clone is expensive
+        self.input_cache = input.clone();
+        self.z_cache = self.weighted_sum(input);
+        
+        self.activation_fn.activate(&self.z_cache)
+    }
+
+    // Frees the input and Z caches, the next backward needs a forward first
+    pub fn clear_cache(&mut self) {
+        self.input_cache = DMatrix::zeros(0, 0);
+        self.z_cache = DMatrix::zeros(0, 0);
+    }
+
+    // Forward pass that doesn't cache anything, so it works through a shared reference.
+    // Use this for inference, forward is only needed before a backward pass.
+    pub fn infer(&self, input: &DMatrix<T>) -> DMatrix<T> {
+        self.activation_fn.activate(&self.weighted_sum(input))
+    }
+
+    // Z = input * W + b
+    pub fn weighted_sum(&self, input: &DMatrix<T>) -> DMatrix<T> {
         // Make sure dimensions match, better to catch dimention errors early then deal with errors in operations
         assert_eq!(input.ncols(), self.weights.nrows(), 
             "FORWARD: Input columns ({}) must match weight rows ({}). Input dims: {}x{}, Weight dims: {}x{}", 
             input.ncols(), self.weights.nrows(), 
             input.nrows(), input.ncols(), 
             self.weights.nrows(), self.weights.ncols());
-        // Bad: Clone is expensive
-        self.input_cache = input.clone();
         
         let z_linear = input * &self.weights; // (batch_size, output_size)
         
-        let bias_row_vector = self.biases.transpose(); // (1, output_size), type RowDVector<f32>
+        let bias_row_vector = self.biases.transpose(); // (1, output_size), type RowDVector<T>
 
         // Compute z_linear + bias_row_vector row by row
         let mut z_biased = DMatrix::zeros(z_linear.nrows(), z_linear.ncols());
@@ -58,32 +221,39 @@ impl DenseLayer {
             let row_sum = z_linear.row(r_idx) + &bias_row_vector; 
             z_biased.row_mut(r_idx).copy_from(&row_sum);
         }
-        self.z_cache = z_biased;
-        
-        self.activation_fn.activate(&self.z_cache)
+        z_biased
     }
 
-    pub fn backward(&mut self, gradient_wrt_z: &DMatrix<f32>, learning_rate: f32) -> DMatrix<f32> {
+    pub fn backward(&mut self, gradient_wrt_z: &DMatrix<T>, learning_rate: T) -> DMatrix<T> {
+        let (gradients, gradient_to_pass_back) = self.compute_gradients(gradient_wrt_z);
+        self.apply_gradients(&gradients, learning_rate);
+        gradient_to_pass_back
+    }
+
+    // Backward pass without touching the weights.
+    // Returns the parameter gradients and dError/dA_prev_layer to pass to the previous layer.
+    pub fn compute_gradients(&self, gradient_wrt_z: &DMatrix<T>) -> (LayerGradients<T>, DMatrix<T>) {
         assert_eq!(gradient_wrt_z.ncols(), self.weights.ncols(), "BACKWARD: Gradient_wrt_Z columns ({}) must match weights columns ({}) (output_size).", gradient_wrt_z.ncols(), self.weights.ncols());
         assert_eq!(gradient_wrt_z.nrows(), self.input_cache.nrows(), "BACKWARD: Gradient_wrt_Z rows ({}) must match batch size of cached input ({}).", gradient_wrt_z.nrows(), self.input_cache.nrows());
 
-        let batch_size = self.input_cache.nrows() as f32;
-        if batch_size == 0.0 { 
+        if self.input_cache.nrows() == 0 { 
             // Return gradient for previous layer's activation, shape (0, prev_layer_output_size)
             // prev_layer_output_size is self.weights.nrows() (input_size to this layer)
-            return DMatrix::zeros(0, self.weights.nrows()); 
+            return (LayerGradients::zeros_like(self), DMatrix::zeros(0, self.input_size())); 
         }
 
 
+        let batch_size = T::cast(self.input_cache.nrows() as f64);
+
         // Calculate gradients for weights: dW = (1/m) * X_prev.T * dZ
         let dw = (&self.input_cache.transpose() * gradient_wrt_z) / batch_size;
 
-        // Calculate gradients for biases: dW = (1/m) * X_prev.T * dZ
+        // Calculate gradients for biases: db = (1/m) * sum of dZ over the batch
         let output_size_for_bias = self.biases.nrows(); // Number of neurons in this layer
         let mut calculated_db_col_vector_data = Vec::with_capacity(output_size_for_bias);
 
         for j in 0..output_size_for_bias { // For each output neuron / bias term
-            let col_j_sum: f32 = gradient_wrt_z.column(j).sum(); 
+            let col_j_sum: T = gradient_wrt_z.column(j).sum(); 
             calculated_db_col_vector_data.push(col_j_sum / batch_size);
         }
 
@@ -93,15 +263,176 @@ impl DenseLayer {
         // Calculate gradient to pass to the previous layer: dError/dA_prev_layer = dZ * W.T
         // Transpose weights to match dimensions
         let gradient_to_pass_back = gradient_wrt_z * self.weights.transpose();
-        
-        let bias_update_term = learning_rate * db_col_vector.clone(); // Clone for debug print if needed, use original for op
 
+        (LayerGradients { weights: dw, biases: db_col_vector }, gradient_to_pass_back)
+    }
+
+    pub fn apply_gradients(&mut self, gradients: &LayerGradients<T>, learning_rate: T) {
         // Update weights and biases
-        // TODO: This is where we could use momentum, weight decay, etc for a better optimizer
-        self.weights -= learning_rate * dw;
-        self.biases -= bias_update_term; 
+        // Plain SGD, other update rules (momentum, Adam, LARS) are Optimizers in optimizer.rs
+        self.weights -= &gradients.weights * learning_rate;
+        self.biases -= &gradients.biases * learning_rate; 
+    }
+}
 
-        
-        gradient_to_pass_back
+impl<T: Real> Layer<T> for DenseLayer<T> {
+    fn forward(&mut self, input: &DMatrix<T>) -> DMatrix<T> {
+        DenseLayer::forward(self, input)
+    }
+
+    fn infer(&self, input: &DMatrix<T>) -> DMatrix<T> {
+        DenseLayer::infer(self, input)
+    }
+
+    fn compute_gradients(&self, gradient_wrt_output: &DMatrix<T>) -> (LayerGradients<T>, DMatrix<T>) {
+        // dError/dZ = dError/dA * dA/dZ, then the usual dense backward pass
+        let gradient_wrt_z = self.activation_fn.jacobian_vector_product(gradient_wrt_output, &self.z_cache);
+        DenseLayer::compute_gradients(self, &gradient_wrt_z)
+    }
+
+    fn input_gradient(&self, gradient_wrt_output: &DMatrix<T>) -> DMatrix<T> {
+        // Skips dW: dError/dInput = dZ * W.T
+        self.activation_fn.jacobian_vector_product(gradient_wrt_output, &self.z_cache) * self.weights.transpose()
     }
-}
\ No newline at end of file
+
+    fn parameters(&self) -> Option<(&DMatrix<T>, &DVector<T>)> {
+        Some((&self.weights, &self.biases))
+    }
+
+    fn parameters_mut(&mut self) -> Option<(&mut DMatrix<T>, &mut DVector<T>)> {
+        Some((&mut self.weights, &mut self.biases))
+    }
+
+    fn apply_gradients(&mut self, gradients: &LayerGradients<T>, learning_rate: T) {
+        DenseLayer::apply_gradients(self, gradients, learning_rate)
+    }
+
+    fn clear_cache(&mut self) {
+        DenseLayer::clear_cache(self)
+    }
+
+    fn input_size(&self) -> usize {
+        DenseLayer::input_size(self)
+    }
+
+    fn output_size(&self) -> usize {
+        DenseLayer::output_size(self)
+    }
+
+    fn layer_type(&self) -> &'static str {
+        "dense"
+    }
+
+    fn to_serializable(&self) -> SerializableLayer {
+        SerializableLayer::Dense(SerializableDenseLayer::from(self))
+    }
+
+    fn as_dense(&self) -> Option<&DenseLayer<T>> {
+        Some(self)
+    }
+
+    fn as_dense_mut(&mut self) -> Option<&mut DenseLayer<T>> {
+        Some(self)
+    }
+}
+
+// Gradients of the loss w.r.t. a layer's parameters, same shapes as the parameters themselves
+#[derive(Debug, Clone)]
+pub struct LayerGradients<T: Real = f32> {
+    pub weights: DMatrix<T>,
+    pub biases: DVector<T>,
+}
+
+impl<T: Real> LayerGradients<T> {
+    // Zeros shaped like the layer's parameters, empty for a layer without any
+    pub fn zeros_like(layer: &dyn Layer<T>) -> Self {
+        match layer.parameters() {
+            Some((weights, biases)) => LayerGradients {
+                weights: DMatrix::zeros(weights.nrows(), weights.ncols()),
+                biases: DVector::zeros(biases.len()),
+            },
+            None => LayerGradients::empty(),
+        }
+    }
+
+    // The gradients of a layer without parameters
+    pub fn empty() -> Self {
+        LayerGradients { weights: DMatrix::zeros(0, 0), biases: DVector::zeros(0) }
+    }
+}
+
+// How DenseLayer::with_initializer draws the weights, biases always start at 0.
+// fan_in is the layer's input size and fan_out its output size.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Initializer {
+    He,            // Normal with std dev sqrt(2 / fan_in), for ReLU and ELU
+    XavierUniform, // Uniform in [-limit, limit] with limit = sqrt(6 / (fan_in + fan_out)), for Sigmoid
+    XavierNormal,  // Normal with std dev sqrt(2 / (fan_in + fan_out))
+    LeCun,         // Normal with std dev sqrt(1 / fan_in)
+    Zeros,
+    Constant(f32),
+}
+
+impl Initializer {
+    // What DenseLayer::new uses
+    pub fn for_activation(activation_fn: ActivationFunction) -> Self {
+        match activation_fn {
+            ActivationFunction::ReLU | ActivationFunction::ELU { .. } => Initializer::He,
+            _ => Initializer::LeCun,
+        }
+    }
+}
+
+// He initialization for ReLU and ELU, LeCun (1/fan_in) for everything else, the std dev of Initializer::for_activation
+pub(crate) fn init_std_dev(input_size: usize, activation_fn: ActivationFunction) -> f64 {
+    match activation_fn {
+        ActivationFunction::ReLU | ActivationFunction::ELU { .. } => (2.0 / input_size as f64).sqrt(),
+        _ => (1.0 / input_size as f64).sqrt(), 
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_deterministic_gives_the_same_weights_on_every_machine() {
+        // Any change here breaks reproducing models trained with earlier versions
+        let layer: DenseLayer = DenseLayer::new_deterministic(4, 3, ActivationFunction::ReLU, 42);
+        assert_eq!(&layer.weights.as_slice()[..4], &[0.6238442, -0.31879902, 0.13318543, 0.15527102]);
+        assert!(layer.biases.iter().all(|&bias| bias == 0.0));
+    }
+
+    #[test]
+    fn sizes_are_the_ones_passed_to_new() {
+        let layer: DenseLayer = DenseLayer::new(7, 3, ActivationFunction::Sigmoid);
+        assert_eq!(layer.input_size(), 7);
+        assert_eq!(layer.output_size(), 3);
+    }
+
+    #[test]
+    fn initializers_have_the_expected_spread() {
+        // 400 inputs and 100 outputs: He 0.0707, LeCun 0.05, Xavier 0.0632 (uniform up to 0.1095)
+        let std_dev = |initializer: Initializer| {
+            let layer: DenseLayer = DenseLayer::with_initializer(400, 100, ActivationFunction::Linear, initializer);
+            let mean = layer.weights.mean();
+            (layer.weights.iter().map(|&weight| (weight - mean).powi(2)).sum::<f32>() / layer.weights.len() as f32).sqrt()
+        };
+        let expected = [
+            (Initializer::He, (2.0f32 / 400.0).sqrt()),
+            (Initializer::LeCun, (1.0f32 / 400.0).sqrt()),
+            (Initializer::XavierNormal, (2.0f32 / 500.0).sqrt()),
+            (Initializer::XavierUniform, (2.0f32 / 500.0).sqrt()),
+        ];
+        for (initializer, expected_std_dev) in expected {
+            let actual = std_dev(initializer);
+            assert!((actual / expected_std_dev - 1.0).abs() < 0.05, "{:?}: std dev {}, expected {}", initializer, actual, expected_std_dev);
+        }
+
+        let uniform: DenseLayer = DenseLayer::with_initializer(400, 100, ActivationFunction::Linear, Initializer::XavierUniform);
+        assert!(uniform.weights.amax() <= (6.0f32 / 500.0).sqrt());
+        assert_eq!(std_dev(Initializer::Zeros), 0.0);
+        let constant: DenseLayer = DenseLayer::with_initializer(3, 2, ActivationFunction::Linear, Initializer::Constant(0.5));
+        assert!(constant.weights.iter().all(|&weight| weight == 0.5));
+    }
+}
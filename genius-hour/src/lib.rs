@@ -2,15 +2,39 @@
 
 // Modules of your library
 pub mod activation;
+pub mod cached;
+pub mod calibration;
+pub mod compact;
+pub mod config;
+pub mod conv;
+pub mod data;
+pub mod distillation;
+pub mod dot;
+pub mod ewc;
+pub mod interpret;
 pub mod layer;
 pub mod loss;
+pub mod metrics;
+pub mod multi_head;
 pub mod network;
+pub mod npy;
+pub mod onnx;
+pub mod optimizer;
+#[cfg(feature = "prefetch")]
+pub mod prefetch;
+pub mod rng;
+pub mod robustness;
+pub mod scalar;
 pub mod serialization; // Assuming this contains SerializableNeuralNetwork etc.
+pub mod standardize;
+pub mod training;
 
 // Re-export key structs/enums for easier use within the crate or by other Rust crates
 pub use activation::ActivationFunction;
-pub use layer::DenseLayer;
+pub use conv::{Conv2DLayer, FlattenLayer, MaxPool2DLayer};
+pub use layer::{DenseLayer, Initializer, Layer};
 pub use loss::LossFunction;
+pub use multi_head::MultiHeadNetwork;
 pub use network::NeuralNetwork;
 
 // WASM library caused problems when trying to compile to train, so conditionally exclude it
@@ -18,7 +42,6 @@ pub use network::NeuralNetwork;
 mod wasm_specific {
     use super::*;
     use nalgebra::DMatrix;
-    use once_cell::sync::Lazy;
     use wasm_bindgen::prelude::*;
 
     // Debug MOde 
@@ -31,63 +54,83 @@ mod wasm_specific {
     // include_bytes! is a compile time macro that includes the contents of a file as a byte slice.
     const MODEL_BYTES: &[u8] = include_bytes!("../mnist_model.bincode"); // Adjust path if model is elsewhere
 
-    // Lazy static for the loaded neural network, only loaded when needed, still available in global scope and never double loaded
-    static MNIST_NETWORK: Lazy<Result<NeuralNetwork, String>> = Lazy::new(|| {
-        // We need to specify the LossFunction used during training.
-        match bincode::deserialize(MODEL_BYTES) {
-            Ok(serializable_nn) => {
-                let snn: super::serialization::SerializableNeuralNetwork = serializable_nn;
-                Ok(snn.into_neural_network(LossFunction::CrossEntropy))
+    // A trained network loaded in the browser, e.g. from bytes written by NeuralNetwork::to_bytes / save_weights.
+    // Input and output sizes come from the model itself, so any dense network works, not only MNIST.
+    #[wasm_bindgen]
+    pub struct WasmNetwork {
+        network: NeuralNetwork,
+    }
+
+    #[wasm_bindgen]
+    impl WasmNetwork {
+        // The loss function only matters for training, so older layers-only files get CrossEntropy
+        pub fn from_bytes(bytes: &[u8]) -> Result<WasmNetwork, JsValue> {
+            let network = NeuralNetwork::from_bytes(bytes, LossFunction::CrossEntropy)
+                .map_err(|e| JsValue::from_str(&format!("Failed to deserialize model: {}", e)))?;
+            if network.get_layers().is_empty() {
+                return Err(JsValue::from_str("Model has no layers"));
             }
-            Err(e) => Err(format!("Failed to deserialize embedded model: {}", e)),
+            Ok(WasmNetwork { network })
         }
-    });
 
-    // WASM function to perform prediction.
-    // Input: a Float32Array representing a single flattened image (e.g., 784 pixels).
-    // Output: a Float32Array representing the probabilities for each class (e.g., 10 probabilities).
-    #[wasm_bindgen]
-    pub fn predict_mnist(image_data: &[f32]) -> Result<Vec<f32>, JsValue> {
-        // 1. Validate input length
-        let expected_input_size = 28 * 28; // MNIST image size
-        if image_data.len() != expected_input_size {
-            return Err(JsValue::from_str(&format!(
-                "Invalid input image data length. Expected {}, got {}",
-                expected_input_size,
-                image_data.len()
-            )));
+        pub fn input_size(&self) -> usize {
+            self.network.get_layers()[0].input_size()
+        }
+
+        pub fn output_size(&self) -> usize {
+            self.network.get_layers()[self.network.get_layers().len() - 1].output_size()
         }
 
-        use std::cell::RefCell;
+        // Input: a Float32Array holding a single flattened sample of input_size() values.
+        // Output: a Float32Array with the output_size() values of the last layer (e.g. class probabilities).
+        pub fn predict(&self, input: &[f32]) -> Result<Vec<f32>, JsValue> {
+            let expected_input_size = self.input_size();
+            if input.len() != expected_input_size {
+                return Err(JsValue::from_str(&format!(
+                    "Invalid input length. Expected {}, got {}",
+                    expected_input_size,
+                    input.len()
+                )));
+            }
+            let input_matrix = DMatrix::from_row_slice(1, expected_input_size, input);
+            Ok(self.network.infer(&input_matrix).as_slice().to_vec())
+        }
+    }
 
+    // Prediction with the embedded MNIST model.
+    // Input: 784 pixels of a flattened 28x28 image. Output: the 10 class probabilities.
+    #[wasm_bindgen]
+    pub fn predict_mnist(image_data: &[f32]) -> Result<Vec<f32>, JsValue> {
         // thread_local! keeps us memory safe while preventing reloading the model
         thread_local! {
-            static THREAD_LOCAL_NETWORK: RefCell<Result<NeuralNetwork, String>> = RefCell::new(
-                match bincode::deserialize(MODEL_BYTES) {
-                    Ok(serializable_nn) => {
-                        let snn: super::serialization::SerializableNeuralNetwork = serializable_nn; // Corrected path
-                        Ok(snn.into_neural_network(LossFunction::CrossEntropy))
-                    }
-                    Err(e) => Err(format!("Failed to deserialize embedded model: {}", e)),
-                }
-            );
+            static MNIST_NETWORK: Result<WasmNetwork, JsValue> = WasmNetwork::from_bytes(MODEL_BYTES);
         }
 
-        THREAD_LOCAL_NETWORK.with(|network_cell| {
-            match *network_cell.borrow_mut() {
-                Ok(ref mut nn) => {
-                    let input_matrix = DMatrix::from_row_slice(1, expected_input_size, image_data);
-                    let output_matrix = nn.predict(&input_matrix);
-                    Ok(output_matrix.as_slice().to_vec())
-                }
-                Err(ref s) => Err(JsValue::from_str(&format!(
-                    "Model not loaded or error: {}",
-                    s
-                ))),
-            }
+        MNIST_NETWORK.with(|network| match network {
+            Ok(network) => network.predict(image_data),
+            Err(e) => Err(e.clone()),
         })
     }
 
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use wasm_bindgen_test::wasm_bindgen_test;
+
+        #[wasm_bindgen_test]
+        fn predicts_with_a_small_loaded_model() {
+            let mut network = NeuralNetwork::new_seeded(LossFunction::CrossEntropy, 19);
+            network.add_dense_layer(3, 5, ActivationFunction::ReLU);
+            network.add_dense_layer(5, 2, ActivationFunction::Softmax);
+            let input = [0.2, -0.4, 0.9];
+
+            let wasm_network = WasmNetwork::from_bytes(&network.to_bytes().unwrap()).unwrap();
+            assert_eq!((wasm_network.input_size(), wasm_network.output_size()), (3, 2));
+            let output = wasm_network.predict(&input).unwrap();
+            assert_eq!(output, network.infer(&DMatrix::from_row_slice(1, 3, &input)).as_slice().to_vec());
+            assert!(wasm_network.predict(&[0.0; 784]).is_err());
+        }
+    }
 }
 
 // Re-export WASM specific functions, as they were placed in their own module
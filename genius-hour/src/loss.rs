@@ -1,46 +1,152 @@
 use nalgebra::DMatrix;
+use serde::{Serialize, Deserialize};
+use std::fmt;
+use std::str::FromStr;
+use crate::scalar::Real;
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum LossFunction {
     MeanSquaredError,
     CrossEntropy, // Assumes predictions are probabilities (e.g., from Softmax)
+    MeanAbsoluteError, // Less sensitive to outliers than MSE, for regression
+    // Quadratic like MSE for errors up to delta, linear like MAE beyond it
+    Huber { delta: f32 },
 }
 
 impl LossFunction {
-    pub fn calculate(&self, predictions: &DMatrix<f32>, targets: &DMatrix<f32>) -> f32 {
+    pub fn calculate<T: Real>(&self, predictions: &DMatrix<T>, targets: &DMatrix<T>) -> T {
         assert_eq!(predictions.shape(), targets.shape(), "Predictions and targets shape mismatch for loss calculation.");
-        let batch_size = predictions.nrows() as f32;
+        let batch_size = T::cast(predictions.nrows() as f64);
         match self {
             LossFunction::MeanSquaredError => {
-                (predictions - targets).map(|x| x * x).sum() / (2.0 * batch_size) // 0.5 * MSE
+                (predictions - targets).map(|x| x * x).sum() / (T::cast(2.0) * batch_size) // 0.5 * MSE
             }
             LossFunction::CrossEntropy => {
                 // Add epsilon to prevent log(0)
-                let epsilon = f32::EPSILON;
-                let clipped_predictions = predictions.map(|p| p.max(epsilon).min(1.0 - epsilon));
+                let epsilon = T::default_epsilon();
+                let clipped_predictions = predictions.map(|p| p.max(epsilon).min(T::one() - epsilon));
                 - (targets.component_mul(&clipped_predictions.map(|p| p.ln()))).sum() / batch_size
             }
+            LossFunction::MeanAbsoluteError => {
+                (predictions - targets).map(|x| x.abs()).sum() / batch_size
+            }
+            LossFunction::Huber { delta } => {
+                // Both pieces meet at |e| = delta with value 0.5 * delta^2 and slope delta
+                let delta = T::cast(*delta as f64);
+                let half = T::cast(0.5);
+                (predictions - targets).map(|e| {
+                    if e.abs() <= delta { half * e * e } else { delta * (e.abs() - half * delta) }
+                }).sum() / batch_size
+            }
         }
     }
 
     // Derivative of the loss function w.r.t. the predictions (network's output activations)
-    pub fn derivative(&self, predictions: &DMatrix<f32>, targets: &DMatrix<f32>) -> DMatrix<f32> {
+    pub fn derivative<T: Real>(&self, predictions: &DMatrix<T>, targets: &DMatrix<T>) -> DMatrix<T> {
         assert_eq!(predictions.shape(), targets.shape(), "Predictions and targets shape mismatch for loss derivative.");
-        let batch_size = predictions.nrows() as f32;
+        let batch_size = T::cast(predictions.nrows() as f64);
         match self {
             LossFunction::MeanSquaredError => {
                 (predictions - targets) / batch_size
             }
             LossFunction::CrossEntropy => {
                 // Add epsilon to prevent division by zero
-                let epsilon = f32::EPSILON;
-                let clipped_predictions = predictions.map(|p| p.max(epsilon).min(1.0 - epsilon));
+                let epsilon = T::default_epsilon();
+                let clipped_predictions = predictions.map(|p| p.max(epsilon).min(T::one() - epsilon));
                 // dL/dp = - (targets / predictions)
                 // This derivative is w.r.t. p (network output).
                 // If the last layer is Softmax, the combined derivative dL/dz = p - y is simpler.
                 // This function returns dL/dp. The network's backprop logic handles combining it.
                  -targets.component_div(&clipped_predictions) / batch_size
             }
+            LossFunction::MeanAbsoluteError => {
+                // sign(p - t), with 0 where the prediction is exact (the subgradient at the kink)
+                (predictions - targets).map(|x| if x > T::zero() { T::one() } else if x < T::zero() { -T::one() } else { T::zero() }) / batch_size
+            }
+            LossFunction::Huber { delta } => {
+                let delta = T::cast(*delta as f64);
+                (predictions - targets).map(|e| e.clamp(-delta, delta)) / batch_size
+            }
+        }
+    }
+}
+// snake_case names, the same ones FromStr accepts
+impl fmt::Display for LossFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            LossFunction::MeanSquaredError => "mean_squared_error",
+            LossFunction::CrossEntropy => "cross_entropy",
+            LossFunction::MeanAbsoluteError => "mean_absolute_error",
+            LossFunction::Huber { delta } => return write!(f, "huber:{}", delta),
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Case-insensitive, also accepts the short "mse", "ce" and "mae".
+// Huber is "huber:<delta>", or just "huber" for delta = 1
+impl FromStr for LossFunction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.trim().to_lowercase();
+        if let Some(delta) = name.strip_prefix("huber:") {
+            let delta: f32 = delta.parse().map_err(|_| format!("Invalid Huber delta in '{}'", s))?;
+            return Ok(LossFunction::Huber { delta });
+        }
+        match name.as_str() {
+            "mean_squared_error" | "mse" => Ok(LossFunction::MeanSquaredError),
+            "cross_entropy" | "ce" => Ok(LossFunction::CrossEntropy),
+            "mean_absolute_error" | "mae" => Ok(LossFunction::MeanAbsoluteError),
+            "huber" => Ok(LossFunction::Huber { delta: 1.0 }),
+            _ => Err(format!("Unknown loss function '{}', expected one of: mean_squared_error, cross_entropy, mean_absolute_error, huber:<delta>", s)),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Central difference of the loss w.r.t. every prediction
+    fn numeric_derivative(loss_fn: LossFunction, predictions: &DMatrix<f64>, targets: &DMatrix<f64>) -> DMatrix<f64> {
+        let epsilon = 1e-6;
+        DMatrix::from_fn(predictions.nrows(), predictions.ncols(), |r, c| {
+            let (mut plus, mut minus) = (predictions.clone(), predictions.clone());
+            plus[(r, c)] += epsilon;
+            minus[(r, c)] -= epsilon;
+            (loss_fn.calculate(&plus, targets) - loss_fn.calculate(&minus, targets)) / (2.0 * epsilon)
+        })
+    }
+
+    #[test]
+    fn mae_derivative_matches_finite_differences() {
+        // No error is exactly 0, where the loss has its kink
+        let predictions = DMatrix::from_row_slice(3, 2, &[0.5, -1.2, 2.0, 0.3, -0.7, 1.1]);
+        let targets = DMatrix::from_row_slice(3, 2, &[0.1, 0.4, 2.5, -0.2, -0.7001, 3.0]);
+        let analytic = LossFunction::MeanAbsoluteError.derivative(&predictions, &targets);
+        let numeric = numeric_derivative(LossFunction::MeanAbsoluteError, &predictions, &targets);
+        assert!((analytic - numeric).amax() < 1e-6);
+    }
+
+    #[test]
+    fn huber_is_continuous_at_delta_and_survives_bincode() {
+        let huber = LossFunction::Huber { delta: 1.5 };
+        let targets = DMatrix::from_element(1, 1, 0.0);
+        let loss_at = |error: f64| huber.calculate(&DMatrix::from_element(1, 1, error), &targets);
+
+        // 0.5 * delta^2 from both sides, with slope delta
+        for sign in [1.0, -1.0] {
+            let (below, above) = (loss_at(sign * (1.5 - 1e-7)), loss_at(sign * (1.5 + 1e-7)));
+            assert!((below - 1.125).abs() < 1e-6 && (above - 1.125).abs() < 1e-6, "{} and {} around the kink", below, above);
+        }
+        assert_eq!(loss_at(1.0), 0.5);
+        assert_eq!(loss_at(3.0), 1.5 * (3.0 - 0.75));
+        let predictions = DMatrix::from_row_slice(1, 4, &[0.5, -1.4, 1.6, -4.0]);
+        let zeros = DMatrix::zeros(1, 4);
+        assert!((huber.derivative(&predictions, &zeros) - numeric_derivative(huber, &predictions, &zeros)).amax() < 1e-6);
+
+        let bytes = bincode::serialize(&huber).unwrap();
+        assert_eq!(bincode::deserialize::<LossFunction>(&bytes).unwrap(), huber);
+    }
+}
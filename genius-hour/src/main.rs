@@ -1,6 +1,5 @@
-use nalgebra::DMatrix;
 use rand::seq::SliceRandom;
-use rand::thread_rng;
+use rand::rng;
 use std::io::{stdout, Write}; // For flushing print output
 
 // Use components from the current crate
@@ -16,40 +15,6 @@ mod mnist_loader;
 const IMAGE_FEATURE_SIZE: usize = 28 * 28;
 const NUM_CLASSES: usize = 10;
 
-fn calculate_accuracy(
-    network: &mut NeuralNetwork,
-    test_images: &DMatrix<f32>,
-    test_labels_raw: &DMatrix<f32>, // Raw labels (0-9), not one-hot
-) -> f32 {
-    if test_images.nrows() == 0 {
-        return 0.0;
-    }
-    let predictions = network.predict(test_images); // Forward pass for all test images
-    let mut correct_predictions = 0;
-
-    for i in 0..predictions.nrows() {
-        let pred_row = predictions.row(i); // Probabilities for each class for image i
-        let actual_label_val = test_labels_raw[(i, 0)]; // Get the scalar label
-
-        // Find the class with the highest probability
-        let (predicted_class, _max_prob) = pred_row.iter().enumerate().fold(
-            (0, -1.0f32), // (index_of_max, max_value)
-            |(idx_max, val_max), (idx, &val)| {
-                if val > val_max {
-                    (idx, val)
-                } else {
-                    (idx_max, val_max)
-                }
-            },
-        );
-
-        if predicted_class == actual_label_val as usize {
-            correct_predictions += 1;
-        }
-    }
-    correct_predictions as f32 / predictions.nrows() as f32
-}
-
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let model_path = "mnist_model.bincode"; // Path to save/load the model
 
@@ -65,9 +30,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         // Optionally, evaluate the loaded model immediately
         // (Ensure test data is loaded if you do this here)
         // let test_images = mnist_loader::load_mnist_images("mnist/t10k-images.idx3-ubyte")?;
-        // let test_labels_raw = mnist_loader::load_mnist_labels("mnist/t10k-labels.idx1-ubyte", false)?;
-        // let accuracy = calculate_accuracy(&mut nn, &test_images, &test_labels_raw);
-        // println!("Loaded model initial test accuracy: {:.2}%", accuracy * 100.0);
+        // let test_labels_one_hot = mnist_loader::load_mnist_labels("mnist/t10k-labels.idx1-ubyte", true)?;
+        // let report = nn.evaluate(&test_images, &test_labels_one_hot);
+        // println!("Loaded model initial test accuracy: {:.2}%", report.accuracy * 100.0);
 
     } else {
         println!("No existing model found. Training a new one.");
@@ -110,7 +75,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
         let num_samples = train_images.nrows();
         let mut indices: Vec<usize> = (0..num_samples).collect();
-        let mut rng = thread_rng();
+        let mut rng = rng();
 
         for epoch in 0..epochs {
             indices.shuffle(&mut rng);
@@ -139,11 +104,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             let test_images_path = "mnist/t10k-images.idx3-ubyte";
             let test_labels_path = "mnist/t10k-labels.idx1-ubyte";
             let test_images_eval = mnist_loader::load_mnist_images(test_images_path)?;
-            let test_labels_raw_eval = mnist_loader::load_mnist_labels(test_labels_path, false)?;
-            let accuracy = calculate_accuracy(&mut nn, &test_images_eval, &test_labels_raw_eval);
+            let test_labels_one_hot_eval = mnist_loader::load_mnist_labels(test_labels_path, true)?;
+            let report = nn.evaluate(&test_images_eval, &test_labels_one_hot_eval);
             
             let avg_epoch_loss = if num_batches_processed > 0 { epoch_loss / num_batches_processed as f32 } else { 0.0 };
-            println!("Epoch {}/{} - Avg Loss: {:.6} - Test Accuracy: {:.2}%", epoch + 1, epochs, avg_epoch_loss, accuracy * 100.0);
+            println!("Epoch {}/{} - Avg Loss: {:.6} - Test Accuracy: {:.2}%", epoch + 1, epochs, avg_epoch_loss, report.accuracy * 100.0);
         }
         println!("\nTraining finished.");
         // Save the trained model
@@ -159,9 +124,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let test_labels_path = "mnist/t10k-labels.idx1-ubyte";
     let test_images = mnist_loader::load_mnist_images(test_images_path)?;
     let test_labels_raw = mnist_loader::load_mnist_labels(test_labels_path, false)?;
+    let test_labels_one_hot = mnist_loader::load_mnist_labels(test_labels_path, true)?;
 
-    let final_accuracy = calculate_accuracy(&mut nn, &test_images, &test_labels_raw);
-    println!("Final Test Accuracy on the model: {:.2}%", final_accuracy * 100.0);
+    let final_report = nn.evaluate(&test_images, &test_labels_one_hot);
+    println!("Final Test Loss: {:.6} - Final Test Accuracy on the model: {:.2}%", final_report.loss, final_report.accuracy * 100.0);
 
     // Example of predicting a single image (or a small batch)
     if test_images.nrows() > 0 {
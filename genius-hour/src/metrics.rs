@@ -0,0 +1,331 @@
+// Evaluation helpers shared by the CLI, the WASM build and the library API
+
+use nalgebra::DMatrix;
+use crate::network::NeuralNetwork;
+
+// Index of the largest value (first one wins on ties), e.g. the predicted class from a row of probabilities
+pub fn argmax<'a>(values: impl IntoIterator<Item = &'a f32>) -> usize {
+    let (idx_max, _val_max) = values.into_iter().enumerate().fold(
+        (0, f32::NEG_INFINITY), // (index_of_max, max_value)
+        |(idx_max, val_max), (idx, &val)| {
+            if val > val_max {
+                (idx, val)
+            } else {
+                (idx_max, val_max)
+            }
+        },
+    );
+    idx_max
+}
+
+// argmax of every row, i.e. the predicted class of every sample in a batch of predictions
+pub fn argmax_rows(predictions: &DMatrix<f32>) -> Vec<usize> {
+    predictions.row_iter().map(|row| argmax(row.iter())).collect()
+}
+
+// The k largest values as (index, value), largest first (lower index first on ties).
+// k is clamped to the number of values.
+pub fn top_k<'a>(values: impl IntoIterator<Item = &'a f32>, k: usize) -> Vec<(usize, f32)> {
+    let mut indexed: Vec<(usize, f32)> = values.into_iter().copied().enumerate().collect();
+    // Stable sort, so ties keep their original order
+    indexed.sort_by(|a, b| b.1.total_cmp(&a.1));
+    indexed.truncate(k);
+    indexed
+}
+
+// Fraction of rows where the predicted class (argmax of the network output) matches the argmax of the one-hot target
+pub fn accuracy(network: &NeuralNetwork, inputs: &DMatrix<f32>, one_hot_targets: &DMatrix<f32>) -> f32 {
+    if inputs.nrows() == 0 {
+        return 0.0;
+    }
+    let predicted_classes = network.predict_classes_batch(inputs);
+    let actual_classes = argmax_rows(one_hot_targets);
+    let correct_predictions = predicted_classes.iter().zip(actual_classes.iter()).filter(|(p, a)| p == a).count();
+    correct_predictions as f32 / inputs.nrows() as f32
+}
+
+// Fraction of samples on which both models predict the same class (1.0 = identical behaviour),
+// e.g. to check that quantizing or pruning a model didn't change its predictions much
+pub fn prediction_agreement(model_a: &NeuralNetwork, model_b: &NeuralNetwork, inputs: &DMatrix<f32>) -> f32 {
+    if inputs.nrows() == 0 {
+        return 1.0; // Nothing to disagree on
+    }
+    let classes_a = model_a.predict_classes_batch(inputs);
+    let classes_b = model_b.predict_classes_batch(inputs);
+    let agreements = classes_a.iter().zip(classes_b.iter()).filter(|(a, b)| a == b).count();
+    agreements as f32 / inputs.nrows() as f32
+}
+
+// Average loss of the samples of every true class, to see which classes the network struggles with.
+// labels_raw holds one class index per row, a class without samples gets 0.
+pub fn per_class_loss(network: &NeuralNetwork, inputs: &DMatrix<f32>, labels_raw: &DMatrix<f32>, num_classes: usize) -> Vec<f32> {
+    let num_outputs = network.get_layers().last().map_or(0, |layer| layer.output_size());
+    assert_eq!(num_outputs, num_classes, "Expected a network with {} outputs, got {}", num_classes, num_outputs);
+    let one_hot_targets = DMatrix::from_fn(labels_raw.nrows(), num_classes, |row, class| {
+        if labels_raw[(row, 0)] as usize == class { 1.0 } else { 0.0 }
+    });
+    let mut loss_sums = vec![0.0f32; num_classes];
+    let mut counts = vec![0usize; num_classes];
+    for (loss, &label) in network.per_sample_losses(inputs, &one_hot_targets).into_iter().zip(labels_raw.column(0).iter()) {
+        loss_sums[label as usize] += loss;
+        counts[label as usize] += 1;
+    }
+    loss_sums.iter().zip(counts.iter())
+        .map(|(&loss_sum, &count)| if count > 0 { loss_sum / count as f32 } else { 0.0 })
+        .collect()
+}
+
+// Expected calibration error: predictions are grouped into num_bins equal-width bins by confidence
+// (the max probability of the row), and the gap |accuracy - mean confidence| of every bin is averaged,
+// weighted by the fraction of samples in the bin. 0 means perfectly calibrated.
+// labels_raw holds one class index per row.
+pub fn expected_calibration_error(probabilities: &DMatrix<f32>, labels_raw: &DMatrix<f32>, num_bins: usize) -> f32 {
+    assert!(num_bins > 0, "expected_calibration_error needs at least one bin");
+    if probabilities.nrows() == 0 {
+        return 0.0;
+    }
+
+    let mut bin_counts = vec![0usize; num_bins];
+    let mut bin_confidence_sums = vec![0.0f32; num_bins];
+    let mut bin_correct = vec![0usize; num_bins];
+    for (row, &label) in probabilities.row_iter().zip(labels_raw.column(0).iter()) {
+        let predicted_class = argmax(row.iter());
+        let confidence = row[predicted_class];
+        // A confidence of exactly 1.0 goes into the last bin
+        let bin = ((confidence * num_bins as f32) as usize).min(num_bins - 1);
+        bin_counts[bin] += 1;
+        bin_confidence_sums[bin] += confidence;
+        if predicted_class == label as usize {
+            bin_correct[bin] += 1;
+        }
+    }
+
+    let num_samples = probabilities.nrows() as f32;
+    (0..num_bins).filter(|&bin| bin_counts[bin] > 0).map(|bin| {
+        let count = bin_counts[bin] as f32;
+        let bin_accuracy = bin_correct[bin] as f32 / count;
+        let mean_confidence = bin_confidence_sums[bin] / count;
+        (count / num_samples) * (bin_accuracy - mean_confidence).abs()
+    }).sum()
+}
+
+// How many samples fall into each of num_bins equal-width confidence bins over [0, 1], where a sample's
+// confidence is its largest predicted probability. A shift of mass towards the low bins on live data is
+// a cheap signal that the inputs drifted away from the training distribution.
+pub fn confidence_histogram(probabilities: &DMatrix<f32>, num_bins: usize) -> Vec<usize> {
+    Histogram::from_values(probabilities.row_iter().map(|row| row.max()), num_bins, 0.0, 1.0).counts
+}
+
+// Lowest confidence threshold at which the accepted predictions (confidence >= threshold) reach
+// target_precision, for serving with an abstain option. Every distinct confidence in the set is a
+// candidate. Returns f32::INFINITY (abstain on everything) when no threshold gets there.
+// labels_raw holds one class index per row.
+pub fn threshold_for_precision(probabilities: &DMatrix<f32>, labels_raw: &DMatrix<f32>, target_precision: f32) -> f32 {
+    let mut scored: Vec<(f32, bool)> = probabilities.row_iter().zip(labels_raw.column(0).iter()).map(|(row, &label)| {
+        let predicted_class = argmax(row.iter());
+        (row[predicted_class], predicted_class == label as usize)
+    }).collect();
+    // Most confident first, so a prefix of the list is exactly what a threshold accepts
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    let mut best_threshold = f32::INFINITY;
+    let mut num_correct = 0;
+    for (i, &(confidence, correct)) in scored.iter().enumerate() {
+        if correct {
+            num_correct += 1;
+        }
+        // Samples with the same confidence are accepted or rejected together
+        if scored.get(i + 1).is_some_and(|next| next.0 == confidence) {
+            continue;
+        }
+        if num_correct as f32 / (i + 1) as f32 >= target_precision {
+            best_threshold = confidence;
+        }
+    }
+    best_threshold
+}
+
+// One-vs-rest threshold tuning: for every class, the threshold on that class's probability that maximizes
+// F1 = 2TP / (2TP + FP + FN) when predicting "this class" for every sample with probability >= threshold.
+// Every distinct probability is a candidate. Returns (best_threshold, best_f1) per class, a class without
+// any positive sample gets (f32::INFINITY, 0.0). labels_raw holds one class index per row.
+pub fn optimal_f1_per_class(probabilities: &DMatrix<f32>, labels_raw: &DMatrix<f32>, num_classes: usize) -> Vec<(f32, f32)> {
+    assert_eq!(probabilities.ncols(), num_classes, "Expected {} probability columns, got {}", num_classes, probabilities.ncols());
+    (0..num_classes).map(|class| {
+        let mut scored: Vec<(f32, bool)> = probabilities.column(class).iter().zip(labels_raw.column(0).iter())
+            .map(|(&probability, &label)| (probability, label as usize == class))
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        let num_positives = scored.iter().filter(|(_, positive)| *positive).count();
+
+        let mut best = (f32::INFINITY, 0.0);
+        let mut true_positives = 0;
+        for (i, &(probability, positive)) in scored.iter().enumerate() {
+            if positive {
+                true_positives += 1;
+            }
+            // Samples with the same probability are accepted or rejected together
+            if scored.get(i + 1).is_some_and(|next| next.0 == probability) {
+                continue;
+            }
+            // 2TP + FP + FN = accepted + positives
+            let f1 = 2.0 * true_positives as f32 / (i + 1 + num_positives) as f32;
+            if f1 > best.1 {
+                best = (probability, f1);
+            }
+        }
+        best
+    }).collect()
+}
+
+// counts[(i, j)] is the number of samples of true class i that were predicted as class j
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfusionMatrix {
+    pub counts: DMatrix<usize>,
+}
+
+impl ConfusionMatrix {
+    pub fn from_predictions(predicted: &[usize], actual: &[usize], num_classes: usize) -> Self {
+        assert_eq!(predicted.len(), actual.len(), "Got {} predictions but {} labels", predicted.len(), actual.len());
+        let mut counts = DMatrix::zeros(num_classes, num_classes);
+        for (&predicted_class, &actual_class) in predicted.iter().zip(actual.iter()) {
+            counts[(actual_class, predicted_class)] += 1;
+        }
+        ConfusionMatrix { counts }
+    }
+
+    pub fn num_classes(&self) -> usize {
+        self.counts.nrows()
+    }
+
+    // Each row divided by its sum, so row i is the distribution of predictions for true class i
+    // (the diagonal is per-class recall). Rows of classes with no samples stay all zero.
+    pub fn normalized(&self) -> DMatrix<f32> {
+        let mut normalized = self.counts.map(|count| count as f32);
+        for mut row in normalized.row_iter_mut() {
+            let row_sum = row.sum();
+            if row_sum > 0.0 {
+                row /= row_sum;
+            }
+        }
+        normalized
+    }
+}
+
+// Counts of values in num_bins equal-width bins covering [min, max].
+// Values equal to max land in the last bin, values outside the range are clamped into the end bins.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    pub min: f32,
+    pub max: f32,
+    pub counts: Vec<usize>,
+}
+
+impl Histogram {
+    pub fn from_values(values: impl IntoIterator<Item = f32>, num_bins: usize, min: f32, max: f32) -> Self {
+        let num_bins = num_bins.max(1);
+        let mut counts = vec![0; num_bins];
+        let range = max - min;
+        for value in values {
+            let bin = if range > 0.0 {
+                (((value - min) / range) * num_bins as f32).max(0.0) as usize
+            } else {
+                0
+            };
+            counts[bin.min(num_bins - 1)] += 1;
+        }
+        Histogram { min, max, counts }
+    }
+
+    pub fn bin_width(&self) -> f32 {
+        (self.max - self.min) / self.counts.len() as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::DVector;
+    use crate::activation::ActivationFunction;
+    use crate::layer::DenseLayer;
+    use crate::loss::LossFunction;
+
+    // One input feature, two classes: class 0 for positive inputs when `sign` is 1, class 1 when it is -1
+    fn threshold_network(sign: f32) -> NeuralNetwork {
+        let mut network = NeuralNetwork::new(LossFunction::CrossEntropy);
+        let weights = DMatrix::from_row_slice(1, 2, &[sign, -sign]);
+        network.add_layer(DenseLayer::from_parameters(weights, DVector::zeros(2), ActivationFunction::Softmax));
+        network
+    }
+
+    #[test]
+    fn a_model_agrees_with_itself_but_not_with_a_different_one() {
+        let inputs = DMatrix::from_fn(20, 1, |r, _| r as f32 - 9.5);
+        let model = threshold_network(1.0);
+        assert_eq!(prediction_agreement(&model, &model, &inputs), 1.0);
+        assert!(prediction_agreement(&model, &threshold_network(-1.0), &inputs) < 1.0);
+    }
+
+    #[test]
+    fn normalized_confusion_rows_sum_to_one() {
+        // Class 2 has no samples, so its row stays empty
+        let predicted = [0, 0, 1, 1, 1, 2, 0];
+        let actual = [0, 0, 0, 1, 1, 1, 3];
+        let normalized = ConfusionMatrix::from_predictions(&predicted, &actual, 4).normalized();
+        for (class, row) in normalized.row_iter().enumerate() {
+            let expected_sum = if class == 2 { 0.0 } else { 1.0 };
+            assert!((row.sum() - expected_sum).abs() < 1e-6, "Row {} sums to {}", class, row.sum());
+        }
+        assert!((normalized[(0, 0)] - 2.0 / 3.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn confidence_histogram_counts_every_sample_in_its_bin() {
+        // Confidences 0.55, 0.65 and 0.95 (twice) in 10 bins
+        let probabilities = DMatrix::from_row_slice(4, 2, &[0.55, 0.45, 0.35, 0.65, 0.95, 0.05, 0.05, 0.95]);
+        let counts = confidence_histogram(&probabilities, 10);
+        assert_eq!(counts.len(), 10);
+        assert_eq!(counts.iter().sum::<usize>(), 4);
+        assert_eq!((counts[5], counts[6], counts[9]), (1, 1, 2));
+    }
+
+    #[test]
+    fn precision_threshold_is_the_lowest_one_reaching_the_target() {
+        // Class 0 is always predicted, the ones at 0.85, 0.6 and 0.55 are wrong
+        let confidences = [0.95, 0.9, 0.85, 0.8, 0.7, 0.6, 0.55];
+        let probabilities = DMatrix::from_fn(7, 2, |r, c| if c == 0 { confidences[r] } else { 1.0 - confidences[r] });
+        let labels = DMatrix::from_column_slice(7, 1, &[0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 1.0]);
+
+        // Accepting down to 0.7 is 4 out of 5 right, going lower drops below 0.75
+        assert_eq!(threshold_for_precision(&probabilities, &labels, 0.75), 0.7);
+        assert_eq!(threshold_for_precision(&probabilities, &labels, 1.0), 0.9);
+        assert_eq!(threshold_for_precision(&probabilities, &labels, 0.5), 0.55);
+        assert_eq!(threshold_for_precision(&probabilities, &labels, 1.5), f32::INFINITY);
+    }
+
+    #[test]
+    fn separable_classes_reach_an_f1_of_one() {
+        // Every class 0 sample has a class 0 probability of at least 0.6, every class 1 sample at most 0.45
+        let class_0_probabilities = [0.9, 0.6, 0.75, 0.45, 0.1, 0.3];
+        let probabilities = DMatrix::from_fn(6, 2, |r, c| if c == 0 { class_0_probabilities[r] } else { 1.0 - class_0_probabilities[r] });
+        let labels = DMatrix::from_column_slice(6, 1, &[0.0, 0.0, 0.0, 1.0, 1.0, 1.0]);
+
+        let best = optimal_f1_per_class(&probabilities, &labels, 2);
+        assert_eq!(best.len(), 2);
+        assert_eq!(best[0], (0.6, 1.0));
+        assert!((best[1].0 - 0.55).abs() < 1e-6 && best[1].1 == 1.0, "{:?}", best[1]);
+    }
+
+    #[test]
+    fn the_class_near_the_boundary_has_the_higher_loss() {
+        // Class 0 samples are far on the positive side, class 1 samples barely on the negative side
+        let inputs = DMatrix::from_column_slice(6, 1, &[4.0, 5.0, 6.0, -0.1, -0.2, -0.3]);
+        let labels = DMatrix::from_column_slice(6, 1, &[0.0, 0.0, 0.0, 1.0, 1.0, 1.0]);
+        let losses = per_class_loss(&threshold_network(1.0), &inputs, &labels, 2);
+        assert_eq!(losses.len(), 2);
+        assert!(losses[1] > 10.0 * losses[0], "Class losses {:?}", losses);
+
+        let expected_easy_loss = (0..3).map(|r| -(1.0 / (1.0 + (-2.0 * inputs[(r, 0)]).exp())).ln()).sum::<f32>() / 3.0;
+        assert!((losses[0] - expected_easy_loss).abs() < 1e-4, "{} vs {}", losses[0], expected_easy_loss);
+    }
+}
@@ -0,0 +1,138 @@
+use nalgebra::DMatrix;
+use crate::layer::Layer;
+use crate::loss::LossFunction;
+use crate::network::{backward_through, output_layer_gradient, OutputGradient};
+
+// One output head: its own chain of layers on top of the shared trunk, with its own loss
+struct Head {
+    layers: Vec<Box<dyn Layer>>,
+    loss_fn: LossFunction,
+}
+
+// Network with a shared trunk feeding several independent heads (multi-task learning).
+// The trunk output is the input of every head, and the trunk is trained on the sum of all head losses.
+pub struct MultiHeadNetwork {
+    trunk: Vec<Box<dyn Layer>>,
+    heads: Vec<Head>,
+}
+
+impl MultiHeadNetwork {
+    pub fn new() -> Self {
+        MultiHeadNetwork {
+            trunk: Vec::new(),
+            heads: Vec::new(),
+        }
+    }
+
+    pub fn add_trunk_layer(&mut self, layer: impl Into<Box<dyn Layer>>) {
+        self.trunk.push(layer.into());
+    }
+
+    // Adds an empty head and returns its index, used with add_head_layer
+    pub fn add_head(&mut self, loss_fn: LossFunction) -> usize {
+        self.heads.push(Head { layers: Vec::new(), loss_fn });
+        self.heads.len() - 1
+    }
+
+    pub fn add_head_layer(&mut self, head_idx: usize, layer: impl Into<Box<dyn Layer>>) {
+        self.heads[head_idx].layers.push(layer.into());
+    }
+
+    pub fn get_trunk(&self) -> &[Box<dyn Layer>] {
+        &self.trunk
+    }
+
+    pub fn num_heads(&self) -> usize {
+        self.heads.len()
+    }
+
+    // Returns one prediction matrix per head, in the order the heads were added
+    pub fn predict(&mut self, input: &DMatrix<f32>) -> Vec<DMatrix<f32>> {
+        let mut trunk_output = input.clone();
+        for layer in self.trunk.iter_mut() {
+            trunk_output = layer.forward(&trunk_output);
+        }
+
+        self.heads.iter_mut().map(|head| {
+            let mut head_output = trunk_output.clone();
+            for layer in head.layers.iter_mut() {
+                head_output = layer.forward(&head_output);
+            }
+            head_output
+        }).collect()
+    }
+
+    // Trains every head on its own targets (targets[i] belongs to head i).
+    // Returns the per-head losses, the trunk is trained on their sum.
+    pub fn train_batch(
+        &mut self,
+        inputs: &DMatrix<f32>,
+        targets: &[DMatrix<f32>],
+        learning_rate: f32
+    ) -> Vec<f32> {
+        assert_eq!(targets.len(), self.heads.len(), "Expected one target matrix per head ({}), got {}", self.heads.len(), targets.len());
+
+        let predictions = self.predict(inputs);
+        let losses: Vec<f32> = self.heads.iter().zip(predictions.iter()).zip(targets.iter())
+            .map(|((head, head_predictions), head_targets)| head.loss_fn.calculate(head_predictions, head_targets))
+            .collect();
+
+        if inputs.nrows() == 0 { return losses; }
+
+        // Backpropagate through each head, summing the gradients w.r.t. the trunk output.
+        // d(sum of losses)/dA_trunk = sum of each head's dLoss/dA_trunk
+        let mut d_error_d_trunk_output: Option<DMatrix<f32>> = None;
+        for ((head, head_predictions), head_targets) in self.heads.iter_mut().zip(predictions.iter()).zip(targets.iter()) {
+            let output_gradient = output_layer_gradient(&head.layers, head.loss_fn, head_predictions, head_targets);
+            let head_input_gradient = backward_through(&mut head.layers, output_gradient, learning_rate);
+            d_error_d_trunk_output = Some(match d_error_d_trunk_output {
+                Some(accumulated) => accumulated + head_input_gradient,
+                None => head_input_gradient,
+            });
+        }
+
+        // Then backpropagate the accumulated gradient through the shared trunk
+        if let Some(d_error_da) = d_error_d_trunk_output.filter(|_| !self.trunk.is_empty()) {
+            backward_through(&mut self.trunk, OutputGradient::Output(d_error_da), learning_rate);
+        }
+        losses
+    }
+}
+
+impl Default for MultiHeadNetwork {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activation::ActivationFunction;
+    use crate::layer::DenseLayer;
+
+    #[test]
+    fn both_heads_learn_through_the_shared_trunk() {
+        let mut network = MultiHeadNetwork::new();
+        network.add_trunk_layer(DenseLayer::new_deterministic(3, 8, ActivationFunction::Sigmoid, 1));
+        let regression = network.add_head(LossFunction::MeanSquaredError);
+        network.add_head_layer(regression, DenseLayer::new_deterministic(8, 1, ActivationFunction::Linear, 2));
+        let classification = network.add_head(LossFunction::CrossEntropy);
+        network.add_head_layer(classification, DenseLayer::new_deterministic(8, 2, ActivationFunction::Softmax, 3));
+
+        let inputs = DMatrix::from_fn(16, 3, |r, c| ((r * 3 + c) as f32 * 0.7).sin());
+        let sums: Vec<f32> = inputs.row_iter().map(|row| row.sum()).collect();
+        let regression_targets = DMatrix::from_fn(16, 1, |r, _| 0.5 * sums[r]);
+        let classification_targets = DMatrix::from_fn(16, 2, |r, c| if (sums[r] > 0.0) == (c == 1) { 1.0 } else { 0.0 });
+        let targets = [regression_targets, classification_targets];
+
+        let initial_losses = network.train_batch(&inputs, &targets, 0.5);
+        let mut losses = initial_losses.clone();
+        for _ in 0..200 {
+            losses = network.train_batch(&inputs, &targets, 0.5);
+        }
+        assert_eq!(network.get_trunk().len(), 1);
+        assert!(losses[0] < 0.8 * initial_losses[0], "Regression loss {} -> {}", initial_losses[0], losses[0]);
+        assert!(losses[1] < 0.8 * initial_losses[1], "Classification loss {} -> {}", initial_losses[1], losses[1]);
+    }
+}
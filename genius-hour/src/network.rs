@@ -1,34 +1,357 @@
-use nalgebra::DMatrix;
-use crate::layer::DenseLayer;
+use nalgebra::{DMatrix, DVector};
+use crate::layer::{DenseLayer, Layer, LayerGradients};
 use crate::loss::LossFunction;
+use crate::metrics::{argmax, argmax_rows, top_k, ConfusionMatrix, Histogram};
+use crate::optimizer::{LrSchedule, Optimizer};
 use crate::activation::ActivationFunction;
-use crate::serialization::SerializableNeuralNetwork;
-use std::fs::File;
-use std::io::{BufWriter, BufReader};
-use bincode::{serialize_into, deserialize_from};
+use crate::data::Augmentation;
+use crate::ewc::EwcPenalty;
+use crate::rng::StableRng;
+use crate::scalar::Real;
+use crate::serialization::{ModelMetadata, SerializableNeuralNetwork};
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::time::{Duration, Instant};
 
-pub struct NeuralNetwork {
-    layers: Vec<DenseLayer>,
+pub struct NeuralNetwork<T: Real = f32> {
+    layers: Vec<Box<dyn Layer<T>>>,
     loss_fn: LossFunction,
+    // When set, train_batch only keeps the input of every N-th layer during the forward pass
+    // and recomputes the layer caches segment by segment during the backward pass
+    checkpoint_segment_size: Option<usize>,
+    // When set, every training step also pulls the weights back towards an earlier task's weights
+    ewc_penalty: Option<EwcPenalty<T>>,
+    // L2 regularization strength, 0 turns it off
+    weight_decay: T,
+    // Update rule used by every training step, plain SGD when None
+    optimizer: Option<Box<dyn Optimizer<T>>>,
+    // Learning rate of every training step, see set_lr_schedule. None uses the learning rate passed to the step.
+    lr_schedule: Option<ScheduledLr<T>>,
+    // Saved with the weights, see ModelMetadata
+    metadata: ModelMetadata,
+    // Layer dropout, see set_stochastic_depth
+    stochastic_depth: Option<StochasticDepth<T>>,
+    // Train/eval mode, only changes what predict does when stochastic depth is on
+    training: bool,
+    // Seeds of the layers add_dense_layer creates, None draws them from the thread RNG
+    layer_seeds: Option<StableRng>,
 }
 
-impl NeuralNetwork {
+struct ScheduledLr<T: Real> {
+    schedule: LrSchedule,
+    base_learning_rate: T,
+    step: usize, // Epoch the schedule is at, see set_lr_step
+}
+
+struct StochasticDepth<T: Real> {
+    survival_probabilities: Vec<T>,
+    rng: StableRng,
+    // How much each layer contributed to the last predict: its output is
+    // contribution * f(input) + (1 - contribution) * input, 0 when it was skipped
+    contributions: Vec<T>,
+}
+
+impl<T: Real> StochasticDepth<T> {
+    fn survival_probability(&self, i: usize) -> T {
+        self.survival_probabilities.get(i).copied().unwrap_or(T::one())
+    }
+
+    // Training mode: every layer survives (1) or is skipped (0) at random. Eval mode: its survival probability.
+    fn draw_contributions(&mut self, num_layers: usize, training: bool) {
+        self.contributions = (0..num_layers).map(|i| {
+            let probability = self.survival_probability(i);
+            if !training || probability == T::one() {
+                probability
+            } else if T::cast(self.rng.next_f64()) < probability {
+                T::one()
+            } else {
+                T::zero()
+            }
+        }).collect();
+    }
+}
+
+impl<T: Real> NeuralNetwork<T> {
     pub fn new(loss_fn: LossFunction) -> Self {
         NeuralNetwork {
             layers: Vec::new(),
             loss_fn,
+            checkpoint_segment_size: None,
+            ewc_penalty: None,
+            weight_decay: T::zero(),
+            optimizer: None,
+            lr_schedule: None,
+            metadata: ModelMetadata::default(),
+            stochastic_depth: None,
+            training: true,
+            layer_seeds: None,
+        }
+    }
+
+    // Same as new, but every layer created with add_dense_layer gets reproducible
+    // weights: the n-th layer uses DenseLayer::new_deterministic with the n-th seed drawn from `seed`, so the
+    // same seed and architecture give bit-identical networks on any machine. Pair it with
+    // TrainingConfig::seed for a fully reproducible run.
+    pub fn new_seeded(loss_fn: LossFunction, seed: u64) -> Self {
+        let mut nn = NeuralNetwork::new(loss_fn);
+        nn.layer_seeds = Some(StableRng::new(seed));
+        nn
+    }
+
+    // Same as new, but training updates the weights with the given optimizer instead of plain SGD,
+    // e.g. NeuralNetwork::new_with_optimizer(LossFunction::CrossEntropy, Box::new(Adam::default())).
+    // The optimizer's state (like Adam's moments) lives only in memory and isn't saved with the weights.
+    pub fn new_with_optimizer(loss_fn: LossFunction, optimizer: Box<dyn Optimizer<T>>) -> Self {
+        let mut nn = NeuralNetwork::new(loss_fn);
+        nn.optimizer = Some(optimizer);
+        nn
+    }
+
+    // Multilayer perceptron from its layer sizes, input first: every consecutive pair becomes a DenseLayer,
+    // the hidden ones with hidden_activation and the last one with output_activation.
+    // The MNIST network is NeuralNetwork::mlp(&[784, 128, 64, 10], ReLU, Softmax, CrossEntropy).
+    pub fn mlp(
+        sizes: &[usize],
+        hidden_activation: ActivationFunction,
+        output_activation: ActivationFunction,
+        loss_fn: LossFunction,
+    ) -> Self {
+        assert!(sizes.len() >= 2, "An MLP needs at least an input and an output size, got {:?}", sizes);
+        let mut nn = NeuralNetwork::new(loss_fn);
+        let num_layers = sizes.len() - 1;
+        for (i, pair) in sizes.windows(2).enumerate() {
+            let activation_fn = if i == num_layers - 1 { output_activation } else { hidden_activation };
+            nn.add_dense_layer(pair[0], pair[1], activation_fn);
+        }
+        nn
+    }
+
+    // Gradient checkpointing trades compute for memory: only one activation per segment of
+    // `segment_size` layers is kept alive instead of every layer's input and Z caches.
+    // The resulting gradients (and weights) are identical to normal training. None turns it off.
+    pub fn set_gradient_checkpointing(&mut self, segment_size: Option<usize>) {
+        self.checkpoint_segment_size = segment_size.map(|size| size.max(1));
+    }
+
+    // Adds an Elastic Weight Consolidation penalty to every training step (and to the loss it returns),
+    // see EwcPenalty. None turns it off, e.g. when starting over on a single task.
+    pub fn set_ewc_penalty(&mut self, penalty: Option<EwcPenalty<T>>) {
+        if let Some(penalty) = &penalty {
+            penalty.check_shapes(&self.layers);
+        }
+        self.ewc_penalty = penalty;
+    }
+
+    // L2 regularization: every training step adds lambda/2 * ||W||^2 (summed over all layers' weights, biases
+    // excluded) to the loss it returns and lambda * W to the weight gradients. 0 (the default) turns it off.
+    pub fn set_weight_decay(&mut self, lambda: T) {
+        self.weight_decay = lambda;
+    }
+
+    // Learning rate schedule: from now on every training step (train_batch, fit, ...) uses current_lr(lr_step())
+    // and ignores the learning rate it's given. fit moves the schedule to the current epoch, a hand-written
+    // training loop calls set_lr_step itself. Starts at step 0.
+    pub fn set_lr_schedule(&mut self, schedule: LrSchedule, base_learning_rate: T) {
+        self.lr_schedule = Some(ScheduledLr { schedule, base_learning_rate, step: 0 });
+    }
+
+    // Back to training with the learning rate passed to every step (the default)
+    pub fn clear_lr_schedule(&mut self) {
+        self.lr_schedule = None;
+    }
+
+    pub fn lr_schedule(&self) -> Option<LrSchedule> {
+        self.lr_schedule.as_ref().map(|scheduled| scheduled.schedule)
+    }
+
+    // Learning rate of the schedule at step (epoch) `step`, None without a schedule
+    pub fn current_lr(&self, step: usize) -> Option<T> {
+        self.lr_schedule.as_ref().map(|scheduled| scheduled.base_learning_rate * T::cast(scheduled.schedule.multiplier(step)))
+    }
+
+    // Step the schedule is at, 0 without a schedule
+    pub fn lr_step(&self) -> usize {
+        self.lr_schedule.as_ref().map_or(0, |scheduled| scheduled.step)
+    }
+
+    pub fn set_lr_step(&mut self, step: usize) {
+        if let Some(scheduled) = self.lr_schedule.as_mut() {
+            scheduled.step = step;
+        }
+    }
+
+    // The learning rate a training step given `learning_rate` actually uses
+    fn effective_lr(&self, learning_rate: T) -> T {
+        self.current_lr(self.lr_step()).unwrap_or(learning_rate)
+    }
+
+    // Stochastic depth (layer dropout): in training mode, predict (and so every training step) skips layer i
+    // with probability 1 - survival_probabilities[i], passing its input through unchanged. In eval mode and
+    // in infer every layer is used, its output scaled to the expected one: p * f(input) + (1 - p) * input.
+    // A layer can only be skipped if its input and output sizes match, and the last layer never is.
+    // Layers added later always survive. None turns it off. Training steps with it on don't use gradient checkpointing.
+    pub fn set_stochastic_depth(&mut self, survival_probabilities: Option<Vec<T>>, seed: u64) {
+        self.stochastic_depth = survival_probabilities.map(|survival_probabilities| {
+            assert_eq!(survival_probabilities.len(), self.layers.len(), "Expected one survival probability per layer ({}), got {}", self.layers.len(), survival_probabilities.len());
+            for (i, (layer, &probability)) in self.layers.iter().zip(survival_probabilities.iter()).enumerate() {
+                assert!(probability > T::zero() && probability <= T::one(), "Survival probability of layer {} must be in (0, 1], got {}", i, probability);
+                if probability < T::one() {
+                    assert!(i + 1 < self.layers.len(), "The last layer can't be skipped");
+                    assert_eq!(layer.input_size(), layer.output_size(), "Layer {} can't be skipped, its input and output sizes differ", i);
+                }
+            }
+            StochasticDepth {
+                survival_probabilities,
+                rng: StableRng::new(seed),
+                contributions: Vec::new(),
+            }
+        });
+    }
+
+    // Training mode (the default) vs eval mode, see set_stochastic_depth
+    pub fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+
+    pub fn is_training(&self) -> bool {
+        self.training
+    }
+
+    // Layers skipped by the last predict in training mode, all false without stochastic depth
+    pub fn skipped_layers(&self) -> Vec<bool> {
+        match &self.stochastic_depth {
+            Some(stochastic_depth) => (0..self.layers.len())
+                .map(|i| stochastic_depth.contributions.get(i).is_some_and(|&contribution| contribution == T::zero()))
+                .collect(),
+            None => vec![false; self.layers.len()],
         }
     }
 
-    pub fn get_layers(&self) -> &Vec<DenseLayer> {
+    // Eval-mode contribution of layer i, 1 without stochastic depth
+    fn survival_probability(&self, i: usize) -> T {
+        self.stochastic_depth.as_ref().map_or(T::one(), |stochastic_depth| stochastic_depth.survival_probability(i))
+    }
+
+    // Output of layer i in eval mode
+    fn infer_layer(&self, i: usize, input: &DMatrix<T>) -> DMatrix<T> {
+        mix_with_input(self.layers[i].infer(input), input, self.survival_probability(i))
+    }
+
+    pub fn get_layers(&self) -> &[Box<dyn Layer<T>>] {
         &self.layers
     }
 
-    pub fn add_layer(&mut self, layer: DenseLayer) {
-        self.layers.push(layer);
+    // Layer i if it is a DenseLayer, None if it's another kind of layer (or out of range)
+    pub fn dense_layer(&self, i: usize) -> Option<&DenseLayer<T>> {
+        self.layers.get(i).and_then(|layer| layer.as_dense())
+    }
+
+    pub fn dense_layer_mut(&mut self, i: usize) -> Option<&mut DenseLayer<T>> {
+        self.layers.get_mut(i).and_then(|layer| layer.as_dense_mut())
+    }
+
+    // Every layer as a DenseLayer, for the tools and file formats that only handle dense networks.
+    // Fails on the first layer of another kind.
+    pub fn dense_layers(&self) -> Result<Vec<&DenseLayer<T>>, String> {
+        self.layers.iter().enumerate()
+            .map(|(i, layer)| layer.as_dense().ok_or_else(|| not_dense_error(i, layer.layer_type())))
+            .collect()
+    }
+
+    pub fn dense_layers_mut(&mut self) -> Result<Vec<&mut DenseLayer<T>>, String> {
+        self.layers.iter_mut().enumerate()
+            .map(|(i, layer)| {
+                let layer_type = layer.layer_type();
+                layer.as_dense_mut().ok_or_else(|| not_dense_error(i, layer_type))
+            })
+            .collect()
+    }
+
+    pub fn get_loss_fn(&self) -> LossFunction {
+        self.loss_fn
+    }
+
+    pub fn metadata(&self) -> &ModelMetadata {
+        &self.metadata
+    }
+
+    pub fn metadata_mut(&mut self) -> &mut ModelMetadata {
+        &mut self.metadata
+    }
+
+    // Mutable access to the layers' parameters, a slice so layers can't be added or removed this way
+    pub fn get_layers_mut(&mut self) -> &mut [Box<dyn Layer<T>>] {
+        &mut self.layers
     }
 
-    pub fn predict(&mut self, input: &DMatrix<f32>) -> DMatrix<f32> {
+    // Takes any layer by value (a DenseLayer, Conv2DLayer, ...) or an already boxed one
+    pub fn add_layer(&mut self, layer: impl Into<Box<dyn Layer<T>>>) {
+        self.layers.push(layer.into());
+    }
+
+    // Creates and adds a freshly initialized DenseLayer, seeded when the network was made with new_seeded
+    pub fn add_dense_layer(&mut self, input_size: usize, output_size: usize, activation_fn: ActivationFunction) {
+        let layer = match self.layer_seeds.as_mut() {
+            Some(rng) => DenseLayer::new_deterministic(input_size, output_size, activation_fn, rng.next_u64()),
+            None => DenseLayer::new(input_size, output_size, activation_fn),
+        };
+        self.layers.push(Box::new(layer));
+    }
+
+    // Copy of every layer's weights and biases, to compare against later with diff_from
+    pub fn snapshot(&self) -> NetworkSnapshot<T> {
+        NetworkSnapshot {
+            layers: self.layers.iter().map(|layer| {
+                layer.parameters().map_or_else(|| (DMatrix::zeros(0, 0), DVector::zeros(0)), |(weights, biases)| (weights.clone(), biases.clone()))
+            }).collect(),
+        }
+    }
+
+    // L2 norm of how much every layer's parameters (weights and biases together) moved since the snapshot,
+    // e.g. around a phase of training to see which layers change most
+    pub fn diff_from(&self, snapshot: &NetworkSnapshot<T>) -> Vec<T> {
+        assert_eq!(snapshot.layers.len(), self.layers.len(), "Snapshot has {} layers, the network {}", snapshot.layers.len(), self.layers.len());
+        self.layers.iter().zip(snapshot.layers.iter()).map(|(layer, (weights, biases))| {
+            match layer.parameters() {
+                Some((layer_weights, layer_biases)) => {
+                    assert_eq!(layer_weights.shape(), weights.shape(), "Snapshot layer shapes don't match the network");
+                    ((layer_weights - weights).norm_squared() + (layer_biases - biases).norm_squared()).sqrt()
+                }
+                None => T::zero(),
+            }
+        }).collect()
+    }
+
+    // Checks that the network has exactly the expected (input_size, output_size) layers, e.g. right
+    // after loading a model file, so a wrong file fails fast instead of on the first prediction
+    pub fn verify_architecture(&self, expected: &[(usize, usize)]) -> Result<(), Box<dyn std::error::Error>> {
+        if self.layers.len() != expected.len() {
+            return Err(format!("Expected {} layers, the network has {}", expected.len(), self.layers.len()).into());
+        }
+        for (i, (layer, &(input_size, output_size))) in self.layers.iter().zip(expected).enumerate() {
+            if layer.input_size() != input_size || layer.output_size() != output_size {
+                return Err(format!(
+                    "Layer {} is {}x{}, expected {}x{}",
+                    i, layer.input_size(), layer.output_size(), input_size, output_size
+                ).into());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn predict(&mut self, input: &DMatrix<T>) -> DMatrix<T> {
+        if let Some(stochastic_depth) = self.stochastic_depth.as_mut() {
+            stochastic_depth.draw_contributions(self.layers.len(), self.training);
+            let mut current_output = input.clone();
+            for (layer, &contribution) in self.layers.iter_mut().zip(stochastic_depth.contributions.iter()) {
+                if contribution == T::zero() {
+                    layer.clear_cache();
+                    continue;
+                }
+                current_output = mix_with_input(layer.forward(&current_output), &current_output, contribution);
+            }
+            return current_output;
+        }
         let mut current_output = input.clone();
         for layer in self.layers.iter_mut() {
             // Corrected line: pass by reference ¤t_output
@@ -37,12 +360,77 @@ impl NeuralNetwork {
         current_output
     }
 
+    // Same output as predict in eval mode, but without filling the layer caches used for backpropagation
+    pub fn infer(&self, input: &DMatrix<T>) -> DMatrix<T> {
+        let mut current_output = input.clone();
+        for i in 0..self.layers.len() {
+            current_output = self.infer_layer(i, &current_output);
+        }
+        current_output
+    }
+
+    // Output of the last layer before its activation (the logits when the last layer is Softmax)
+    pub fn logits(&self, input: &DMatrix<T>) -> DMatrix<T> {
+        let last_layer = self.dense_output_layer();
+        let mut current_output = input.clone();
+        for i in 0..self.layers.len() - 1 {
+            current_output = self.infer_layer(i, &current_output);
+        }
+        last_layer.weighted_sum(&current_output)
+    }
+
+    // Like infer, but keeps every layer's output (after its activation), outputs[i] belongs to layer i
+    pub fn layer_outputs(&self, input: &DMatrix<T>) -> Vec<DMatrix<T>> {
+        let mut outputs: Vec<DMatrix<T>> = Vec::with_capacity(self.layers.len());
+        for i in 0..self.layers.len() {
+            let layer_output = self.infer_layer(i, outputs.last().unwrap_or(input));
+            outputs.push(layer_output);
+        }
+        outputs
+    }
+
+    // Mean forward time of every layer over `iterations` inference passes on input, durations[i] belongs
+    // to layer i. Shows which layer is worth shrinking or quantizing.
+    pub fn profile_layers(&self, input: &DMatrix<T>, iterations: usize) -> Vec<Duration> {
+        assert!(iterations > 0, "profile_layers needs at least one iteration");
+        let mut totals = vec![Duration::ZERO; self.layers.len()];
+        for _ in 0..iterations {
+            let mut current_output = input.clone();
+            for (layer, total) in self.layers.iter().zip(totals.iter_mut()) {
+                let start = Instant::now();
+                current_output = layer.infer(&current_output);
+                *total += start.elapsed();
+            }
+        }
+        totals.into_iter().map(|total| total / iterations as u32).collect()
+    }
+
+    // Loss of every sample (row) on its own, under the current weights
+    pub fn per_sample_losses(&self, inputs: &DMatrix<T>, targets: &DMatrix<T>) -> Vec<T> {
+        let predictions = self.infer(inputs);
+        (0..predictions.nrows())
+            .map(|i| self.loss_fn.calculate(&predictions.rows(i, 1).into_owned(), &targets.rows(i, 1).into_owned()))
+            .collect()
+    }
+
     pub fn train_batch(
         &mut self, 
-        inputs: &DMatrix<f32>, 
-        targets: &DMatrix<f32>, 
-        learning_rate: f32
-    ) -> f32 {
+        inputs: &DMatrix<T>, 
+        targets: &DMatrix<T>, 
+        learning_rate: T
+    ) -> T {
+        let learning_rate = self.effective_lr(learning_rate);
+        if let (Some(segment_size), None) = (self.checkpoint_segment_size, &self.stochastic_depth) {
+            return self.train_batch_checkpointed(inputs, targets, learning_rate, segment_size);
+        }
+        if self.ewc_penalty.is_some() || self.weight_decay != T::zero() || self.optimizer.is_some() || self.stochastic_depth.is_some() {
+            // Penalties have to be added to the gradients before they're applied,
+            // optimizers need all gradients at once and skipped layers have to be stepped over
+            let (loss, gradients) = self.compute_gradients(inputs, targets);
+            self.apply_gradients(&gradients, learning_rate);
+            return loss;
+        }
+
         // Forward pass
         // This also caches inputs and z_values in layers, to avoid recalculation
         let predictions = self.predict(inputs); 
@@ -51,58 +439,987 @@ impl NeuralNetwork {
         let loss = self.loss_fn.calculate(&predictions, targets);
 
         // Backward pass
-        // Calculate initial gradient: dError/dZ_L for the last layer L
-        let mut d_error_dz: DMatrix<f32>;
-        let last_layer_idx = self.layers.len() - 1;
-
-        // Special case for Softmax + CrossEntropy: dLoss/dZ = Predictions - Targets
-        if self.layers[last_layer_idx].activation_fn == ActivationFunction::Softmax &&
-           self.loss_fn == LossFunction::CrossEntropy {
-            let batch_size = predictions.nrows() as f32;
-            if batch_size == 0.0 { return loss; } // Avoid division by zero if batch is empty
-            d_error_dz = (&predictions - targets) / batch_size;
-        } else {
-            // General case: dError/dZ_L = dError/dA_L * dA_L/dZ_L
-            let d_error_da = self.loss_fn.derivative(&predictions, targets); 
-            // Bad: Cloning z_cache every time is expensive, but I couldn't get a mutable reference to it
-            let last_layer_z_cache = self.layers[last_layer_idx].z_cache.clone(); 
-            let da_dz = self.layers[last_layer_idx].activation_fn.derivative(&last_layer_z_cache); 
-            d_error_dz = d_error_da.component_mul(&da_dz); 
-        }
+        // Calculate initial gradient: dError/dZ_L for the last layer L (dError/dA_L if it isn't dense)
+        if predictions.nrows() == 0 { return loss; } // Avoid division by zero if batch is empty
+        let output_gradient = output_layer_gradient(&self.layers, self.loss_fn, &predictions, targets);
 
         // Propagate gradient backwards starting from the last layer
-        let mut gradient_from_next_layer_wrt_activation = 
-            self.layers[last_layer_idx].backward(&d_error_dz, learning_rate);
-
-        // For hidden layers (from L-1 down to 0)
-        for i in (0..last_layer_idx).rev() {
-            // gradient_from_next_layer_wrt_activation is dError/dA_current
-            // Accessing z_cache, clone to avoid borrow conflicts.
-            let current_layer_z_cache = self.layers[i].z_cache.clone(); 
-            let da_dz_current = self.layers[i].activation_fn.derivative(&current_layer_z_cache);
-            
-            d_error_dz = gradient_from_next_layer_wrt_activation.component_mul(&da_dz_current);
-            
-            gradient_from_next_layer_wrt_activation = 
-                self.layers[i].backward(&d_error_dz, learning_rate);
+        backward_through(&mut self.layers, output_gradient, learning_rate);
+        loss
+    }
+
+    fn train_batch_checkpointed(
+        &mut self,
+        inputs: &DMatrix<T>,
+        targets: &DMatrix<T>,
+        learning_rate: T,
+        segment_size: usize
+    ) -> T {
+        let segment_starts: Vec<usize> = (0..self.layers.len()).step_by(segment_size).collect();
+
+        // Forward pass without caches, only remembering the input of each segment
+        let mut segment_inputs = Vec::with_capacity(segment_starts.len());
+        let mut current_output = inputs.clone();
+        for &start in segment_starts.iter() {
+            let end = (start + segment_size).min(self.layers.len());
+            segment_inputs.push(current_output.clone());
+            for layer in self.layers[start..end].iter() {
+                current_output = layer.infer(&current_output);
+            }
         }
+        let predictions = current_output;
+        let mut loss = self.loss_fn.calculate(&predictions, targets);
+        if predictions.nrows() == 0 { return loss; }
+
+        // Backward pass, last segment first: refill that segment's caches from its saved input,
+        // backpropagate through it, then drop the caches again
+        let mut gradients = Vec::with_capacity(self.layers.len());
+        let mut d_error_d_segment_output: Option<DMatrix<T>> = None;
+        for (&start, segment_input) in segment_starts.iter().zip(segment_inputs.iter()).rev() {
+            let end = (start + segment_size).min(self.layers.len());
+            let segment = &mut self.layers[start..end];
+
+            let mut recomputed = segment_input.clone();
+            for layer in segment.iter_mut() {
+                recomputed = layer.forward(&recomputed);
+            }
+
+            let output_gradient = match d_error_d_segment_output {
+                None => output_layer_gradient(segment, self.loss_fn, &predictions, targets),
+                Some(d_error_da) => OutputGradient::Output(d_error_da),
+            };
+            let (segment_gradients, input_gradient) = gradients_through(segment, output_gradient);
+            gradients.push(segment_gradients);
+            d_error_d_segment_output = Some(input_gradient);
+
+            for layer in segment.iter_mut() {
+                layer.clear_cache();
+            }
+        }
+
+        gradients.reverse();
+        let mut gradients: Vec<LayerGradients<T>> = gradients.into_iter().flatten().collect();
+        loss += self.add_penalties(&mut gradients);
+        self.apply_gradients(&gradients, learning_rate);
         loss
     }
 
+    // Same as train_batch, but also measures how long the forward pass, the backward pass
+    // and the weight update took. Kept separate so train_batch doesn't pay for the timers.
+    pub fn train_batch_profiled(
+        &mut self,
+        inputs: &DMatrix<T>,
+        targets: &DMatrix<T>,
+        learning_rate: T
+    ) -> (T, PhaseTimings) {
+        let mut timings = PhaseTimings::default();
+
+        let start = Instant::now();
+        let predictions = self.predict(inputs);
+        let mut loss = self.loss_fn.calculate(&predictions, targets);
+        timings.forward = start.elapsed();
+        if predictions.nrows() == 0 { return (loss, timings); }
+
+        let start = Instant::now();
+        let output_gradient = output_layer_gradient(&self.layers, self.loss_fn, &predictions, targets);
+        let (mut gradients, _) = self.gradients_through_layers(output_gradient);
+        loss += self.add_penalties(&mut gradients);
+        timings.backward = start.elapsed();
+
+        let start = Instant::now();
+        self.apply_gradients(&gradients, learning_rate);
+        timings.update = start.elapsed();
+
+        (loss, timings)
+    }
+
+    // Same as train_batch, but the weights are updated by the given optimizer instead of plain SGD
+    pub fn train_batch_with_optimizer(
+        &mut self,
+        inputs: &DMatrix<T>,
+        targets: &DMatrix<T>,
+        learning_rate: T,
+        optimizer: &mut dyn Optimizer<T>
+    ) -> T {
+        let (loss, gradients) = self.compute_gradients(inputs, targets);
+        let learning_rate = self.effective_lr(learning_rate);
+        optimizer.step(&mut self.layers, &gradients, learning_rate);
+        loss
+    }
+
+    // Forward + backward pass without updating any weights.
+    // Returns the loss and one LayerGradients per layer (same order as get_layers).
+    pub fn compute_gradients(&mut self, inputs: &DMatrix<T>, targets: &DMatrix<T>) -> (T, Vec<LayerGradients<T>>) {
+        let predictions = self.predict(inputs);
+        let loss = self.loss_fn.calculate(&predictions, targets);
+        if predictions.nrows() == 0 {
+            return (loss, self.layers.iter().map(|layer| LayerGradients::zeros_like(layer.as_ref())).collect());
+        }
+
+        let output_gradient = output_layer_gradient(&self.layers, self.loss_fn, &predictions, targets);
+        let (mut gradients, _) = self.gradients_through_layers(output_gradient);
+        let loss = loss + self.add_penalties(&mut gradients);
+        (loss, gradients)
+    }
+
+    // Adds the gradients of the weight decay and EWC penalties (where enabled) and returns the penalty to add to the loss
+    fn add_penalties(&self, gradients: &mut [LayerGradients<T>]) -> T {
+        let mut penalty = T::zero();
+        if self.weight_decay != T::zero() {
+            for (layer, layer_gradients) in self.layers.iter().zip(gradients.iter_mut()) {
+                if let Some((weights, _)) = layer.parameters() {
+                    layer_gradients.weights += weights * self.weight_decay;
+                    penalty += T::cast(0.5) * self.weight_decay * weights.norm_squared();
+                }
+            }
+        }
+        if let Some(ewc_penalty) = &self.ewc_penalty {
+            ewc_penalty.add_gradients(&self.layers, gradients);
+            penalty += ewc_penalty.penalty(&self.layers);
+        }
+        penalty
+    }
+
+    // dLoss/dInput for every sample (row) of inputs, the weights are left untouched
+    pub fn input_gradient(&mut self, inputs: &DMatrix<T>, targets: &DMatrix<T>) -> DMatrix<T> {
+        let predictions = self.predict(inputs);
+        if predictions.nrows() == 0 { return DMatrix::zeros(0, inputs.ncols()); }
+        let output_gradient = output_layer_gradient(&self.layers, self.loss_fn, &predictions, targets);
+        self.input_gradient_through_layers(output_gradient)
+    }
+
+    // Gradient of one output logit (the last layer's Z, before the activation) w.r.t. every input feature.
+    // Needs a DenseLayer as the last layer.
+    pub fn logit_input_gradient(&mut self, inputs: &DMatrix<T>, class: usize) -> DMatrix<T> {
+        self.dense_output_layer();
+        let predictions = self.predict(inputs);
+        let mut d_logit_dz = DMatrix::zeros(predictions.nrows(), predictions.ncols());
+        d_logit_dz.column_mut(class).fill(T::one());
+        self.input_gradient_through_layers(OutputGradient::PreActivation(d_logit_dz))
+    }
+
+    // The last layer as a DenseLayer, for everything that works with logits (the last layer's Z)
+    pub(crate) fn dense_output_layer(&self) -> &DenseLayer<T> {
+        let last_layer = self.layers.last().expect("Network has no layers");
+        last_layer.as_dense().unwrap_or_else(|| panic!("The last layer is a {} layer, this needs a dense one", last_layer.layer_type()))
+    }
+
+    // gradients_through the whole network, following the layer contributions of the last predict.
+    // Everything that backpropagates after self.predict goes through these two, so skipped layers
+    // (whose caches are cleared) are stepped over.
+    pub(crate) fn gradients_through_layers(&self, output_gradient: OutputGradient<T>) -> (Vec<LayerGradients<T>>, DMatrix<T>) {
+        match &self.stochastic_depth {
+            Some(stochastic_depth) => gradients_through_scaled(&self.layers, &stochastic_depth.contributions, output_gradient),
+            None => gradients_through(&self.layers, output_gradient),
+        }
+    }
+
+    pub(crate) fn input_gradient_through_layers(&self, output_gradient: OutputGradient<T>) -> DMatrix<T> {
+        match &self.stochastic_depth {
+            Some(stochastic_depth) => gradients_through_scaled(&self.layers, &stochastic_depth.contributions, output_gradient).1,
+            None => input_gradient_through(&self.layers, output_gradient),
+        }
+    }
+
+    // Numerical gradient check: every weight and bias is nudged by +-epsilon, the central difference of the loss
+    // is compared to the gradient from compute_gradients, and the largest relative error
+    // |analytic - numeric| / max(|analytic| + |numeric|, epsilon) is returned. The weights end up unchanged.
+    // Run it with NeuralNetwork<f64> on a tiny network: in f32 the rounding in the loss alone gives errors
+    // around 1e-3. Stochastic depth is checked in eval mode so every loss sees the same layers.
+    // Every sample (row) is checked on its own: on a batch of m samples the analytic gradients come out m times
+    // smaller than the loss's, since both the loss derivative and DenseLayer::compute_gradients divide by the batch size.
+    pub fn check_gradients(&mut self, inputs: &DMatrix<T>, targets: &DMatrix<T>, epsilon: T) -> T {
+        assert_eq!(inputs.nrows(), targets.nrows(), "Inputs ({}) and targets ({}) must have the same number of samples", inputs.nrows(), targets.nrows());
+        let training = self.training;
+        self.training = false;
+        let mut max_relative_error = T::zero();
+        for row in 0..inputs.nrows() {
+            let relative_error = self.check_sample_gradients(&inputs.rows(row, 1).into_owned(), &targets.rows(row, 1).into_owned(), epsilon);
+            max_relative_error = max_relative_error.max(relative_error);
+        }
+        self.training = training;
+        max_relative_error
+    }
+
+    fn check_sample_gradients(&mut self, input: &DMatrix<T>, target: &DMatrix<T>, epsilon: T) -> T {
+        let (_, analytic_gradients) = self.compute_gradients(input, target);
+
+        let mut max_relative_error = T::zero();
+        let mut compare = |analytic: T, numeric: T| {
+            let relative_error = (analytic - numeric).abs() / (analytic.abs() + numeric.abs()).max(epsilon);
+            max_relative_error = max_relative_error.max(relative_error);
+        };
+        // Layers without parameters have empty gradients, so there's nothing to nudge
+        for (i, layer_gradients) in analytic_gradients.iter().enumerate() {
+            for (idx, &analytic) in layer_gradients.weights.iter().enumerate() {
+                compare(analytic, self.numeric_gradient(i, false, idx, input, target, epsilon));
+            }
+            for (idx, &analytic) in layer_gradients.biases.iter().enumerate() {
+                compare(analytic, self.numeric_gradient(i, true, idx, input, target, epsilon));
+            }
+        }
+        max_relative_error
+    }
+
+    // Central difference of the loss w.r.t. weight idx (column-major) or bias idx of layer i
+    fn numeric_gradient(&mut self, i: usize, is_bias: bool, idx: usize, input: &DMatrix<T>, target: &DMatrix<T>, epsilon: T) -> T {
+        let original = *self.parameter_mut(i, is_bias, idx);
+        *self.parameter_mut(i, is_bias, idx) = original + epsilon;
+        let (loss_plus, _) = self.compute_gradients(input, target);
+        *self.parameter_mut(i, is_bias, idx) = original - epsilon;
+        let (loss_minus, _) = self.compute_gradients(input, target);
+        *self.parameter_mut(i, is_bias, idx) = original;
+        (loss_plus - loss_minus) / (epsilon + epsilon)
+    }
+
+    // Copy of every layer's weights, 0x0 for layers without parameters
+    pub(crate) fn weight_matrices(&self) -> Vec<DMatrix<T>> {
+        self.layers.iter().map(|layer| layer.parameters().map_or_else(|| DMatrix::zeros(0, 0), |(weights, _)| weights.clone())).collect()
+    }
+
+    fn parameter_mut(&mut self, i: usize, is_bias: bool, idx: usize) -> &mut T {
+        let (weights, biases) = self.layers[i].parameters_mut().expect("Only layers with parameters have gradients");
+        if is_bias { &mut biases[idx] } else { &mut weights[idx] }
+    }
+
+    // One update with the network's optimizer (plain SGD if it has none), at the scheduled learning rate if there's a schedule
+    pub fn apply_gradients(&mut self, gradients: &[LayerGradients<T>], learning_rate: T) {
+        assert_eq!(gradients.len(), self.layers.len(), "Expected one LayerGradients per layer ({}), got {}", self.layers.len(), gradients.len());
+        let learning_rate = self.effective_lr(learning_rate);
+        match self.optimizer.as_mut() {
+            Some(optimizer) => optimizer.step(&mut self.layers, gradients, learning_rate),
+            None => {
+                for (layer, layer_gradients) in self.layers.iter_mut().zip(gradients) {
+                    layer.apply_gradients(layer_gradients, learning_rate);
+                }
+            }
+        }
+    }
+
+}
+
+// Everything tied to f32: class predictions, diagnostics and the on-disk format
+impl NeuralNetwork {
+    // Most likely class for a single flattened sample
+    pub fn predict_class(&self, input: &[f32]) -> Result<usize, Box<dyn std::error::Error>> {
+        let expected_input_size = self.layers.first().map_or(0, |layer| layer.input_size());
+        if input.len() != expected_input_size {
+            return Err(format!("Invalid input length. Expected {}, got {}", expected_input_size, input.len()).into());
+        }
+        let output = self.infer(&DMatrix::from_row_slice(1, input.len(), input));
+        Ok(argmax(output.iter()))
+    }
+
+    // Most likely class for every row of inputs
+    pub fn predict_classes_batch(&self, inputs: &DMatrix<f32>) -> Vec<usize> {
+        argmax_rows(&self.infer(inputs))
+    }
+
+    // Entry (i, j) counts the samples of true class i predicted as class j, so the diagonal holds the
+    // correct predictions of every class. raw_labels holds one class index per row.
+    pub fn confusion_matrix(&self, inputs: &DMatrix<f32>, raw_labels: &DMatrix<f32>, num_classes: usize) -> DMatrix<usize> {
+        let actual_classes: Vec<usize> = raw_labels.column(0).iter().map(|&label| label as usize).collect();
+        ConfusionMatrix::from_predictions(&self.predict_classes_batch(inputs), &actual_classes, num_classes).counts
+    }
+
+    // The k most likely (class, probability) pairs of every row of inputs, most likely first.
+    // k larger than the number of classes returns every class.
+    pub fn predict_top_k(&self, inputs: &DMatrix<f32>, k: usize) -> Vec<Vec<(usize, f32)>> {
+        self.infer(inputs).row_iter().map(|row| top_k(row.iter(), k)).collect()
+    }
+
+    // Average loss and argmax accuracy on a labelled set (one-hot targets, any number of classes).
+    // An empty set gives zeros.
+    pub fn evaluate(&self, inputs: &DMatrix<f32>, one_hot_targets: &DMatrix<f32>) -> EvalReport {
+        if inputs.nrows() == 0 {
+            return EvalReport::default();
+        }
+        let predictions = self.infer(inputs);
+        let correct_predictions = argmax_rows(&predictions).iter().zip(argmax_rows(one_hot_targets).iter())
+            .filter(|(predicted, actual)| predicted == actual)
+            .count();
+        EvalReport {
+            loss: self.loss_fn.calculate(&predictions, one_hot_targets),
+            accuracy: correct_predictions as f32 / inputs.nrows() as f32,
+        }
+    }
+
+    // Out-of-distribution score of a single flattened sample: 1 - the largest predicted probability.
+    // Near 0 for inputs the network is sure about, approaching 1 - 1/num_classes for a uniform output,
+    // so thresholding it is a cheap way to reject inputs unlike anything seen in training.
+    pub fn ood_score(&self, input: &[f32]) -> Result<f32, Box<dyn std::error::Error>> {
+        let expected_input_size = self.layers.first().map_or(0, |layer| layer.input_size());
+        if input.len() != expected_input_size {
+            return Err(format!("Invalid input length. Expected {}, got {}", expected_input_size, input.len()).into());
+        }
+        let output = self.infer(&DMatrix::from_row_slice(1, input.len(), input));
+        Ok(1.0 - output.max())
+    }
+
+    // Cheap uncertainty estimate for a single flattened sample: the network output for `samples` copies of it,
+    // each with every feature dropped (set to 0) with probability drop_prob and the kept ones scaled by
+    // 1 / (1 - drop_prob) like inverted dropout. Returns the variance of every output over those passes.
+    pub fn input_dropout_uncertainty(&self, input: &[f32], drop_prob: f32, samples: usize, seed: u64) -> DVector<f32> {
+        assert!((0.0..1.0).contains(&drop_prob), "drop_prob must be in [0, 1), got {}", drop_prob);
+        assert!(samples > 0, "input_dropout_uncertainty needs at least one sample");
+        let mut rng = StableRng::new(seed);
+        let keep_scale = 1.0 / (1.0 - drop_prob);
+        let masked = DMatrix::from_fn(samples, input.len(), |_, feature| {
+            if (rng.next_f64() as f32) < drop_prob { 0.0 } else { input[feature] * keep_scale }
+        });
+        let outputs = self.infer(&masked);
+        // Shifted by the first pass's output, so identical outputs give exactly 0
+        DVector::from_iterator(outputs.ncols(), outputs.column_iter().map(|column| {
+            let shifted = column.add_scalar(-column[0]);
+            (shifted.norm_squared() / samples as f32 - shifted.mean().powi(2)).max(0.0)
+        }))
+    }
+
+    // Test-time augmentation: the network output for every augmented copy of a single flattened sample
+    // (e.g. small shifts made with data::shift_image), averaged. Include the identity to keep the original.
+    pub fn predict_tta(&self, input: &[f32], augmentations: &[Augmentation]) -> Vec<f32> {
+        assert!(!augmentations.is_empty(), "predict_tta needs at least one augmentation");
+        let augmented: Vec<f32> = augmentations.iter().enumerate().flat_map(|(i, augment)| {
+            let augmented_input = augment(input);
+            assert_eq!(augmented_input.len(), input.len(), "Augmentation {} returned {} values for an input of {}", i, augmented_input.len(), input.len());
+            augmented_input
+        }).collect();
+        let batch = DMatrix::from_row_slice(augmentations.len(), input.len(), &augmented);
+        self.infer(&batch).row_mean().iter().copied().collect()
+    }
+
+    // Same as train_batch, but also returns ||W_after - W_before|| / ||W_before|| for every layer, the size of
+    // the update the optimizer actually applied relative to the weights (||lr * dW|| / ||W|| for plain SGD).
+    // A layer whose weights were all zero is measured against its weights after the update instead (0 if both are zero).
+    // A common rule of thumb is that this should be around 1e-3: much larger means the learning rate is too high.
+    pub fn train_batch_with_update_ratios(
+        &mut self,
+        inputs: &DMatrix<f32>,
+        targets: &DMatrix<f32>,
+        learning_rate: f32
+    ) -> (f32, Vec<f32>) {
+        let (loss, gradients) = self.compute_gradients(inputs, targets);
+        let weights_before = self.weight_matrices();
+        self.apply_gradients(&gradients, learning_rate);
+        let update_ratios = self.weight_matrices().iter().zip(weights_before.iter()).map(|(weights_after, weights_before)| {
+            let update_norm = (weights_after - weights_before).norm();
+            let weights_norm = match weights_before.norm() {
+                0.0 => weights_after.norm(),
+                norm => norm,
+            };
+            if weights_norm > 0.0 { update_norm / weights_norm } else { 0.0 }
+        }).collect();
+        (loss, update_ratios)
+    }
+
+    // Same as train_batch, but also returns a histogram per layer of the magnitudes |W_after - W_before| of the
+    // weight updates that were applied (|lr * dW| for plain SGD), binned between 0 and that layer's largest update
+    pub fn train_batch_with_update_histograms(
+        &mut self,
+        inputs: &DMatrix<f32>,
+        targets: &DMatrix<f32>,
+        learning_rate: f32,
+        num_bins: usize
+    ) -> (f32, Vec<Histogram>) {
+        let (loss, gradients) = self.compute_gradients(inputs, targets);
+        let weights_before = self.weight_matrices();
+        self.apply_gradients(&gradients, learning_rate);
+        let histograms = self.weight_matrices().iter().zip(weights_before.iter()).map(|(weights_after, weights_before)| {
+            let magnitudes = (weights_after - weights_before).abs();
+            Histogram::from_values(magnitudes.iter().copied(), num_bins, 0.0, magnitudes.max())
+        }).collect();
+        (loss, histograms)
+    }
+
+    // Same as train_batch, but also returns (mean |dW|, max |dW|) for every layer, for plotting gradient flow.
+    // Values that shrink layer by layer towards the input are the sign of vanishing gradients.
+    pub fn train_batch_with_gradient_flow(
+        &mut self,
+        inputs: &DMatrix<f32>,
+        targets: &DMatrix<f32>,
+        learning_rate: f32
+    ) -> (f32, Vec<(f32, f32)>) {
+        let (loss, gradients) = self.compute_gradients(inputs, targets);
+        let gradient_flow = gradients.iter().map(|layer_gradients| {
+            let magnitudes = layer_gradients.weights.abs();
+            (magnitudes.mean(), magnitudes.max())
+        }).collect();
+        self.apply_gradients(&gradients, learning_rate);
+        (loss, gradient_flow)
+    }
+
     pub fn save_weights(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        fs::write(path, self.to_bytes()?)?;
+        Ok(())
+    }
+
+    // The bincode model file content, in memory
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        Ok(bincode::serialize(&SerializableNeuralNetwork::from(self))?)
+    }
+
+    // Counterpart of to_bytes with the same rules as load_weights: the given loss function is used
+    // and bytes in the older layers-only format are accepted. E.g. for model bytes fetched in the browser.
+    pub fn from_bytes(bytes: &[u8], loss_fn: LossFunction) -> Result<Self, Box<dyn std::error::Error>> {
+        let serializable_nn = SerializableNeuralNetwork::from_bincode(bytes, Some(loss_fn))?;
+        serializable_nn.into_neural_network(loss_fn)
+    }
+
+    // Same content as save_weights, as human-readable JSON with every weight matrix as nested rows
+    pub fn save_json(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
         let serializable_nn = SerializableNeuralNetwork::from(self);
-        let file = File::create(path)?;
-        let writer = BufWriter::new(file);
-        serialize_into(writer, &serializable_nn)?;
+        let writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(writer, &serializable_nn)?;
         Ok(())
     }
 
+    pub fn load_json(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let serializable_nn = SerializableNeuralNetwork::from_json(&fs::read_to_string(path)?)?;
+        let loss_fn = serializable_nn.loss_fn();
+        serializable_nn.into_neural_network(loss_fn)
+    }
+
+    // Loads the weights but uses the given loss function instead of the saved one.
+    // Also reads models saved before the loss function was stored.
     pub fn load_weights(path: &str, loss_fn: LossFunction) -> Result<Self, Box<dyn std::error::Error>> {
-        let file = File::open(path)?;
-        let reader = BufReader::new(file);
-        let serializable_nn: SerializableNeuralNetwork = deserialize_from(reader)?;
-        Ok(serializable_nn.into_neural_network(loss_fn))
+        Self::from_bytes(&fs::read(path)?, loss_fn)
+    }
+
+    // Loads a model saved with save_weights, including its loss function and metadata
+    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let serializable_nn = SerializableNeuralNetwork::from_bincode(&fs::read(path)?, None)?;
+        let loss_fn = serializable_nn.loss_fn();
+        serializable_nn.into_neural_network(loss_fn)
+    }
+}
+
+// Where backpropagation through a chain of layers starts
+pub(crate) enum OutputGradient<T: Real> {
+    // dError/dA of the last layer's output
+    Output(DMatrix<T>),
+    // dError/dZ of the last layer, which has to be a DenseLayer. Skips its activation, e.g. for the
+    // Softmax + CrossEntropy shortcut or gradients of the logits.
+    PreActivation(DMatrix<T>),
+}
+
+impl<T: Real> OutputGradient<T> {
+    // Gradients of the last layer and dError/dA for its input
+    fn through_last_layer(self, last_layer: &dyn Layer<T>) -> (LayerGradients<T>, DMatrix<T>) {
+        match self {
+            OutputGradient::Output(d_error_da) => last_layer.compute_gradients(&d_error_da),
+            OutputGradient::PreActivation(d_error_dz) => dense_last_layer(last_layer).compute_gradients(&d_error_dz),
+        }
+    }
+
+    // Only dError/dA for the last layer's input
+    fn input_gradient_of_last_layer(self, last_layer: &dyn Layer<T>) -> DMatrix<T> {
+        match self {
+            OutputGradient::Output(d_error_da) => last_layer.input_gradient(&d_error_da),
+            OutputGradient::PreActivation(d_error_dz) => d_error_dz * dense_last_layer(last_layer).weights.transpose(),
+        }
+    }
+}
+
+fn dense_last_layer<T: Real>(last_layer: &dyn Layer<T>) -> &DenseLayer<T> {
+    last_layer.as_dense().unwrap_or_else(|| panic!("A gradient w.r.t. Z needs a dense last layer, got a {} layer", last_layer.layer_type()))
+}
+
+fn not_dense_error(i: usize, layer_type: &str) -> String {
+    format!("Layer {} is a {} layer, only dense layers are supported here", i, layer_type)
+}
+
+// Gradient of the loss where backpropagation through a chain of layers starts.
+// Must be called after a forward pass so the last layer's caches are filled.
+pub(crate) fn output_layer_gradient<T: Real>(
+    layers: &[Box<dyn Layer<T>>],
+    loss_fn: LossFunction,
+    predictions: &DMatrix<T>,
+    targets: &DMatrix<T>,
+) -> OutputGradient<T> {
+    let last_layer = layers.last().expect("Cannot compute output gradient for a network with no layers");
+
+    // Special case for Softmax + CrossEntropy: dLoss/dZ = Predictions - Targets
+    if last_layer.as_dense().is_some_and(|layer| layer.activation_fn == ActivationFunction::Softmax) &&
+       loss_fn == LossFunction::CrossEntropy {
+        let batch_size = T::cast(predictions.nrows() as f64);
+        OutputGradient::PreActivation((predictions - targets) / batch_size)
+    } else {
+        // General case: the last layer turns dError/dA_L into dError/dZ_L itself (the full Jacobian for Softmax with another loss)
+        OutputGradient::Output(loss_fn.derivative(predictions, targets))
+    }
+}
+
+// Backpropagates the output gradient through a chain of layers, updating each one.
+// Returns dError/dA for the input of the first layer, so the chain can be fed by another one.
+pub(crate) fn backward_through<T: Real>(
+    layers: &mut [Box<dyn Layer<T>>],
+    output_gradient: OutputGradient<T>,
+    learning_rate: T,
+) -> DMatrix<T> {
+    // Every gradient is computed from the pre-update weights, so computing them all
+    // before applying any gives the same result as updating layer by layer.
+    let (gradients, input_gradient) = gradients_through(layers, output_gradient);
+    for (layer, layer_gradients) in layers.iter_mut().zip(gradients.iter()) {
+        layer.apply_gradients(layer_gradients, learning_rate);
+    }
+    input_gradient
+}
+
+// Same as backward_through but leaves the weights untouched.
+// Returns the gradients of every layer (first to last) and dError/dA for the input of the first layer.
+pub(crate) fn gradients_through<T: Real>(
+    layers: &[Box<dyn Layer<T>>],
+    output_gradient: OutputGradient<T>,
+) -> (Vec<LayerGradients<T>>, DMatrix<T>) {
+    let last_layer_idx = layers.len() - 1;
+    let mut gradients = Vec::with_capacity(layers.len());
+    let (last_layer_gradients, mut gradient_from_next_layer_wrt_activation) =
+        output_gradient.through_last_layer(layers[last_layer_idx].as_ref());
+    gradients.push(last_layer_gradients);
+
+    // For hidden layers (from L-1 down to 0)
+    for i in (0..last_layer_idx).rev() {
+        // gradient_from_next_layer_wrt_activation is dError/dA_current
+        let (layer_gradients, gradient_to_pass_back) = layers[i].compute_gradients(&gradient_from_next_layer_wrt_activation);
+        gradients.push(layer_gradients);
+        gradient_from_next_layer_wrt_activation = gradient_to_pass_back;
+    }
+    gradients.reverse();
+    (gradients, gradient_from_next_layer_wrt_activation)
+}
+
+// gradients_through for layers whose outputs are contribution * f(input) + (1 - contribution) * input
+// (stochastic depth), contributions[i] belongs to layer i and the last layer's must be 1.
+// A skipped layer (contribution 0) gets zero gradients and passes the gradient through unchanged.
+fn gradients_through_scaled<T: Real>(
+    layers: &[Box<dyn Layer<T>>],
+    contributions: &[T],
+    output_gradient: OutputGradient<T>,
+) -> (Vec<LayerGradients<T>>, DMatrix<T>) {
+    let last_layer_idx = layers.len() - 1;
+    let mut gradients = Vec::with_capacity(layers.len());
+    let (last_layer_gradients, mut d_error_da) = output_gradient.through_last_layer(layers[last_layer_idx].as_ref());
+    gradients.push(last_layer_gradients);
+
+    for i in (0..last_layer_idx).rev() {
+        let layer = layers[i].as_ref();
+        let contribution = contributions[i];
+        if contribution == T::zero() {
+            gradients.push(LayerGradients::zeros_like(layer));
+            continue;
+        }
+        let (layer_gradients, gradient_through_layer) = layer.compute_gradients(&(&d_error_da * contribution));
+        gradients.push(layer_gradients);
+        if contribution != T::one() {
+            d_error_da = gradient_through_layer + d_error_da * (T::one() - contribution);
+        } else {
+            d_error_da = gradient_through_layer;
+        }
     }
+    gradients.reverse();
+    (gradients, d_error_da)
+}
 
+// contribution * layer_output + (1 - contribution) * layer_input, the layer_output itself for a contribution of 1
+fn mix_with_input<T: Real>(layer_output: DMatrix<T>, layer_input: &DMatrix<T>, contribution: T) -> DMatrix<T> {
+    if contribution == T::one() {
+        layer_output
+    } else {
+        layer_output * contribution + layer_input * (T::one() - contribution)
+    }
+}
 
-}
\ No newline at end of file
+// Only the dError/dA for the input of the first layer, skipping the parameter gradients
+pub(crate) fn input_gradient_through<T: Real>(layers: &[Box<dyn Layer<T>>], output_gradient: OutputGradient<T>) -> DMatrix<T> {
+    let (last_layer, hidden_layers) = layers.split_last().expect("Cannot backpropagate through no layers");
+    let mut d_error_da = output_gradient.input_gradient_of_last_layer(last_layer.as_ref());
+    for layer in hidden_layers.iter().rev() {
+        d_error_da = layer.input_gradient(&d_error_da);
+    }
+    d_error_da
+}
+
+// Parameters of a network at one point in time, see NeuralNetwork::snapshot
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetworkSnapshot<T: Real = f32> {
+    layers: Vec<(DMatrix<T>, DVector<T>)>,
+}
+
+// Result of NeuralNetwork::evaluate
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct EvalReport {
+    pub loss: f32,     // Average over the samples, like the training loss
+    pub accuracy: f32, // Fraction of samples whose argmax matches the target's
+}
+
+// Time spent in each phase of a training step
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PhaseTimings {
+    pub forward: Duration,
+    pub backward: Duration,
+    pub update: Duration,
+}
+
+impl PhaseTimings {
+    pub fn total(&self) -> Duration {
+        self.forward + self.backward + self.update
+    }
+}
+
+impl std::ops::AddAssign for PhaseTimings {
+    fn add_assign(&mut self, other: Self) {
+        self.forward += other.forward;
+        self.backward += other.backward;
+        self.update += other.update;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // ReLU hidden layers and a Softmax output, reproducible through new_seeded
+    fn seeded_mlp(sizes: &[usize], seed: u64) -> NeuralNetwork {
+        let mut network = NeuralNetwork::new_seeded(LossFunction::CrossEntropy, seed);
+        for (i, pair) in sizes.windows(2).enumerate() {
+            let activation_fn = if i + 2 == sizes.len() { ActivationFunction::Softmax } else { ActivationFunction::ReLU };
+            network.add_dense_layer(pair[0], pair[1], activation_fn);
+        }
+        network
+    }
+
+    // Sigmoid hidden layers, so there are no kinks for a gradient check to trip over
+    fn smooth_f64_mlp(sizes: &[usize], output_activation: ActivationFunction, loss_fn: LossFunction, seed: u64) -> NeuralNetwork<f64> {
+        let mut network = NeuralNetwork::<f64>::new_seeded(loss_fn, seed);
+        for (i, pair) in sizes.windows(2).enumerate() {
+            let activation_fn = if i + 2 == sizes.len() { output_activation } else { ActivationFunction::Sigmoid };
+            network.add_dense_layer(pair[0], pair[1], activation_fn);
+        }
+        network
+    }
+
+    fn sample_inputs(num_samples: usize, num_features: usize) -> DMatrix<f32> {
+        DMatrix::from_fn(num_samples, num_features, |r, c| ((r * num_features + c) as f32 * 0.37).sin())
+    }
+
+    #[test]
+    fn predicted_class_is_the_most_likely_one() {
+        let network = seeded_mlp(&[5, 8, 4], 1);
+        let inputs = sample_inputs(10, 5);
+        let probabilities = network.infer(&inputs);
+        let classes = network.predict_classes_batch(&inputs);
+        for (r, &class) in classes.iter().enumerate() {
+            let row = probabilities.row(r);
+            assert!(row.iter().all(|&probability| probability <= row[class]));
+            let sample: Vec<f32> = inputs.row(r).iter().copied().collect();
+            assert_eq!(network.predict_class(&sample).unwrap(), class);
+        }
+        assert!(network.predict_class(&[0.0; 3]).is_err());
+    }
+
+    #[test]
+    fn gradient_checkpointing_gives_identical_weights() {
+        let inputs = sample_inputs(8, 4);
+        let targets = DMatrix::from_fn(8, 3, |r, c| if r % 3 == c { 1.0 } else { 0.0 });
+        let mut plain = seeded_mlp(&[4, 6, 6, 5, 3], 2);
+        let mut checkpointed = seeded_mlp(&[4, 6, 6, 5, 3], 2);
+        checkpointed.set_gradient_checkpointing(Some(2));
+        for _ in 0..5 {
+            assert_eq!(checkpointed.train_batch(&inputs, &targets, 0.1), plain.train_batch(&inputs, &targets, 0.1));
+        }
+        assert_eq!(checkpointed.snapshot(), plain.snapshot());
+    }
+
+    #[test]
+    fn update_ratios_are_positive_and_linear_in_the_learning_rate() {
+        let inputs = sample_inputs(8, 4);
+        let targets = DMatrix::from_fn(8, 3, |r, c| if r % 3 == c { 1.0 } else { 0.0 });
+        let (_, ratios) = seeded_mlp(&[4, 5, 3], 3).train_batch_with_update_ratios(&inputs, &targets, 0.01);
+        let (_, doubled_ratios) = seeded_mlp(&[4, 5, 3], 3).train_batch_with_update_ratios(&inputs, &targets, 0.02);
+        for (ratio, doubled_ratio) in ratios.iter().zip(doubled_ratios.iter()) {
+            assert!(*ratio > 0.0);
+            assert!((doubled_ratio / ratio - 2.0).abs() < 1e-3, "{} vs {}", ratio, doubled_ratio);
+        }
+    }
+
+    #[test]
+    fn update_histograms_count_every_weight() {
+        let inputs = sample_inputs(8, 4);
+        let targets = DMatrix::from_fn(8, 3, |r, c| if r % 3 == c { 1.0 } else { 0.0 });
+        let mut network = seeded_mlp(&[4, 5, 3], 4);
+        let (_, histograms) = network.train_batch_with_update_histograms(&inputs, &targets, 0.1, 10);
+        assert_eq!(histograms.len(), 2);
+        for (histogram, weights) in histograms.iter().zip(network.weight_matrices()) {
+            assert_eq!(histogram.counts.len(), 10);
+            assert_eq!(histogram.counts.iter().sum::<usize>(), weights.len());
+        }
+    }
+
+    #[test]
+    fn softmax_with_mse_passes_a_gradient_check() {
+        let mut network = smooth_f64_mlp(&[3, 4, 3], ActivationFunction::Softmax, LossFunction::MeanSquaredError, 5);
+        let inputs = sample_inputs(4, 3).map(f64::from);
+        let targets = DMatrix::from_fn(4, 3, |r, c| if r % 3 == c { 1.0 } else { 0.0 });
+        let relative_error = network.check_gradients(&inputs, &targets, 1e-6);
+        assert!(relative_error < 1e-4, "Max relative error {}", relative_error);
+    }
+
+    #[test]
+    fn strong_weight_decay_shrinks_the_weights() {
+        let inputs = sample_inputs(8, 4);
+        let targets = DMatrix::from_fn(8, 3, |r, c| if r % 3 == c { 1.0 } else { 0.0 });
+        let total_norm = |network: &NeuralNetwork| network.weight_matrices().iter().map(|weights| weights.norm()).sum::<f32>();
+        let mut plain = seeded_mlp(&[4, 5, 3], 6);
+        let mut decayed = seeded_mlp(&[4, 5, 3], 6);
+        let mut no_decay = seeded_mlp(&[4, 5, 3], 6);
+        decayed.set_weight_decay(1.0);
+        no_decay.set_weight_decay(0.0);
+
+        let norm_before = total_norm(&decayed);
+        for _ in 0..10 {
+            plain.train_batch(&inputs, &targets, 0.1);
+            decayed.train_batch(&inputs, &targets, 0.1);
+            no_decay.train_batch(&inputs, &targets, 0.1);
+        }
+        assert!(total_norm(&decayed) < 0.6 * norm_before, "Norm went from {} to {}", norm_before, total_norm(&decayed));
+        assert_eq!(no_decay.snapshot(), plain.snapshot());
+    }
+
+    #[test]
+    fn an_f64_network_trains_one_batch() {
+        let mut network = smooth_f64_mlp(&[3, 4, 2], ActivationFunction::Softmax, LossFunction::CrossEntropy, 7);
+        let inputs = sample_inputs(6, 3).map(f64::from);
+        let targets = DMatrix::from_fn(6, 2, |r, c| if r % 2 == c { 1.0 } else { 0.0 });
+
+        let loss_of = |network: &NeuralNetwork<f64>| LossFunction::CrossEntropy.calculate(&network.infer(&inputs), &targets);
+        let loss_before = loss_of(&network);
+        let batch_loss = network.train_batch(&inputs, &targets, 0.5);
+        assert!(batch_loss.is_finite() && (batch_loss - loss_before).abs() < 1e-12);
+        assert!(loss_of(&network) < loss_before);
+        assert!(network.infer(&inputs).row_iter().all(|row| (row.sum() - 1.0).abs() < 1e-12));
+    }
+
+    #[test]
+    fn profiling_gives_one_positive_duration_per_layer() {
+        let network = seeded_mlp(&[16, 32, 32, 4], 8);
+        let durations = network.profile_layers(&sample_inputs(32, 16), 5);
+        assert_eq!(durations.len(), 3);
+        assert!(durations.iter().all(|duration| !duration.is_zero()));
+    }
+
+    #[test]
+    fn mlp_has_the_given_layer_shapes_and_activations() {
+        let network = NeuralNetwork::<f32>::mlp(&[784, 128, 64, 10], ActivationFunction::ReLU, ActivationFunction::Softmax, LossFunction::CrossEntropy);
+        let layers: Vec<_> = (0..3).map(|i| network.dense_layer(i).unwrap()).collect();
+        let shapes: Vec<_> = layers.iter().map(|layer| (layer.input_size(), layer.output_size())).collect();
+        assert_eq!(shapes, vec![(784, 128), (128, 64), (64, 10)]);
+        let activations: Vec<_> = layers.iter().map(|layer| layer.activation_fn).collect();
+        assert_eq!(activations, vec![ActivationFunction::ReLU, ActivationFunction::ReLU, ActivationFunction::Softmax]);
+        assert_eq!(network.get_layers().len(), 3);
+        assert_eq!(network.get_loss_fn(), LossFunction::CrossEntropy);
+    }
+
+    #[test]
+    fn verify_architecture_names_the_mismatched_layer() {
+        let network = seeded_mlp(&[4, 6, 3], 9);
+        assert!(network.verify_architecture(&[(4, 6), (6, 3)]).is_ok());
+        let error = network.verify_architecture(&[(4, 6), (6, 2)]).unwrap_err().to_string();
+        assert!(error.contains("Layer 1"), "{}", error);
+        assert!(network.verify_architecture(&[(4, 6)]).is_err());
+    }
+
+    #[test]
+    fn ood_score_is_low_when_confident_and_high_when_uniform() {
+        // Class 0 gets more certain as the single input grows, a zero input gives a uniform output
+        let mut network = NeuralNetwork::new(LossFunction::CrossEntropy);
+        network.add_layer(DenseLayer::from_parameters(DMatrix::from_row_slice(1, 3, &[5.0, 0.0, 0.0]), DVector::zeros(3), ActivationFunction::Softmax));
+
+        assert!(network.ood_score(&[2.0]).unwrap() < 1e-3);
+        assert!((network.ood_score(&[0.0]).unwrap() - 2.0 / 3.0).abs() < 1e-6);
+        assert!(network.ood_score(&[0.0, 1.0]).is_err());
+    }
+
+    #[test]
+    fn identity_tta_is_a_single_prediction() {
+        let network = seeded_mlp(&[4, 6, 3], 10);
+        let input = [0.3, -0.2, 0.8, 0.1];
+        let identity = |input: &[f32]| input.to_vec();
+        let single: Vec<f32> = network.infer(&DMatrix::from_row_slice(1, 4, &input)).iter().copied().collect();
+
+        for copies in 1..=3 {
+            let augmentations: Vec<Augmentation> = vec![&identity; copies];
+            let averaged = network.predict_tta(&input, &augmentations);
+            assert!(averaged.iter().zip(single.iter()).all(|(a, b)| (a - b).abs() < 1e-6), "{:?} vs {:?}", averaged, single);
+        }
+    }
+
+    #[test]
+    fn evaluate_on_a_known_two_class_set() {
+        // Class 0 for positive inputs, so the last two of the four samples are misclassified
+        let mut network = NeuralNetwork::new(LossFunction::CrossEntropy);
+        network.add_layer(DenseLayer::from_parameters(DMatrix::from_row_slice(1, 2, &[1.0, -1.0]), DVector::zeros(2), ActivationFunction::Softmax));
+        let inputs = DMatrix::from_column_slice(4, 1, &[1.0, -1.0, 1.0, -1.0]);
+        let targets = DMatrix::from_row_slice(4, 2, &[1.0, 0.0, 0.0, 1.0, 0.0, 1.0, 1.0, 0.0]);
+
+        let report = network.evaluate(&inputs, &targets);
+        assert_eq!(report.accuracy, 0.5);
+        // -ln(sigmoid(2)) for the right ones, -ln(sigmoid(-2)) for the wrong ones
+        let expected_loss = (2.0 * (1.0 + (-2.0f32).exp()).ln() + 2.0 * (1.0 + 2.0f32.exp()).ln()) / 4.0;
+        assert!((report.loss - expected_loss).abs() < 1e-5, "Loss {} instead of {}", report.loss, expected_loss);
+        assert_eq!(network.evaluate(&DMatrix::zeros(0, 1), &DMatrix::zeros(0, 2)), EvalReport::default());
+    }
+
+    #[test]
+    fn bytes_round_trip_gives_identical_predictions() {
+        let network = seeded_mlp(&[5, 7, 3], 11);
+        let loaded = NeuralNetwork::from_bytes(&network.to_bytes().unwrap(), LossFunction::CrossEntropy).unwrap();
+        let inputs = sample_inputs(6, 5);
+        assert_eq!(loaded.infer(&inputs), network.infer(&inputs));
+        assert!(NeuralNetwork::from_bytes(&[1, 2, 3], LossFunction::CrossEntropy).is_err());
+    }
+
+    #[test]
+    fn gradients_vanish_towards_the_input_of_a_deep_sigmoid_network() {
+        let mut network = NeuralNetwork::new_seeded(LossFunction::MeanSquaredError, 12);
+        for _ in 0..6 {
+            network.add_dense_layer(8, 8, ActivationFunction::Sigmoid);
+        }
+        let targets = DMatrix::from_fn(16, 8, |r, c| ((r + c) % 2) as f32);
+
+        let (_, flow) = network.train_batch_with_gradient_flow(&sample_inputs(16, 8), &targets, 0.1);
+        assert_eq!(flow.len(), 6);
+        assert!(flow.iter().all(|&(mean, max)| 0.0 < mean && mean <= max));
+        let (first_mean, last_mean) = (flow[0].0, flow[5].0);
+        assert!(first_mean * 10.0 < last_mean, "Mean |dW| of {} at the input vs {} at the output", first_mean, last_mean);
+    }
+
+    #[test]
+    fn top_k_lists_the_most_likely_classes_first() {
+        // An identity layer, so the outputs are the inputs
+        let mut network = NeuralNetwork::new(LossFunction::CrossEntropy);
+        network.add_layer(DenseLayer::from_parameters(DMatrix::identity(4, 4), DVector::zeros(4), ActivationFunction::Linear));
+        let outputs = DMatrix::from_row_slice(2, 4, &[0.1, 0.6, 0.05, 0.25, 0.4, 0.1, 0.3, 0.2]);
+
+        let top_2 = network.predict_top_k(&outputs, 2);
+        assert_eq!(top_2, vec![vec![(1, 0.6), (3, 0.25)], vec![(0, 0.4), (2, 0.3)]]);
+        assert_eq!(network.predict_top_k(&outputs, 10)[0].len(), 4);
+    }
+
+    #[test]
+    fn low_survival_probabilities_skip_layers_only_in_training_mode() {
+        let inputs = sample_inputs(4, 4);
+        let mut network = seeded_mlp(&[4, 4, 4, 4, 3], 8);
+        network.set_stochastic_depth(Some(vec![1.0, 1.0, 1.0, 1.0]), 9);
+        for _ in 0..20 {
+            network.predict(&inputs);
+            assert_eq!(network.skipped_layers(), vec![false; 4]);
+        }
+
+        network.set_stochastic_depth(Some(vec![0.5, 0.5, 0.5, 1.0]), 9);
+        let mut times_skipped = [0; 4];
+        for _ in 0..50 {
+            network.predict(&inputs);
+            for (count, skipped) in times_skipped.iter_mut().zip(network.skipped_layers()) {
+                *count += skipped as usize;
+            }
+        }
+        assert!(times_skipped[..3].iter().all(|&count| count > 5 && count < 45), "Skip counts {:?}", times_skipped);
+        assert_eq!(times_skipped[3], 0, "The last layer was skipped");
+
+        network.set_training(false);
+        let eval_output = network.predict(&inputs);
+        assert_eq!(network.predict(&inputs), eval_output, "Eval mode isn't deterministic");
+    }
+
+    #[test]
+    fn a_single_layer_perceptron_learns_or() {
+        let inputs = DMatrix::from_row_slice(4, 2, &[0.0, 0.0, 0.0, 1.0, 1.0, 0.0, 1.0, 1.0]);
+        let targets = DMatrix::from_row_slice(4, 1, &[0.0, 1.0, 1.0, 1.0]);
+        let mut network = NeuralNetwork::new_seeded(LossFunction::MeanSquaredError, 10);
+        network.add_dense_layer(2, 1, ActivationFunction::Sigmoid);
+        for _ in 0..2000 {
+            network.train_batch(&inputs, &targets, 2.0);
+        }
+        let outputs = network.infer(&inputs);
+        for (r, &target) in targets.iter().enumerate() {
+            assert_eq!(outputs[(r, 0)] > 0.5, target > 0.5, "Wrong OR of {:?}: {}", inputs.row(r), outputs[(r, 0)]);
+        }
+    }
+
+    #[test]
+    fn diff_from_measures_how_far_each_layer_moved() {
+        let mut network = seeded_mlp(&[3, 4, 2], 11);
+        let snapshot = network.snapshot();
+        assert_eq!(network.diff_from(&snapshot), vec![0.0, 0.0]);
+
+        // A 0.3 and a 0.4 step in the second layer only, 0.5 in total
+        let layer = network.dense_layer_mut(1).unwrap();
+        layer.weights[(2, 1)] += 0.3;
+        layer.biases[0] -= 0.4;
+        let diffs = network.diff_from(&snapshot);
+        assert_eq!(diffs[0], 0.0);
+        assert!((diffs[1] - 0.5).abs() < 1e-6, "Second layer moved {}", diffs[1]);
+
+        network.train_batch(&sample_inputs(4, 3), &DMatrix::from_fn(4, 2, |r, c| if r % 2 == c { 1.0 } else { 0.0 }), 0.1);
+        assert!(network.diff_from(&snapshot)[0] > 0.0, "Training didn't move the first layer");
+    }
+
+    #[test]
+    fn f64_gradients_match_finite_differences() {
+        let inputs = sample_inputs(5, 3).map(f64::from);
+        let mut regressor = smooth_f64_mlp(&[3, 5, 4, 2], ActivationFunction::Linear, LossFunction::MeanSquaredError, 12);
+        let regression_targets = DMatrix::from_fn(5, 2, |r, c| (r as f64 - c as f64) * 0.25);
+        let relative_error = regressor.check_gradients(&inputs, &regression_targets, 1e-6);
+        assert!(relative_error < 1e-4, "Max relative error {} with MSE", relative_error);
+
+        let mut classifier = smooth_f64_mlp(&[3, 5, 3], ActivationFunction::Softmax, LossFunction::CrossEntropy, 13);
+        let class_targets = DMatrix::from_fn(5, 3, |r, c| if r % 3 == c { 1.0 } else { 0.0 });
+        let relative_error = classifier.check_gradients(&inputs, &class_targets, 1e-6);
+        assert!(relative_error < 1e-4, "Max relative error {} with cross entropy", relative_error);
+    }
+
+    #[test]
+    fn the_same_seed_gives_identical_networks() {
+        let inputs = sample_inputs(6, 4);
+        let targets = DMatrix::from_fn(6, 3, |r, c| if r % 3 == c { 1.0 } else { 0.0 });
+        let mut first = seeded_mlp(&[4, 7, 5, 3], 14);
+        let mut second = seeded_mlp(&[4, 7, 5, 3], 14);
+        assert_eq!(first.snapshot(), second.snapshot());
+        assert_ne!(first.snapshot(), seeded_mlp(&[4, 7, 5, 3], 15).snapshot(), "A different seed gave the same weights");
+        // Layers within one network get different weights too
+        assert_ne!(first.dense_layer(1).unwrap().weights.as_slice()[..5], first.dense_layer(2).unwrap().weights.as_slice()[..5]);
+
+        for _ in 0..3 {
+            assert_eq!(first.train_batch(&inputs, &targets, 0.1), second.train_batch(&inputs, &targets, 0.1));
+        }
+        assert_eq!(first.snapshot(), second.snapshot());
+    }
+
+    #[test]
+    fn input_dropout_variance_matches_a_linear_model() {
+        // y = x with x = 1: every pass gives 0 (dropped) or 2 (kept and scaled), a variance of 4 * 0.25 = 1
+        let mut network = NeuralNetwork::new(LossFunction::MeanSquaredError);
+        network.add_layer(DenseLayer::from_parameters(DMatrix::from_element(1, 1, 1.0), DVector::zeros(1), ActivationFunction::Linear));
+        let variance = network.input_dropout_uncertainty(&[1.0], 0.5, 4000, 16)[0];
+        assert!((variance - 1.0).abs() < 0.05, "Variance {}", variance);
+
+        assert_eq!(network.input_dropout_uncertainty(&[1.0], 0.0, 100, 16)[0], 0.0);
+        assert_eq!(network.input_dropout_uncertainty(&[1.0], 0.5, 100, 16), network.input_dropout_uncertainty(&[1.0], 0.5, 100, 16));
+    }
+
+    #[test]
+    fn confusion_matrix_counts_a_known_pattern() {
+        // Predicts the class of the one-hot input feature
+        let mut network = NeuralNetwork::new(LossFunction::CrossEntropy);
+        network.add_layer(DenseLayer::from_parameters(DMatrix::identity(3, 3) * 5.0, DVector::zeros(3), ActivationFunction::Softmax));
+        let predicted = [0, 0, 1, 2, 2, 2];
+        let actual = [0.0, 1.0, 1.0, 2.0, 2.0, 0.0];
+        let inputs = DMatrix::from_fn(6, 3, |r, c| if predicted[r] == c { 1.0 } else { 0.0 });
+        let labels = DMatrix::from_column_slice(6, 1, &actual);
+
+        let counts = network.confusion_matrix(&inputs, &labels, 3);
+        assert_eq!(counts, DMatrix::from_row_slice(3, 3, &[1, 0, 1, 1, 1, 0, 0, 0, 2]));
+        assert_eq!(counts.sum(), 6);
+        assert_eq!(counts.diagonal().sum(), 4, "Four of the six predictions are right");
+    }
+}
@@ -0,0 +1,211 @@
+// NumPy .npy files, for moving weights to and from Python analysis scripts.
+//
+// Layout (format version 1.0):
+//   magic        6 bytes  "\x93NUMPY"
+//   version      2 bytes  1, 0
+//   header_len   u16 little-endian
+//   header       Python dict literal, e.g. {'descr': '<f4', 'fortran_order': False, 'shape': (784, 128), }
+//                padded with spaces and a final '\n' so the data starts at a multiple of 64 bytes
+//   data         little-endian f32 in row-major (C) order
+// Reading also accepts versions 2.0 and 3.0 (u32 header_len) and Fortran (column-major) order, but only '<f4' data.
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Cursor, Error, ErrorKind, Read, Write};
+use std::path::Path;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use nalgebra::{DMatrix, DVector};
+use crate::layer::DenseLayer;
+use crate::network::NeuralNetwork;
+
+const NPY_MAGIC: &[u8; 6] = b"\x93NUMPY";
+const NPY_HEADER_ALIGNMENT: usize = 64;
+
+// Writes an f32 array of the given shape, `values` in row-major order
+pub fn write_npy(path: &Path, shape: &[usize], values: impl IntoIterator<Item = f32>) -> Result<(), Box<dyn std::error::Error>> {
+    // A 1-D shape needs the trailing comma to be a Python tuple
+    let shape_string = match shape {
+        [length] => format!("({},)", length),
+        _ => format!("({})", shape.iter().map(|dim| dim.to_string()).collect::<Vec<_>>().join(", ")),
+    };
+    let mut header = format!("{{'descr': '<f4', 'fortran_order': False, 'shape': {}, }}", shape_string);
+    // magic + version + header_len + header + '\n'
+    let unpadded_len = NPY_MAGIC.len() + 2 + 2 + header.len() + 1;
+    header.push_str(&" ".repeat(unpadded_len.next_multiple_of(NPY_HEADER_ALIGNMENT) - unpadded_len));
+    header.push('\n');
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(NPY_MAGIC)?;
+    writer.write_all(&[1, 0])?;
+    writer.write_u16::<LittleEndian>(header.len() as u16)?;
+    writer.write_all(header.as_bytes())?;
+    for value in values {
+        writer.write_f32::<LittleEndian>(value)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// Reads an f32 array, returns its shape and the values in row-major order
+pub fn read_npy(path: &Path) -> Result<(Vec<usize>, Vec<f32>), Box<dyn std::error::Error>> {
+    let bytes = fs::read(path)?;
+    let mut cursor = Cursor::new(bytes.as_slice());
+
+    let mut magic = [0u8; 6];
+    cursor.read_exact(&mut magic)?;
+    if &magic != NPY_MAGIC {
+        return Err(Error::new(ErrorKind::InvalidData, format!("{} is not a .npy file (bad magic)", path.display())).into());
+    }
+    let major_version = cursor.read_u8()?;
+    let _minor_version = cursor.read_u8()?;
+    let header_len = match major_version {
+        1 => cursor.read_u16::<LittleEndian>()? as usize,
+        2 | 3 => cursor.read_u32::<LittleEndian>()? as usize,
+        _ => return Err(Error::new(ErrorKind::InvalidData, format!("Unsupported .npy version {}", major_version)).into()),
+    };
+    // Sizes from the file are checked against what's left of it before allocating anything
+    if header_len > remaining(&cursor) {
+        return Err(Error::new(ErrorKind::InvalidData, format!("{} is truncated, its header needs {} bytes", path.display(), header_len)).into());
+    }
+    let mut header = vec![0u8; header_len];
+    cursor.read_exact(&mut header)?;
+    let header = String::from_utf8_lossy(&header);
+
+    let descr = header_value(&header, "descr")?;
+    if descr.trim_matches(|c| c == '\'' || c == '"') != "<f4" {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Expected little-endian f32 data ('<f4'), got dtype {}", descr)).into());
+    }
+    let fortran_order = header_value(&header, "fortran_order")? == "True";
+    let shape = parse_shape(header_value(&header, "shape")?)?;
+
+    let num_values = shape.iter().try_fold(1usize, |product, &dim| product.checked_mul(dim));
+    let num_values = match num_values.filter(|num_values| num_values.checked_mul(4) == Some(remaining(&cursor))) {
+        Some(num_values) => num_values,
+        None => return Err(Error::new(ErrorKind::InvalidData, format!(
+            "{} holds {} bytes of data, which doesn't match its shape {:?}", path.display(), remaining(&cursor), shape
+        )).into()),
+    };
+    let mut values = vec![0.0; num_values];
+    cursor.read_f32_into::<LittleEndian>(&mut values)?;
+    if fortran_order && shape.len() == 2 {
+        values = DMatrix::from_vec(shape[0], shape[1], values).transpose().as_slice().to_vec();
+    } else if fortran_order && shape.len() > 2 {
+        return Err(Error::new(ErrorKind::InvalidData, "Fortran-ordered arrays with more than 2 dimensions aren't supported").into());
+    }
+    Ok((shape, values))
+}
+
+fn remaining(cursor: &Cursor<&[u8]>) -> usize {
+    cursor.get_ref().len().saturating_sub(cursor.position() as usize)
+}
+
+// The raw text of `key`'s value in the header dict, e.g. "(784, 128)" for 'shape'
+fn header_value<'a>(header: &'a str, key: &str) -> Result<&'a str, Error> {
+    let missing = || Error::new(ErrorKind::InvalidData, format!(".npy header has no '{}'", key));
+    let start = header.find(&format!("'{}':", key)).ok_or_else(missing)? + key.len() + 3;
+    let rest = header[start..].trim_start();
+    // A tuple runs to its closing parenthesis, anything else to the next comma
+    let end = if rest.starts_with('(') { rest.find(')').map(|i| i + 1) } else { rest.find([',', '}']) };
+    Ok(rest[..end.ok_or_else(missing)?].trim())
+}
+
+fn parse_shape(shape: &str) -> Result<Vec<usize>, Error> {
+    shape.trim_matches(|c| c == '(' || c == ')')
+        .split(',')
+        .map(str::trim)
+        .filter(|dim| !dim.is_empty())
+        .map(|dim| dim.parse().map_err(|_| Error::new(ErrorKind::InvalidData, format!("Invalid .npy shape {}", shape))))
+        .collect()
+}
+
+impl DenseLayer {
+    // Replaces the weights and biases with arrays saved from NumPy (e.g. by export_npy or np.save on
+    // float32 arrays), weights shaped (input_size, output_size) and biases (output_size,).
+    // Fails without touching the layer if a file has another dtype or shape.
+    pub fn load_npy(&mut self, weights_path: &Path, biases_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let (weights_shape, weights_values) = read_npy(weights_path)?;
+        if weights_shape != [self.input_size(), self.output_size()] {
+            return Err(format!("{} has shape {:?}, the layer's weights are [{}, {}]",
+                weights_path.display(), weights_shape, self.input_size(), self.output_size()).into());
+        }
+        let (biases_shape, biases_values) = read_npy(biases_path)?;
+        if biases_shape != [self.output_size()] {
+            return Err(format!("{} has shape {:?}, the layer's biases are [{}]",
+                biases_path.display(), biases_shape, self.output_size()).into());
+        }
+        self.weights = DMatrix::from_row_slice(self.input_size(), self.output_size(), &weights_values);
+        self.biases = DVector::from_vec(biases_values);
+        Ok(())
+    }
+}
+
+impl NeuralNetwork {
+    // Writes layer{i}_weights.npy (input_size x output_size) and layer{i}_biases.npy (output_size)
+    // for every layer into dir, creating it if needed. np.load gives the same shapes as DenseLayer.
+    // Fails for networks with layers other than DenseLayers.
+    pub fn export_npy(&self, dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        fs::create_dir_all(dir)?;
+        for (i, layer) in self.dense_layers()?.into_iter().enumerate() {
+            // nalgebra stores columns contiguously, so go through the rows for C order
+            let weights_row_major = layer.weights.row_iter().flat_map(|row| row.iter().copied().collect::<Vec<_>>());
+            write_npy(&dir.join(format!("layer{}_weights.npy", i)), &[layer.input_size(), layer.output_size()], weights_row_major)?;
+            write_npy(&dir.join(format!("layer{}_biases.npy", i)), &[layer.output_size()], layer.biases.iter().copied())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activation::ActivationFunction;
+    use crate::loss::LossFunction;
+
+    fn scratch_dir(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("genius-hour-npy-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn exported_header_has_the_shape_and_dtype() {
+        let mut network = NeuralNetwork::new_seeded(LossFunction::MeanSquaredError, 14);
+        network.add_dense_layer(3, 2, ActivationFunction::Linear);
+        let dir = scratch_dir("export");
+        network.export_npy(&dir).unwrap();
+        let bytes = fs::read(dir.join("layer0_weights.npy")).unwrap();
+        let biases_header = String::from_utf8_lossy(&fs::read(dir.join("layer0_biases.npy")).unwrap()[10..]).into_owned();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(&bytes[..8], b"\x93NUMPY\x01\x00");
+        let header_len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+        assert_eq!((10 + header_len) % NPY_HEADER_ALIGNMENT, 0);
+        let header = std::str::from_utf8(&bytes[10..10 + header_len]).unwrap();
+        assert!(header.starts_with("{'descr': '<f4', 'fortran_order': False, 'shape': (3, 2), }"), "{}", header);
+        assert!(biases_header.contains("'shape': (2,)"));
+
+        // Row-major data: the second value is the weight from input 0 to output 1
+        let data = &bytes[10 + header_len..];
+        assert_eq!(data.len(), 6 * 4);
+        let weights = &network.dense_layer(0).unwrap().weights;
+        assert_eq!(f32::from_le_bytes(data[4..8].try_into().unwrap()), weights[(0, 1)]);
+    }
+
+    #[test]
+    fn export_then_import_gives_identical_weights() {
+        let mut network = NeuralNetwork::new_seeded(LossFunction::MeanSquaredError, 17);
+        network.add_dense_layer(4, 3, ActivationFunction::ReLU);
+        network.dense_layer_mut(0).unwrap().biases = DVector::from_vec(vec![0.5, -0.25, 1.0]);
+        let dir = scratch_dir("import");
+        network.export_npy(&dir).unwrap();
+
+        let mut imported = DenseLayer::from_parameters(DMatrix::zeros(4, 3), DVector::zeros(3), ActivationFunction::ReLU);
+        let loaded = imported.load_npy(&dir.join("layer0_weights.npy"), &dir.join("layer0_biases.npy"));
+        let mut wrong_shape = DenseLayer::from_parameters(DMatrix::zeros(3, 4), DVector::zeros(4), ActivationFunction::ReLU);
+        let mismatched = wrong_shape.load_npy(&dir.join("layer0_weights.npy"), &dir.join("layer0_biases.npy"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        loaded.unwrap();
+        let original = network.dense_layer(0).unwrap();
+        assert_eq!((&imported.weights, &imported.biases), (&original.weights, &original.biases));
+        assert!(mismatched.is_err());
+        assert_eq!(wrong_shape.weights, DMatrix::zeros(3, 4));
+    }
+}
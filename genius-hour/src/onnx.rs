@@ -0,0 +1,267 @@
+// ONNX export, to run trained models in other runtimes (onnxruntime, ONNX.js, ...).
+//
+// Every DenseLayer becomes a Gemm node (Y = X * W + B, W shaped (input_size, output_size)) followed by
+// the node of its activation, Linear layers have none. Weights and biases are float initializers, the
+// graph input is "input" and the output "output", both shaped [batch, features] with a symbolic batch.
+//
+// There is no protobuf dependency: the few ONNX messages needed are written by hand in the protobuf
+// wire format. Field numbers are the ones in onnx.proto.
+
+use std::fs;
+use crate::activation::ActivationFunction;
+use crate::network::NeuralNetwork;
+
+const ONNX_IR_VERSION: u64 = 7;
+const ONNX_OPSET_VERSION: u64 = 13;
+const ONNX_FLOAT: u64 = 1; // TensorProto.DataType.FLOAT
+const ONNX_ATTRIBUTE_FLOAT: u64 = 1; // AttributeProto.AttributeType.FLOAT
+const ONNX_ATTRIBUTE_INT: u64 = 2; // AttributeProto.AttributeType.INT
+
+enum Attribute {
+    Int(i64),
+    Float(f32),
+}
+
+// ONNX op applied after a layer's Gemm, None when the activation is the identity
+fn activation_op(activation_fn: ActivationFunction) -> Result<Option<&'static str>, String> {
+    match activation_fn {
+        ActivationFunction::Linear => Ok(None),
+        ActivationFunction::Sigmoid => Ok(Some("Sigmoid")),
+        ActivationFunction::ReLU => Ok(Some("Relu")),
+        ActivationFunction::Softmax => Ok(Some("Softmax")),
+        ActivationFunction::ELU { .. } => Ok(Some("Elu")),
+    }
+}
+
+// Writes the network as an ONNX model file
+pub fn export(network: &NeuralNetwork, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    fs::write(path, to_onnx_bytes(network)?)?;
+    Ok(())
+}
+
+// The serialized ModelProto
+pub fn to_onnx_bytes(network: &NeuralNetwork) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let layers = network.dense_layers()?;
+    let (first_layer, last_layer) = match (layers.first(), layers.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return Err("Cannot export a network with no layers to ONNX".into()),
+    };
+
+    let mut graph = Vec::new();
+    let mut current_tensor = "input".to_string();
+    for (i, layer) in layers.iter().enumerate() {
+        let (weights_name, biases_name) = (format!("layer{}_weights", i), format!("layer{}_biases", i));
+        // Row-major, the order ONNX expects for raw_data
+        let weights_row_major = layer.weights.transpose();
+        write_message(&mut graph, 5, &tensor(&weights_name, &[layer.input_size(), layer.output_size()], weights_row_major.as_slice()));
+        write_message(&mut graph, 5, &tensor(&biases_name, &[layer.output_size()], layer.biases.as_slice()));
+
+        let op = activation_op(layer.activation_fn).map_err(|e| format!("Layer {}: {}", i, e))?;
+        let is_last = i == layers.len() - 1;
+        let gemm_output = if is_last && op.is_none() { "output".to_string() } else { format!("layer{}_z", i) };
+        write_message(&mut graph, 1, &node(&format!("layer{}_gemm", i), "Gemm", &[&current_tensor, &weights_name, &biases_name], &gemm_output, None));
+        current_tensor = gemm_output;
+
+        if let Some(op) = op {
+            let activation_output = if is_last { "output".to_string() } else { format!("layer{}_a", i) };
+            let attribute = match layer.activation_fn {
+                // Softmax over the features of every sample
+                ActivationFunction::Softmax => Some(("axis", Attribute::Int(1))),
+                ActivationFunction::ELU { alpha } => Some(("alpha", Attribute::Float(alpha))),
+                _ => None,
+            };
+            write_message(&mut graph, 1, &node(&format!("layer{}_{}", i, op.to_lowercase()), op, &[&current_tensor], &activation_output, attribute));
+            current_tensor = activation_output;
+        }
+    }
+    write_string(&mut graph, 2, "genius_hour");
+    write_message(&mut graph, 11, &value_info("input", first_layer.input_size()));
+    write_message(&mut graph, 12, &value_info("output", last_layer.output_size()));
+
+    let mut opset = Vec::new();
+    write_string(&mut opset, 1, "");
+    write_varint_field(&mut opset, 2, ONNX_OPSET_VERSION);
+
+    let mut model = Vec::new();
+    write_varint_field(&mut model, 1, ONNX_IR_VERSION);
+    write_string(&mut model, 2, "genius-hour");
+    write_string(&mut model, 3, env!("CARGO_PKG_VERSION"));
+    write_message(&mut model, 7, &graph);
+    write_message(&mut model, 8, &opset);
+    Ok(model)
+}
+
+// NodeProto with a single output and at most one attribute
+fn node(name: &str, op_type: &str, inputs: &[&str], output: &str, attribute: Option<(&str, Attribute)>) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for input in inputs {
+        write_string(&mut bytes, 1, input);
+    }
+    write_string(&mut bytes, 2, output);
+    write_string(&mut bytes, 3, name);
+    write_string(&mut bytes, 4, op_type);
+    if let Some((attribute_name, value)) = attribute {
+        let mut attribute = Vec::new();
+        write_string(&mut attribute, 1, attribute_name);
+        match value {
+            Attribute::Float(value) => {
+                write_fixed32_field(&mut attribute, 2, value.to_bits());
+                write_varint_field(&mut attribute, 20, ONNX_ATTRIBUTE_FLOAT);
+            }
+            Attribute::Int(value) => {
+                // int64 fields are varints of the two's complement value
+                write_varint_field(&mut attribute, 3, value as u64);
+                write_varint_field(&mut attribute, 20, ONNX_ATTRIBUTE_INT);
+            }
+        }
+        write_message(&mut bytes, 5, &attribute);
+    }
+    bytes
+}
+
+// TensorProto holding f32 values as raw little-endian bytes
+fn tensor(name: &str, dims: &[usize], values: &[f32]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for &dim in dims {
+        write_varint_field(&mut bytes, 1, dim as u64);
+    }
+    write_varint_field(&mut bytes, 2, ONNX_FLOAT);
+    write_string(&mut bytes, 8, name);
+    let raw_data: Vec<u8> = values.iter().flat_map(|value| value.to_le_bytes()).collect();
+    write_bytes(&mut bytes, 9, &raw_data);
+    bytes
+}
+
+// ValueInfoProto of a float tensor shaped [batch, num_features]
+fn value_info(name: &str, num_features: usize) -> Vec<u8> {
+    let mut batch_dim = Vec::new();
+    write_string(&mut batch_dim, 2, "batch");
+    let mut features_dim = Vec::new();
+    write_varint_field(&mut features_dim, 1, num_features as u64);
+    let mut shape = Vec::new();
+    write_message(&mut shape, 1, &batch_dim);
+    write_message(&mut shape, 1, &features_dim);
+
+    let mut tensor_type = Vec::new();
+    write_varint_field(&mut tensor_type, 1, ONNX_FLOAT);
+    write_message(&mut tensor_type, 2, &shape);
+    let mut type_proto = Vec::new();
+    write_message(&mut type_proto, 1, &tensor_type);
+
+    let mut bytes = Vec::new();
+    write_string(&mut bytes, 1, name);
+    write_message(&mut bytes, 2, &type_proto);
+    bytes
+}
+
+// Protobuf wire format: every field starts with (field_number << 3 | wire_type) as a varint,
+// wire type 0 is a varint value, 2 a length-prefixed byte string (strings and nested messages)
+// and 5 four little-endian bytes (floats)
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push((value as u8 & 0x7F) | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn write_varint_field(bytes: &mut Vec<u8>, field_number: u64, value: u64) {
+    write_varint(bytes, field_number << 3);
+    write_varint(bytes, value);
+}
+
+fn write_fixed32_field(bytes: &mut Vec<u8>, field_number: u64, value: u32) {
+    write_varint(bytes, (field_number << 3) | 5);
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_bytes(bytes: &mut Vec<u8>, field_number: u64, data: &[u8]) {
+    write_varint(bytes, (field_number << 3) | 2);
+    write_varint(bytes, data.len() as u64);
+    bytes.extend_from_slice(data);
+}
+
+fn write_string(bytes: &mut Vec<u8>, field_number: u64, value: &str) {
+    write_bytes(bytes, field_number, value.as_bytes());
+}
+
+fn write_message(bytes: &mut Vec<u8>, field_number: u64, message: &[u8]) {
+    write_bytes(bytes, field_number, message);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loss::LossFunction;
+
+    enum Field<'a> {
+        Varint(u64),
+        Bytes(&'a [u8]),
+        Fixed,
+    }
+
+    fn read_varint(bytes: &[u8], position: &mut usize) -> u64 {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = bytes[*position];
+            *position += 1;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return value;
+            }
+            shift += 7;
+        }
+    }
+
+    // Every (field number, value) of a protobuf message, panicking on malformed input
+    fn parse_message(bytes: &[u8]) -> Vec<(u64, Field<'_>)> {
+        let mut fields = Vec::new();
+        let mut position = 0;
+        while position < bytes.len() {
+            let key = read_varint(bytes, &mut position);
+            let field = match key & 7 {
+                0 => Field::Varint(read_varint(bytes, &mut position)),
+                1 => { position += 8; Field::Fixed }
+                2 => {
+                    let length = read_varint(bytes, &mut position) as usize;
+                    position += length;
+                    Field::Bytes(&bytes[position - length..position])
+                }
+                5 => { position += 4; Field::Fixed }
+                wire_type => panic!("Unexpected wire type {}", wire_type),
+            };
+            fields.push((key >> 3, field));
+        }
+        assert_eq!(position, bytes.len(), "Message overruns its bytes");
+        fields
+    }
+
+    fn submessages<'a>(fields: &[(u64, Field<'a>)], number: u64) -> Vec<&'a [u8]> {
+        fields.iter().filter(|(field_number, _)| *field_number == number)
+            .map(|(_, field)| match field { Field::Bytes(bytes) => *bytes, _ => panic!("Field {} isn't length-delimited", number) })
+            .collect()
+    }
+
+    #[test]
+    fn exported_model_parses_back_with_one_gemm_per_layer() {
+        let mut network = NeuralNetwork::new_seeded(LossFunction::CrossEntropy, 18);
+        network.add_dense_layer(4, 5, ActivationFunction::ReLU);
+        network.add_dense_layer(5, 5, ActivationFunction::Linear);
+        network.add_dense_layer(5, 3, ActivationFunction::Softmax);
+
+        let bytes = to_onnx_bytes(&network).unwrap();
+        let model = parse_message(&bytes);
+        assert!(model.iter().any(|(number, field)| *number == 1 && matches!(field, Field::Varint(ONNX_IR_VERSION))));
+        let graphs = submessages(&model, 7);
+        assert_eq!(graphs.len(), 1);
+        let graph = parse_message(graphs[0]);
+        let op_types: Vec<String> = submessages(&graph, 1).into_iter()
+            .map(|node| String::from_utf8(submessages(&parse_message(node), 4)[0].to_vec()).unwrap())
+            .collect();
+        assert_eq!(op_types, vec!["Gemm", "Relu", "Gemm", "Gemm", "Softmax"]);
+        assert_eq!(op_types.iter().filter(|op| *op == "Gemm").count(), 3);
+        // Weights and biases of every layer
+        assert_eq!(submessages(&graph, 5).len(), 6);
+    }
+}
@@ -0,0 +1,301 @@
+// Update rules that turn gradients into weight changes. A network does plain SGD unless it was built with
+// NeuralNetwork::new_with_optimizer, NeuralNetwork::train_batch_with_optimizer takes any Optimizer for a single step.
+
+use nalgebra::{DMatrix, DVector};
+use serde::{Serialize, Deserialize};
+use crate::layer::{Layer, LayerGradients};
+use crate::scalar::Real;
+
+// Generic over the scalar type like the layers, Sgd works for any of them, the others are f32 only.
+// Send + Sync so a network owning its optimizer can still live in a static or move between threads.
+pub trait Optimizer<T: Real = f32>: Send + Sync {
+    // One update of all layers. gradients holds one LayerGradients per layer, in layer order
+    // (empty ones for layers without parameters, which are left alone).
+    fn step(&mut self, layers: &mut [Box<dyn Layer<T>>], gradients: &[LayerGradients<T>], learning_rate: T);
+}
+
+// Plain stochastic gradient descent, the same update as train_batch
+pub struct Sgd;
+
+impl<T: Real> Optimizer<T> for Sgd {
+    fn step(&mut self, layers: &mut [Box<dyn Layer<T>>], gradients: &[LayerGradients<T>], learning_rate: T) {
+        for (layer, layer_gradients) in layers.iter_mut().zip(gradients) {
+            layer.apply_gradients(layer_gradients, learning_rate);
+        }
+    }
+}
+
+// Layer-wise Adaptive Rate Scaling (You et al., 2017) for large-batch training.
+// Every layer's learning rate is multiplied by its trust ratio
+//   trust_coefficient * ||W|| / (||dW|| + epsilon)
+// so layers whose gradients are small relative to their weights take larger steps, and vice versa.
+// The biases use the same rate as their layer's weights.
+pub struct Lars {
+    pub trust_coefficient: f32,
+    pub epsilon: f32,
+}
+
+impl Lars {
+    pub fn new(trust_coefficient: f32) -> Self {
+        Lars { trust_coefficient, epsilon: 1e-8 }
+    }
+
+    // The factor every layer's learning rate gets multiplied by. Layers with all-zero weights or
+    // gradients (e.g. right after a zero init) fall back to 1 so they can still move.
+    pub fn trust_ratios(&self, layers: &[Box<dyn Layer>], gradients: &[LayerGradients]) -> Vec<f32> {
+        layers.iter().zip(gradients).map(|(layer, layer_gradients)| {
+            let weight_norm = layer.parameters().map_or(0.0, |(weights, _)| weights.norm());
+            let gradient_norm = layer_gradients.weights.norm();
+            if weight_norm == 0.0 || gradient_norm == 0.0 {
+                1.0
+            } else {
+                self.trust_coefficient * weight_norm / (gradient_norm + self.epsilon)
+            }
+        }).collect()
+    }
+}
+
+impl Optimizer for Lars {
+    fn step(&mut self, layers: &mut [Box<dyn Layer>], gradients: &[LayerGradients], learning_rate: f32) {
+        let trust_ratios = self.trust_ratios(layers, gradients);
+        for ((layer, layer_gradients), trust_ratio) in layers.iter_mut().zip(gradients).zip(trust_ratios) {
+            layer.apply_gradients(layer_gradients, learning_rate * trust_ratio);
+        }
+    }
+}
+
+// Velocity of one layer's parameters for Momentum
+pub struct Velocity {
+    pub velocity_weights: DMatrix<f32>,
+    pub velocity_biases: DVector<f32>,
+}
+
+// SGD with (heavy ball) momentum: v = mu * v - lr * grad, param += v.
+// The velocities are created lazily (as zeros) on the first step, so one Momentum belongs to one network.
+pub struct Momentum {
+    pub mu: f32,
+    velocities: Vec<Velocity>,
+}
+
+impl Momentum {
+    pub fn new(mu: f32) -> Self {
+        Momentum { mu, velocities: Vec::new() }
+    }
+
+    // Current velocity of layer layer_idx, None before the first step
+    pub fn velocity(&self, layer_idx: usize) -> Option<&Velocity> {
+        self.velocities.get(layer_idx)
+    }
+}
+
+impl Optimizer for Momentum {
+    fn step(&mut self, layers: &mut [Box<dyn Layer>], gradients: &[LayerGradients], learning_rate: f32) {
+        if self.velocities.len() != layers.len() {
+            self.velocities = layers.iter().map(|layer| {
+                let zeros = LayerGradients::zeros_like(layer.as_ref());
+                Velocity { velocity_weights: zeros.weights, velocity_biases: zeros.biases }
+            }).collect();
+        }
+        for ((layer, layer_gradients), velocity) in layers.iter_mut().zip(gradients).zip(self.velocities.iter_mut()) {
+            if let Some((weights, biases)) = layer.parameters_mut() {
+                velocity.velocity_weights = self.mu * &velocity.velocity_weights - learning_rate * &layer_gradients.weights;
+                velocity.velocity_biases = self.mu * &velocity.velocity_biases - learning_rate * &layer_gradients.biases;
+                *weights += &velocity.velocity_weights;
+                *biases += &velocity.velocity_biases;
+            }
+        }
+    }
+}
+
+// Per-layer moment estimates of Adam, shaped like the layer's parameters
+struct AdamMoments {
+    m_weights: DMatrix<f32>,
+    v_weights: DMatrix<f32>,
+    m_biases: DVector<f32>,
+    v_biases: DVector<f32>,
+}
+
+// Adam (Kingma & Ba, 2014): every parameter gets its own step size from running averages of its gradient (m)
+// and squared gradient (v), bias-corrected for the zero initialization. The moments are created lazily
+// (as zeros) on the first step, so one Adam belongs to one network.
+pub struct Adam {
+    pub beta1: f32,
+    pub beta2: f32,
+    pub epsilon: f32,
+    moments: Vec<AdamMoments>,
+    timestep: i32,
+}
+
+impl Adam {
+    pub fn new(beta1: f32, beta2: f32, epsilon: f32) -> Self {
+        Adam { beta1, beta2, epsilon, moments: Vec::new(), timestep: 0 }
+    }
+
+    // Number of steps taken so far
+    pub fn timestep(&self) -> i32 {
+        self.timestep
+    }
+}
+
+impl Default for Adam {
+    // The defaults from the paper
+    fn default() -> Self {
+        Adam::new(0.9, 0.999, 1e-8)
+    }
+}
+
+impl Optimizer for Adam {
+    fn step(&mut self, layers: &mut [Box<dyn Layer>], gradients: &[LayerGradients], learning_rate: f32) {
+        if self.moments.len() != layers.len() {
+            self.moments = layers.iter().map(|layer| {
+                let zeros = LayerGradients::zeros_like(layer.as_ref());
+                AdamMoments {
+                    m_weights: zeros.weights.clone(),
+                    v_weights: zeros.weights,
+                    m_biases: zeros.biases.clone(),
+                    v_biases: zeros.biases,
+                }
+            }).collect();
+        }
+        self.timestep += 1;
+        let (beta1, beta2, epsilon) = (self.beta1, self.beta2, self.epsilon);
+        let m_correction = 1.0 - beta1.powi(self.timestep);
+        let v_correction = 1.0 - beta2.powi(self.timestep);
+
+        for ((layer, layer_gradients), moments) in layers.iter_mut().zip(gradients).zip(self.moments.iter_mut()) {
+            if let Some((weights, biases)) = layer.parameters_mut() {
+                moments.m_weights = beta1 * &moments.m_weights + (1.0 - beta1) * &layer_gradients.weights;
+                moments.v_weights = beta2 * &moments.v_weights + (1.0 - beta2) * layer_gradients.weights.map(|g| g * g);
+                moments.m_biases = beta1 * &moments.m_biases + (1.0 - beta1) * &layer_gradients.biases;
+                moments.v_biases = beta2 * &moments.v_biases + (1.0 - beta2) * layer_gradients.biases.map(|g| g * g);
+
+                *weights -= moments.m_weights.zip_map(&moments.v_weights, |m, v| {
+                    learning_rate * (m / m_correction) / ((v / v_correction).sqrt() + epsilon)
+                });
+                *biases -= moments.m_biases.zip_map(&moments.v_biases, |m, v| {
+                    learning_rate * (m / m_correction) / ((v / v_correction).sqrt() + epsilon)
+                });
+            }
+        }
+    }
+}
+
+// How the learning rate changes over training, as a factor on the base learning rate at step (epoch) t,
+// starting at t = 0. fit steps the schedule once per epoch.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum LrSchedule {
+    // The base learning rate throughout
+    #[default]
+    Constant,
+    // Multiplied by gamma every step_size steps
+    StepDecay { step_size: usize, gamma: f32 },
+    // Multiplied by gamma every step
+    ExponentialDecay { gamma: f32 },
+    // Half a cosine from the base learning rate down to 0 at step t_max, which should be the number of epochs.
+    // Stays at 0 after that.
+    CosineAnnealing { t_max: usize },
+}
+
+impl LrSchedule {
+    pub fn multiplier(&self, step: usize) -> f64 {
+        match *self {
+            LrSchedule::Constant => 1.0,
+            LrSchedule::StepDecay { step_size, gamma } => (gamma as f64).powi((step / step_size.max(1)) as i32),
+            LrSchedule::ExponentialDecay { gamma } => (gamma as f64).powi(step as i32),
+            LrSchedule::CosineAnnealing { t_max } => {
+                let progress = step.min(t_max) as f64 / t_max.max(1) as f64;
+                0.5 * (1.0 + (std::f64::consts::PI * progress).cos())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activation::ActivationFunction;
+    use crate::layer::DenseLayer;
+    use crate::loss::LossFunction;
+    use crate::network::NeuralNetwork;
+
+    fn constant_layer(weight: f32) -> Box<dyn Layer> {
+        Box::new(DenseLayer::from_parameters(DMatrix::from_element(2, 2, weight), DVector::zeros(2), ActivationFunction::ReLU))
+    }
+
+    fn constant_gradients(gradient: f32) -> LayerGradients {
+        LayerGradients { weights: DMatrix::from_element(2, 2, gradient), biases: DVector::zeros(2) }
+    }
+
+    #[test]
+    fn lars_trusts_large_weights_with_small_gradients_more() {
+        let layers = vec![constant_layer(10.0), constant_layer(0.1)];
+        let gradients = vec![constant_gradients(0.01), constant_gradients(1.0)];
+
+        let ratios = Lars::new(0.001).trust_ratios(&layers, &gradients);
+        assert!((ratios[0] - 1.0).abs() < 1e-4, "Got {}", ratios[0]);
+        assert!((ratios[1] - 1e-4).abs() < 1e-8, "Got {}", ratios[1]);
+        assert!(ratios[0] > ratios[1]);
+    }
+
+    #[test]
+    fn momentum_velocity_accumulates_over_steps() {
+        let mut layers = vec![constant_layer(1.0)];
+        let gradients = vec![constant_gradients(2.0)];
+        let mut momentum = Momentum::new(0.9);
+        assert!(momentum.velocity(0).is_none());
+
+        momentum.step(&mut layers, &gradients, 0.1);
+        assert!(momentum.velocity(0).unwrap().velocity_weights.iter().all(|&v| (v + 0.2).abs() < 1e-6));
+        momentum.step(&mut layers, &gradients, 0.1);
+        // v = 0.9 * -0.2 - 0.1 * 2, and the weights moved by both velocities
+        assert!(momentum.velocity(0).unwrap().velocity_weights.iter().all(|&v| (v + 0.38).abs() < 1e-6));
+        let (weights, _) = layers[0].parameters().unwrap();
+        assert!(weights.iter().all(|&w| (w - 0.42).abs() < 1e-6));
+    }
+
+    fn zero_regressor() -> NeuralNetwork {
+        let mut network = NeuralNetwork::new(LossFunction::MeanSquaredError);
+        network.add_layer(DenseLayer::from_parameters(DMatrix::zeros(2, 1), DVector::zeros(1), ActivationFunction::Linear));
+        network
+    }
+
+    #[test]
+    fn adam_fits_a_small_regression_faster_than_sgd() {
+        // y = 3 * x0 - 2 * x1 + 1 on small inputs, where the plain gradients are small too
+        let inputs = DMatrix::from_fn(16, 2, |r, c| ((r * 2 + c) as f32 * 0.7).sin() * 0.1);
+        let targets = DMatrix::from_fn(16, 1, |r, _| 3.0 * inputs[(r, 0)] - 2.0 * inputs[(r, 1)] + 1.0);
+        let mut sgd = zero_regressor();
+        let mut adam = zero_regressor();
+        let mut optimizer = Adam::default();
+
+        let initial_loss = sgd.evaluate(&inputs, &targets).loss;
+        for _ in 0..50 {
+            sgd.train_batch(&inputs, &targets, 0.05);
+            adam.train_batch_with_optimizer(&inputs, &targets, 0.05, &mut optimizer);
+        }
+        let (sgd_loss, adam_loss) = (sgd.evaluate(&inputs, &targets).loss, adam.evaluate(&inputs, &targets).loss);
+        assert!(adam_loss < 0.1 * initial_loss, "Adam went from {} to {}", initial_loss, adam_loss);
+        assert!(adam_loss < sgd_loss, "Adam reached {}, SGD {}", adam_loss, sgd_loss);
+        assert_eq!(optimizer.timestep(), 50);
+    }
+
+    #[test]
+    fn schedule_multipliers_at_sample_steps() {
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-6;
+        assert!([0, 7, 1000].iter().all(|&step| LrSchedule::Constant.multiplier(step) == 1.0));
+
+        let step_decay = LrSchedule::StepDecay { step_size: 3, gamma: 0.5 };
+        let multipliers: Vec<f64> = (0..7).map(|step| step_decay.multiplier(step)).collect();
+        assert_eq!(multipliers, vec![1.0, 1.0, 1.0, 0.5, 0.5, 0.5, 0.25]);
+
+        let exponential = LrSchedule::ExponentialDecay { gamma: 0.9 };
+        assert!(close(exponential.multiplier(0), 1.0));
+        assert!(close(exponential.multiplier(2), 0.81));
+
+        let cosine = LrSchedule::CosineAnnealing { t_max: 10 };
+        assert!(close(cosine.multiplier(0), 1.0));
+        assert!(close(cosine.multiplier(5), 0.5));
+        assert!(close(cosine.multiplier(10), 0.0));
+        assert!(close(cosine.multiplier(25), 0.0), "Cosine annealing should stay at 0 after t_max");
+    }
+}
@@ -0,0 +1,61 @@
+// Background-thread batch loading, so preparing the next batch overlaps with training on the current one.
+// Only built with the "prefetch" feature, since wasm32-unknown-unknown has no threads.
+
+use std::sync::Arc;
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::thread;
+use nalgebra::DMatrix;
+use crate::data::Dataset;
+
+pub struct PrefetchLoader {
+    receiver: Receiver<(DMatrix<f32>, DMatrix<f32>)>,
+}
+
+impl PrefetchLoader {
+    // Yields the batches of `indices` (split into chunks of batch_size) in order, with at most
+    // `prefetch` batches loaded ahead of the consumer. The worker thread stops once the loader is dropped.
+    pub fn new<D: Dataset + Send + Sync + 'static>(
+        dataset: Arc<D>,
+        indices: Vec<usize>,
+        batch_size: usize,
+        prefetch: usize,
+    ) -> Self {
+        let (sender, receiver) = sync_channel(prefetch.max(1));
+        thread::spawn(move || {
+            for batch_indices in indices.chunks(batch_size.max(1)) {
+                // send only fails when the receiving PrefetchLoader was dropped
+                if sender.send(dataset.get_batch(batch_indices)).is_err() {
+                    break;
+                }
+            }
+        });
+        PrefetchLoader { receiver }
+    }
+}
+
+impl Iterator for PrefetchLoader {
+    type Item = (DMatrix<f32>, DMatrix<f32>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Errors once the worker has sent every batch and hung up
+        self.receiver.recv().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::InMemoryDataset;
+
+    #[test]
+    fn yields_the_same_batches_as_synchronous_loading() {
+        let dataset = Arc::new(InMemoryDataset::new(
+            DMatrix::from_fn(23, 3, |r, c| (r * 3 + c) as f32),
+            DMatrix::from_fn(23, 1, |r, _| r as f32),
+        ));
+        let indices: Vec<usize> = (0..23).rev().collect();
+        let expected: Vec<_> = indices.chunks(5).map(|batch_indices| dataset.get_batch(batch_indices)).collect();
+        let prefetched: Vec<_> = PrefetchLoader::new(Arc::clone(&dataset), indices, 5, 2).collect();
+        assert_eq!(prefetched, expected);
+    }
+}
@@ -0,0 +1,67 @@
+// Small PRNG that is fully specified in this crate, so a seed produces the same numbers
+// regardless of the rand/rand_distr versions or the platform.
+// Algorithm: SplitMix64 (Steele, Lea & Flood, 2014).
+
+use serde::{Serialize, Deserialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StableRng {
+    state: u64,
+}
+
+impl StableRng {
+    pub fn new(seed: u64) -> Self {
+        StableRng { state: seed }
+    }
+
+    // The whole generator state. StableRng::new(rng.state()) continues exactly where rng left off,
+    // which is what makes bit-exact training resumes possible.
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [0, 1), built from the top 53 bits so every value is exactly representable
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // Standard normal sample using the Box-Muller transform.
+    // Each call consumes exactly two u64s and only uses the cosine branch, so the sampling order is fixed.
+    pub fn next_standard_normal(&mut self) -> f64 {
+        // 1 - u keeps u1 in (0, 1] so ln never sees 0
+        let u1 = 1.0 - self.next_f64();
+        let u2 = self.next_f64();
+        (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+    }
+
+    // Fisher-Yates shuffle, from the last element down, swapping i with next_u64() % (i + 1).
+    // The modulo bias is negligible for any realistic slice length.
+    pub fn shuffle<T>(&mut self, slice: &mut [T]) {
+        for i in (1..slice.len()).rev() {
+            let j = (self.next_u64() % (i as u64 + 1)) as usize;
+            slice.swap(i, j);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_splitmix64_reference_output() {
+        let mut rng = StableRng::new(1234567);
+        let expected = [6457827717110365317, 3203168211198807973, 9817491932198370423, 4593380528125082431, 16408922859458223821];
+        for value in expected {
+            assert_eq!(rng.next_u64(), value);
+        }
+    }
+}
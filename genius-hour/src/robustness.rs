@@ -0,0 +1,151 @@
+// Robustness checks: how much the predictions change under adversarial or random perturbations
+
+use nalgebra::DMatrix;
+use crate::metrics::{accuracy, argmax};
+use crate::network::NeuralNetwork;
+use crate::rng::StableRng;
+
+// Fast Gradient Sign Method for a whole batch: every input feature is moved by exactly epsilon
+// in the direction that increases the loss, x_adv = x + epsilon * sign(dLoss/dx).
+// The result isn't clamped, so callers working with pixels may want to clamp it to [0, 1].
+pub fn fgsm_attack_batch(network: &mut NeuralNetwork, inputs: &DMatrix<f32>, targets: &DMatrix<f32>, epsilon: f32) -> DMatrix<f32> {
+    // The loss is averaged over the batch, which scales each row's gradient but not its sign
+    let gradient = network.input_gradient(inputs, targets);
+    inputs + gradient.map(|g| epsilon * g.signum())
+}
+
+// Accuracy lost under an FGSM attack: clean accuracy minus accuracy on the perturbed inputs (targets are one-hot)
+pub fn fgsm_accuracy_drop(network: &mut NeuralNetwork, inputs: &DMatrix<f32>, targets: &DMatrix<f32>, epsilon: f32) -> f32 {
+    let clean_accuracy = accuracy(network, inputs, targets);
+    let adversarial_inputs = fgsm_attack_batch(network, inputs, targets, epsilon);
+    clean_accuracy - accuracy(network, &adversarial_inputs, targets)
+}
+
+// Accuracy under random (not adversarial) corruption: for every noise level, Gaussian noise with that
+// standard deviation is added to every input feature and the accuracy on the noisy inputs is measured.
+// Returns (noise_std, accuracy) pairs in the given order. labels_raw holds one class index per row.
+// Every level uses the same noise pattern (scaled), drawn from `seed`, so the curve is reproducible and smooth.
+pub fn evaluate_noise_robustness(
+    network: &NeuralNetwork,
+    inputs: &DMatrix<f32>,
+    labels_raw: &DMatrix<f32>,
+    noise_levels: &[f32],
+    seed: u64
+) -> Vec<(f32, f32)> {
+    assert_eq!(inputs.nrows(), labels_raw.nrows(), "Got {} inputs but {} labels", inputs.nrows(), labels_raw.nrows());
+    let mut rng = StableRng::new(seed);
+    let noise = DMatrix::from_fn(inputs.nrows(), inputs.ncols(), |_, _| rng.next_standard_normal() as f32);
+
+    noise_levels.iter().map(|&noise_std| {
+        if inputs.nrows() == 0 {
+            return (noise_std, 0.0);
+        }
+        let noisy_inputs = inputs + noise_std * &noise;
+        let correct = network.predict_classes_batch(&noisy_inputs).iter().zip(labels_raw.column(0).iter())
+            .filter(|&(&predicted, &label)| predicted == label as usize)
+            .count();
+        (noise_std, correct as f32 / inputs.nrows() as f32)
+    }).collect()
+}
+
+// Largest FGSM epsilon tried before min_adversarial_epsilon gives up (in input units)
+const MAX_ADVERSARIAL_EPSILON: f32 = 1024.0;
+
+impl NeuralNetwork {
+    // Approximate minimum L-infinity perturbation that changes the predicted class of one input (1 x input_size):
+    // takes the FGSM direction sign(dLoss/dx) w.r.t. the currently predicted class, doubles epsilon until the
+    // prediction flips, then binary-searches the flip point with `steps` bisections.
+    // Returns the smallest flipping epsilon found (an upper bound on the true minimum), or f32::INFINITY
+    // if even MAX_ADVERSARIAL_EPSILON doesn't flip it. Larger means more robust around this input.
+    pub fn min_adversarial_epsilon(&mut self, input: &DMatrix<f32>, steps: usize) -> f32 {
+        assert_eq!(input.nrows(), 1, "min_adversarial_epsilon takes a single input row, got {}", input.nrows());
+        let clean_output = self.infer(input);
+        let clean_class = argmax(clean_output.iter());
+        let mut target = DMatrix::zeros(1, clean_output.ncols());
+        target[(0, clean_class)] = 1.0;
+
+        let direction = self.input_gradient(input, &target).map(f32::signum);
+        let flips = |network: &NeuralNetwork, epsilon: f32| {
+            argmax(network.infer(&(input + epsilon * &direction)).iter()) != clean_class
+        };
+
+        // Find an epsilon that flips the prediction, the flip point is then somewhere in (low, high]
+        let mut low = 0.0;
+        let mut high = 1e-3;
+        while !flips(self, high) {
+            if high >= MAX_ADVERSARIAL_EPSILON {
+                return f32::INFINITY;
+            }
+            low = high;
+            high *= 2.0;
+        }
+        for _ in 0..steps {
+            let mid = (low + high) / 2.0;
+            if flips(self, mid) {
+                high = mid;
+            } else {
+                low = mid;
+            }
+        }
+        high
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activation::ActivationFunction;
+    use crate::layer::DenseLayer;
+    use crate::loss::LossFunction;
+    use nalgebra::DVector;
+
+    fn seeded_classifier(seed: u64) -> NeuralNetwork {
+        let mut network = NeuralNetwork::new_seeded(LossFunction::CrossEntropy, seed);
+        network.add_dense_layer(4, 6, ActivationFunction::ReLU);
+        network.add_dense_layer(6, 3, ActivationFunction::Softmax);
+        network
+    }
+
+    #[test]
+    fn fgsm_moves_every_feature_by_exactly_epsilon() {
+        let mut network = seeded_classifier(1);
+        let inputs = DMatrix::from_fn(5, 4, |r, c| ((r * 4 + c) as f32 * 0.37).sin());
+        let targets = DMatrix::from_fn(5, 3, |r, c| if r % 3 == c { 1.0 } else { 0.0 });
+        let epsilon = 0.05;
+        let perturbed = fgsm_attack_batch(&mut network, &inputs, &targets, epsilon);
+        for difference in (perturbed - &inputs).iter() {
+            assert!((difference.abs() - epsilon).abs() < 1e-6, "Feature moved by {}", difference);
+        }
+    }
+
+    // Class 0 when x0 > threshold, so an input at x0 = 1 flips once it moves by 1 - threshold
+    fn threshold_classifier(threshold: f32) -> NeuralNetwork {
+        let weights = DMatrix::from_row_slice(2, 2, &[1.0, -1.0, 0.0, 0.0]);
+        let biases = DVector::from_vec(vec![-threshold, threshold]);
+        let mut network = NeuralNetwork::new(LossFunction::CrossEntropy);
+        network.add_layer(DenseLayer::from_parameters(weights, biases, ActivationFunction::Softmax));
+        network
+    }
+
+    #[test]
+    fn a_wider_margin_needs_a_larger_epsilon_to_flip() {
+        let input = DMatrix::from_row_slice(1, 2, &[1.0, 0.0]);
+        let robust = threshold_classifier(0.0).min_adversarial_epsilon(&input, 20);
+        let fragile = threshold_classifier(0.9).min_adversarial_epsilon(&input, 20);
+        assert!((robust - 1.0).abs() < 1e-3, "Got {}", robust);
+        assert!((fragile - 0.1).abs() < 1e-3, "Got {}", fragile);
+    }
+
+    #[test]
+    fn noise_free_accuracy_is_the_clean_one_and_heavy_noise_lowers_it() {
+        let network = threshold_classifier(0.0);
+        let inputs = DMatrix::from_fn(40, 2, |r, c| if c == 1 { 0.0 } else if r % 2 == 0 { 0.1 + r as f32 / 40.0 } else { -0.1 - r as f32 / 40.0 });
+        let labels = DMatrix::from_fn(40, 1, |r, _| (r % 2) as f32);
+
+        let curve = evaluate_noise_robustness(&network, &inputs, &labels, &[0.0, 0.1, 10.0], 12);
+        assert_eq!(curve.iter().map(|&(noise_std, _)| noise_std).collect::<Vec<_>>(), vec![0.0, 0.1, 10.0]);
+        assert_eq!(curve[0].1, 1.0);
+        assert!(curve[1].1 <= curve[0].1);
+        assert!(curve[2].1 < 0.8, "Accuracy {} under heavy noise", curve[2].1);
+    }
+}
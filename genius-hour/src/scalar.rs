@@ -0,0 +1,31 @@
+// Floating point type a network computes in. Everything defaults to f32 (MNIST, WASM, the file formats),
+// f64 networks (NeuralNetwork<f64>) are there for numerical gradient checking and scientific use.
+
+use nalgebra::RealField;
+
+pub trait Real: RealField + Copy {
+    // Lossy for f32, like an `as` cast. Used for constants and counts in generic code.
+    fn cast(value: f64) -> Self;
+
+    fn as_f64(self) -> f64;
+}
+
+impl Real for f32 {
+    fn cast(value: f64) -> Self {
+        value as f32
+    }
+
+    fn as_f64(self) -> f64 {
+        self as f64
+    }
+}
+
+impl Real for f64 {
+    fn cast(value: f64) -> Self {
+        value
+    }
+
+    fn as_f64(self) -> f64 {
+        self
+    }
+}
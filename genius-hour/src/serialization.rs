@@ -1,11 +1,20 @@
-use serde::{Serialize, Deserialize};
+use bincode::Options;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
 use nalgebra::{DMatrix, DVector};
-use crate::activation::ActivationFunction; // Your existing ActivationFunction
-use crate::layer::DenseLayer;
+use crate::activation::{ActivationFunction, BlendedActivation}; // Your existing ActivationFunction
+use crate::conv::{FlattenLayer, MaxPool2DLayer, SerializableConv2DLayer};
+use crate::layer::{DenseLayer, Layer};
 use crate::network::NeuralNetwork;
 use crate::loss::LossFunction; // Assuming LossFunction might be part of network state too
+use crate::scalar::Real;
+use crate::standardize::InputStandardizeLayer;
 
-#[derive(Serialize, Deserialize, Debug)]
+// Binary formats (bincode) store the weights as one flat column-major vec plus the dimensions.
+// Human-readable ones (JSON) get nested arrays instead, one inner array per row (input neuron):
+//   {"weights": [[...], ...], "biases": [...], "activation_fn": "ReLU"}
+#[derive(Debug)]
 pub struct SerializableDenseLayer {
     weights_data: Vec<f32>,
     weights_rows: usize,
@@ -14,49 +23,350 @@ pub struct SerializableDenseLayer {
     activation_fn: ActivationFunction,
 }
 
-impl From<&DenseLayer> for SerializableDenseLayer {
-    fn from(layer: &DenseLayer) -> Self {
+// Files always hold f32, f64 layers are rounded
+impl<T: Real> From<&DenseLayer<T>> for SerializableDenseLayer {
+    fn from(layer: &DenseLayer<T>) -> Self {
         Self {
-            weights_data: layer.weights.as_slice().to_vec(),
-            weights_rows: layer.weights.nrows(),
-            weights_cols: layer.weights.ncols(),
-            biases_data: layer.biases.as_slice().to_vec(),
+            weights_data: layer.weights.iter().map(|&weight| weight.as_f64() as f32).collect(),
+            weights_rows: layer.input_size(),
+            weights_cols: layer.output_size(),
+            biases_data: layer.biases.iter().map(|&bias| bias.as_f64() as f32).collect(),
             activation_fn: layer.activation_fn,
         }
     }
 }
 
+// The bincode layout, borrowed for writing
+#[derive(Serialize)]
+struct FlatDenseLayerRef<'a> {
+    weights_data: &'a [f32],
+    weights_rows: usize,
+    weights_cols: usize,
+    biases_data: &'a [f32],
+    activation_fn: ActivationFunction,
+}
+
+#[derive(Deserialize)]
+struct FlatDenseLayer {
+    weights_data: Vec<f32>,
+    weights_rows: usize,
+    weights_cols: usize,
+    biases_data: Vec<f32>,
+    activation_fn: ActivationFunction,
+}
+
+#[derive(Serialize, Deserialize)]
+struct NestedDenseLayer {
+    weights: Vec<Vec<f32>>, // weights[input][output]
+    biases: Vec<f32>,
+    activation_fn: ActivationFunction,
+}
+
+impl Serialize for SerializableDenseLayer {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            let weights = DMatrix::from_column_slice(self.weights_rows, self.weights_cols, &self.weights_data);
+            NestedDenseLayer {
+                weights: weights.row_iter().map(|row| row.iter().copied().collect()).collect(),
+                biases: self.biases_data.clone(),
+                activation_fn: self.activation_fn,
+            }.serialize(serializer)
+        } else {
+            FlatDenseLayerRef {
+                weights_data: &self.weights_data,
+                weights_rows: self.weights_rows,
+                weights_cols: self.weights_cols,
+                biases_data: &self.biases_data,
+                activation_fn: self.activation_fn,
+            }.serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for SerializableDenseLayer {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let nested = NestedDenseLayer::deserialize(deserializer)?;
+            // The biases give the number of columns even when there are no rows
+            let weights_cols = nested.biases.len();
+            if let Some(row) = nested.weights.iter().position(|row| row.len() != weights_cols) {
+                return Err(de::Error::custom(format!(
+                    "Weight row {} has {} values, expected {} (one per bias)", row, nested.weights[row].len(), weights_cols
+                )));
+            }
+            let weights_rows = nested.weights.len();
+            let weights = DMatrix::from_row_iterator(weights_rows, weights_cols, nested.weights.into_iter().flatten());
+            Ok(SerializableDenseLayer {
+                weights_data: weights.as_slice().to_vec(),
+                weights_rows,
+                weights_cols,
+                biases_data: nested.biases,
+                activation_fn: nested.activation_fn,
+            })
+        } else {
+            let flat = FlatDenseLayer::deserialize(deserializer)?;
+            Ok(SerializableDenseLayer {
+                weights_data: flat.weights_data,
+                weights_rows: flat.weights_rows,
+                weights_cols: flat.weights_cols,
+                biases_data: flat.biases_data,
+                activation_fn: flat.activation_fn,
+            })
+        }
+    }
+}
+
 impl SerializableDenseLayer {
-    // Converts back to a DenseLayer.
-    pub fn into_dense_layer(self) -> DenseLayer {
-        let mut layer = DenseLayer::new(self.weights_rows, self.weights_cols, self.activation_fn);
-        layer.weights = DMatrix::from_vec(self.weights_rows, self.weights_cols, self.weights_data);
-        layer.biases = DVector::from_vec(self.biases_data);
-        layer
+    // Converts back to a DenseLayer, failing when the weights or biases don't fit the stored dimensions
+    pub fn into_dense_layer(self) -> Result<DenseLayer, Box<dyn std::error::Error>> {
+        if self.weights_data.len() != self.weights_rows * self.weights_cols {
+            return Err(format!(
+                "Dense layer has {} weights, expected {}x{}", self.weights_data.len(), self.weights_rows, self.weights_cols
+            ).into());
+        }
+        if self.biases_data.len() != self.weights_cols {
+            return Err(format!("Dense layer has {} biases, expected {}", self.biases_data.len(), self.weights_cols).into());
+        }
+        let weights = DMatrix::from_vec(self.weights_rows, self.weights_cols, self.weights_data);
+        Ok(DenseLayer::from_parameters(weights, DVector::from_vec(self.biases_data), self.activation_fn))
     }
 }
 
+// Free-form information saved along with a model
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ModelMetadata {
+    // When the model was first saved, in seconds since the Unix epoch. Filled in automatically if unset.
+    pub created_at: Option<String>,
+    pub extra: HashMap<String, String>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SerializableNeuralNetwork {
+    layers: Vec<SerializableLayer>,
+    loss_fn: LossFunction,
+    metadata: ModelMetadata,
+}
+
+// Models saved while networks could only hold DenseLayers: the same fields, but the layers carry no type tag
+#[derive(Deserialize)]
+struct DenseSerializableNeuralNetwork {
+    layers: Vec<SerializableDenseLayer>,
+    loss_fn: LossFunction,
+    metadata: ModelMetadata,
+}
+
+impl From<DenseSerializableNeuralNetwork> for SerializableNeuralNetwork {
+    fn from(dense: DenseSerializableNeuralNetwork) -> Self {
+        SerializableNeuralNetwork {
+            layers: dense.layers.into_iter().map(SerializableLayer::Dense).collect(),
+            loss_fn: dense.loss_fn,
+            metadata: dense.metadata,
+        }
+    }
+}
+
+// Models saved before the loss function and metadata were stored only contain the (dense) layers
+#[derive(Deserialize)]
+struct LegacySerializableNeuralNetwork {
     layers: Vec<SerializableDenseLayer>,
-    // TODO: Serialize loss_fn, and metadata like training date
 }
 
 impl From<&NeuralNetwork> for SerializableNeuralNetwork {
     fn from(network: &NeuralNetwork) -> Self {
-        let serializable_layers = network.get_layers().iter().map(SerializableDenseLayer::from).collect();
+        let serializable_layers = network.get_layers().iter().map(|layer| layer.to_serializable()).collect();
+        let mut metadata = network.metadata().clone();
+        if metadata.created_at.is_none() {
+            // A clock before 1970 isn't worth failing a save over
+            let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+            metadata.created_at = Some(seconds.to_string());
+        }
         Self {
             layers: serializable_layers,
+            loss_fn: network.get_loss_fn(),
+            metadata,
         }
     }
 }
 
 impl SerializableNeuralNetwork {
-    pub fn into_neural_network(self, loss_fn: LossFunction) -> NeuralNetwork {
+    // Decodes a bincode model, also in the older dense-only formats. Files in the oldest, layers-only format
+    // carry no loss function, so they take legacy_loss_fn and fail to load when it is None.
+    pub fn from_bincode(bytes: &[u8], legacy_loss_fn: Option<LossFunction>) -> Result<Self, Box<dyn std::error::Error>> {
+        // bincode isn't self-describing, so a file of another format could happen to decode as the current one
+        // if it were allowed to stop early. Insisting on every byte being used rules that out.
+        let error = match bincode::DefaultOptions::new().with_fixint_encoding().reject_trailing_bytes().deserialize(bytes) {
+            Ok(serializable_nn) => return Ok(serializable_nn),
+            Err(e) => e,
+        };
+        if let Ok(dense) = bincode::deserialize::<DenseSerializableNeuralNetwork>(bytes) {
+            return Ok(dense.into());
+        }
+        let legacy: LegacySerializableNeuralNetwork = match bincode::deserialize(bytes) {
+            Ok(legacy) => legacy,
+            // No format fits, the error of the current one is the most useful
+            Err(_) => return Err(error.into()),
+        };
+        let loss_fn = legacy_loss_fn.ok_or("Model was saved without its loss function, load it with load_weights(path, loss_fn)")?;
+        Ok(SerializableNeuralNetwork {
+            layers: legacy.layers.into_iter().map(SerializableLayer::Dense).collect(),
+            loss_fn,
+            metadata: ModelMetadata::default(),
+        })
+    }
+
+    // Decodes a JSON model, also in the older dense-only format
+    pub fn from_json(json: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let error = match serde_json::from_str(json) {
+            Ok(serializable_nn) => return Ok(serializable_nn),
+            Err(e) => e,
+        };
+        match serde_json::from_str::<DenseSerializableNeuralNetwork>(json) {
+            Ok(dense) => Ok(dense.into()),
+            Err(_) => Err(error.into()),
+        }
+    }
+
+    pub fn loss_fn(&self) -> LossFunction {
+        self.loss_fn
+    }
+
+    pub fn metadata(&self) -> &ModelMetadata {
+        &self.metadata
+    }
+
+    // Fails when a stored layer is malformed, e.g. a corrupted or hand-edited file
+    pub fn into_neural_network(self, loss_fn: LossFunction) -> Result<NeuralNetwork, Box<dyn std::error::Error>> {
         let mut nn = NeuralNetwork::new(loss_fn);
-        for serializable_layer in self.layers {
-            nn.add_layer(serializable_layer.into_dense_layer());
+        for (i, serializable_layer) in self.layers.into_iter().enumerate() {
+            let layer = serializable_layer.into_layer().map_err(|e| format!("Layer {}: {}", i, e))?;
+            nn.add_layer(layer);
         }
-        nn
+        *nn.metadata_mut() = self.metadata;
+        Ok(nn)
+    }
+}
+// Any layer a NeuralNetwork can hold, the variant is the tag that says which type to rebuild
+#[derive(Serialize, Deserialize, Debug)]
+pub enum SerializableLayer {
+    Dense(SerializableDenseLayer),
+    InputStandardize { num_features: usize, epsilon: f32 },
+    BlendedActivation { num_features: usize, from: ActivationFunction, to: ActivationFunction, t: f32 },
+    Conv2D(SerializableConv2DLayer),
+    MaxPool2D { input_shape: (usize, usize, usize), pool_size: usize, stride: usize },
+    Flatten { input_shape: (usize, usize, usize) },
+}
+
+impl SerializableLayer {
+    // Fails instead of panicking on parameters the layer's constructor would reject
+    pub fn into_layer(self) -> Result<Box<dyn Layer>, Box<dyn std::error::Error>> {
+        Ok(match self {
+            SerializableLayer::Dense(layer) => Box::new(layer.into_dense_layer()?),
+            SerializableLayer::InputStandardize { num_features, epsilon } => {
+                Box::new(InputStandardizeLayer::with_epsilon(num_features, epsilon))
+            }
+            SerializableLayer::BlendedActivation { num_features, from, to, t } => {
+                let mut layer = BlendedActivation::new(num_features, from, to);
+                layer.t = t;
+                Box::new(layer)
+            }
+            SerializableLayer::Conv2D(layer) => Box::new(layer.into_conv2d_layer()?),
+            SerializableLayer::MaxPool2D { input_shape, pool_size, stride } => {
+                let (_, height, width) = input_shape;
+                if pool_size == 0 || stride == 0 {
+                    return Err("MaxPool2D pool_size and stride must be at least 1".into());
+                }
+                if pool_size > height || pool_size > width {
+                    return Err(format!("A {}x{} pool doesn't fit a {}x{} input", pool_size, pool_size, height, width).into());
+                }
+                Box::new(MaxPool2DLayer::new(input_shape, pool_size, stride))
+            }
+            SerializableLayer::Flatten { input_shape } => Box::new(FlattenLayer::new(input_shape)),
+        })
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_network_mixing_layer_types_round_trips() {
+        let mut network = NeuralNetwork::new_seeded(LossFunction::CrossEntropy, 13);
+        network.add_layer(InputStandardizeLayer::new(3));
+        network.add_dense_layer(3, 4, ActivationFunction::ReLU);
+        network.add_layer(BlendedActivation::new(4, ActivationFunction::ReLU, ActivationFunction::Sigmoid));
+        network.add_dense_layer(4, 2, ActivationFunction::Softmax);
+        let inputs = DMatrix::from_fn(5, 3, |r, c| ((r * 3 + c) as f32 * 0.37).sin());
+
+        let loaded = NeuralNetwork::from_bytes(&network.to_bytes().unwrap(), LossFunction::CrossEntropy).unwrap();
+        let layer_types: Vec<_> = loaded.get_layers().iter().map(|layer| layer.layer_type()).collect();
+        assert_eq!(layer_types, network.get_layers().iter().map(|layer| layer.layer_type()).collect::<Vec<_>>());
+        assert_eq!(loaded.infer(&inputs), network.infer(&inputs));
+    }
+
+    #[test]
+    fn malformed_layers_fail_to_load_instead_of_panicking() {
+        let with_layer = |layer: SerializableLayer| bincode::serialize(&SerializableNeuralNetwork {
+            layers: vec![layer],
+            loss_fn: LossFunction::MeanSquaredError,
+            metadata: ModelMetadata::default(),
+        }).unwrap();
+        let dense = |weights_data: Vec<f32>, biases_data: Vec<f32>| SerializableLayer::Dense(SerializableDenseLayer {
+            weights_data,
+            weights_rows: 2,
+            weights_cols: 2,
+            biases_data,
+            activation_fn: ActivationFunction::Linear,
+        });
+
+        assert!(NeuralNetwork::from_bytes(&with_layer(dense(vec![1.0; 4], vec![0.0; 2])), LossFunction::MeanSquaredError).is_ok());
+        assert!(NeuralNetwork::from_bytes(&with_layer(dense(vec![1.0; 3], vec![0.0; 2])), LossFunction::MeanSquaredError).is_err());
+        assert!(NeuralNetwork::from_bytes(&with_layer(dense(vec![1.0; 4], vec![0.0; 3])), LossFunction::MeanSquaredError).is_err());
+        let oversized_pool = SerializableLayer::MaxPool2D { input_shape: (1, 2, 2), pool_size: 3, stride: 1 };
+        assert!(NeuralNetwork::from_bytes(&with_layer(oversized_pool), LossFunction::MeanSquaredError).is_err());
+    }
+
+    fn scratch_path(name: &str) -> String {
+        std::env::temp_dir().join(format!("genius-hour-{}-{}", name, std::process::id())).to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn a_saved_model_loads_back_with_its_loss_function_and_metadata() {
+        let mut network = NeuralNetwork::new_seeded(LossFunction::CrossEntropy, 15);
+        network.add_dense_layer(3, 2, ActivationFunction::Softmax);
+        network.metadata_mut().extra.insert("dataset".to_string(), "mnist".to_string());
+        let path = scratch_path("loss-fn.bin");
+        network.save_weights(&path).unwrap();
+        let loaded = NeuralNetwork::load(&path);
+        let reloaded_with_mse = NeuralNetwork::load_weights(&path, LossFunction::MeanSquaredError);
+        std::fs::remove_file(&path).unwrap();
+
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.get_loss_fn(), LossFunction::CrossEntropy);
+        assert_eq!(loaded.metadata().extra.get("dataset").map(String::as_str), Some("mnist"));
+        assert!(loaded.metadata().created_at.is_some());
+        assert_eq!(reloaded_with_mse.unwrap().get_loss_fn(), LossFunction::MeanSquaredError);
+    }
+
+    #[test]
+    fn json_round_trip_is_lossless() {
+        let mut network = NeuralNetwork::new_seeded(LossFunction::CrossEntropy, 16);
+        network.add_dense_layer(3, 4, ActivationFunction::ELU { alpha: 0.5 });
+        network.add_dense_layer(4, 2, ActivationFunction::Softmax);
+        let path = scratch_path("round-trip.json");
+        network.save_json(&path).unwrap();
+        let json = std::fs::read_to_string(&path).unwrap();
+        let loaded = NeuralNetwork::load_json(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let loaded = loaded.unwrap();
+        let inputs = DMatrix::from_fn(5, 3, |r, c| ((r * 3 + c) as f32 * 0.37).sin());
+        assert_eq!(loaded.snapshot(), network.snapshot());
+        assert_eq!(loaded.infer(&inputs), network.infer(&inputs));
+        // Nested rows: the first layer's weights are 3 arrays of 4
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let weights = value["layers"][0]["Dense"]["weights"].as_array().unwrap();
+        assert_eq!(weights.len(), 3);
+        assert!(weights.iter().all(|row| row.as_array().unwrap().len() == 4));
+    }
+}
@@ -0,0 +1,120 @@
+use nalgebra::DMatrix;
+use crate::layer::{Layer, LayerGradients};
+use crate::serialization::SerializableLayer;
+
+// Standardizes every feature using the statistics of the current batch: (x - batch mean) / batch std.
+// Unlike batchnorm there are no learnable parameters and no running averages, the same
+// computation is done in training and inference (so a batch of 1 outputs all zeros).
+pub struct InputStandardizeLayer {
+    num_features: usize,
+    epsilon: f32, // Added to the variance so constant features don't divide by zero
+
+    // Cache for backpropagation
+    normalized_cache: DMatrix<f32>, // Standardized input of the last forward pass
+    inv_std_cache: Vec<f32>,        // 1 / sqrt(variance + epsilon) per feature
+}
+
+impl InputStandardizeLayer {
+    pub fn new(num_features: usize) -> Self {
+        Self::with_epsilon(num_features, 1e-5)
+    }
+
+    pub fn with_epsilon(num_features: usize, epsilon: f32) -> Self {
+        InputStandardizeLayer {
+            num_features,
+            epsilon,
+            normalized_cache: DMatrix::zeros(0, 0),
+            inv_std_cache: Vec::new(),
+        }
+    }
+
+    // Returns the standardized input and 1/std of every feature
+    fn standardize(&self, input: &DMatrix<f32>) -> (DMatrix<f32>, Vec<f32>) {
+        assert_eq!(input.ncols(), self.num_features, "InputStandardizeLayer expects {} features, got {}", self.num_features, input.ncols());
+        let mut normalized = input.clone();
+        let mut inv_stds = Vec::with_capacity(self.num_features);
+        if input.nrows() == 0 {
+            return (normalized, vec![0.0; self.num_features]);
+        }
+        for mut column in normalized.column_iter_mut() {
+            let mean = column.mean();
+            let variance = column.iter().map(|x| (x - mean) * (x - mean)).sum::<f32>() / column.len() as f32;
+            let inv_std = 1.0 / (variance + self.epsilon).sqrt();
+            column.apply(|x| *x = (*x - mean) * inv_std);
+            inv_stds.push(inv_std);
+        }
+        (normalized, inv_stds)
+    }
+}
+
+impl Layer for InputStandardizeLayer {
+    fn forward(&mut self, input: &DMatrix<f32>) -> DMatrix<f32> {
+        let (normalized, inv_stds) = self.standardize(input);
+        self.normalized_cache = normalized.clone();
+        self.inv_std_cache = inv_stds;
+        normalized
+    }
+
+    fn infer(&self, input: &DMatrix<f32>) -> DMatrix<f32> {
+        self.standardize(input).0
+    }
+
+    // The mean and std depend on every sample of the batch, so per feature:
+    // dx = inv_std * (g - mean(g) - x_hat * mean(g * x_hat))
+    fn compute_gradients(&self, gradient_wrt_output: &DMatrix<f32>) -> (LayerGradients<f32>, DMatrix<f32>) {
+        assert_eq!(gradient_wrt_output.shape(), self.normalized_cache.shape(), "BACKWARD: gradient shape must match the cached forward pass");
+        let mut gradient_wrt_input = gradient_wrt_output.clone();
+        if gradient_wrt_input.nrows() == 0 {
+            return (LayerGradients::empty(), gradient_wrt_input);
+        }
+        for (j, mut column) in gradient_wrt_input.column_iter_mut().enumerate() {
+            let normalized = self.normalized_cache.column(j);
+            let mean_gradient = column.mean();
+            let mean_gradient_x_hat = column.dot(&normalized) / column.len() as f32;
+            let inv_std = self.inv_std_cache[j];
+            for (g, &x_hat) in column.iter_mut().zip(normalized.iter()) {
+                *g = inv_std * (*g - mean_gradient - x_hat * mean_gradient_x_hat);
+            }
+        }
+        (LayerGradients::empty(), gradient_wrt_input)
+    }
+
+    fn clear_cache(&mut self) {
+        self.normalized_cache = DMatrix::zeros(0, 0);
+        self.inv_std_cache = Vec::new();
+    }
+
+    fn input_size(&self) -> usize {
+        self.num_features
+    }
+
+    fn output_size(&self) -> usize {
+        self.num_features
+    }
+
+    fn layer_type(&self) -> &'static str {
+        "input_standardize"
+    }
+
+    fn to_serializable(&self) -> SerializableLayer {
+        SerializableLayer::InputStandardize { num_features: self.num_features, epsilon: self.epsilon }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_feature_gets_zero_mean_and_unit_variance() {
+        let mut layer = InputStandardizeLayer::new(3);
+        let input = DMatrix::from_fn(16, 3, |r, c| (r as f32 * (c as f32 + 1.0)).sin() * 10.0 + c as f32 * 100.0);
+        let output = layer.forward(&input);
+        for column in output.column_iter() {
+            let mean = column.mean();
+            let variance = column.iter().map(|x| (x - mean) * (x - mean)).sum::<f32>() / column.len() as f32;
+            assert!(mean.abs() < 1e-4, "Mean {}", mean);
+            assert!((variance - 1.0).abs() < 1e-3, "Variance {}", variance);
+        }
+    }
+}
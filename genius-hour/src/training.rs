@@ -0,0 +1,754 @@
+// Training loop (NeuralNetwork::fit) plus helpers for monitoring and steering the training process
+
+use nalgebra::{DMatrix, DVector};
+use rand::seq::SliceRandom;
+use serde::{Serialize, Deserialize};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use crate::config::NetworkConfig;
+use crate::data::select_rows;
+use crate::layer::LayerGradients;
+use crate::metrics::accuracy;
+use crate::network::{NeuralNetwork, PhaseTimings};
+use crate::rng::StableRng;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TrainingConfig {
+    pub epochs: usize,
+    // Ignored when the network has a learning rate schedule, see NeuralNetwork::set_lr_schedule
+    pub learning_rate: f32,
+    pub batch_size: usize,
+    // Record forward/backward/update timings for every epoch.
+    // Uses std::time::Instant, which isn't available on wasm32-unknown-unknown.
+    pub profile: bool,
+    // Only train on the first N samples (still reshuffled every epoch), for quick smoke tests
+    pub subset_size: Option<usize>,
+    // Stochastic Weight Averaging: average the weights at the end of each of the last N epochs
+    // and install that average once training is done
+    pub swa_epochs: Option<usize>,
+    // Write a TrainingLog (JSON) to this path, rewritten after every epoch
+    pub metrics_output: Option<PathBuf>,
+    // Seed of the data shuffling, makes the batch order reproducible. To resume an interrupted run
+    // bit-exactly, pass the previous run's TrainingHistory::rng_state here. None uses the thread RNG.
+    pub seed: Option<u64>,
+    // Curriculum learning: instead of shuffling, every epoch presents the samples from lowest to highest
+    // loss under the current weights (easy ones first)
+    pub curriculum: bool,
+    // Record how often each parameter's gradient changes sign between consecutive steps, see
+    // TrainingHistory::sign_flip_rates. Batches are not profiled while this is on.
+    pub track_sign_flips: bool,
+}
+
+impl TrainingConfig {
+    pub fn new(epochs: usize, learning_rate: f32, batch_size: usize) -> Self {
+        TrainingConfig {
+            epochs,
+            learning_rate,
+            batch_size,
+            profile: false,
+            subset_size: None,
+            swa_epochs: None,
+            metrics_output: None,
+            seed: None,
+            curriculum: false,
+            track_sign_flips: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TrainingHistory {
+    pub epoch_losses: Vec<f32>,           // Average batch loss of each epoch
+    pub epoch_timings: Vec<EpochTimings>, // One entry per epoch, only filled when profiling
+    pub validation_losses: Vec<f32>,      // One entry per epoch, only filled when validation data is given
+    pub validation_accuracies: Vec<f32>,  // Same, argmax accuracy against one-hot targets
+    pub rng_state: Option<u64>,           // Shuffling RNG state after the last epoch, only when TrainingConfig::seed was set
+    // Per layer, the fraction of (parameter, consecutive step pair) where the gradient sign flipped,
+    // over the whole run. ~0.5 means the updates are mostly noise, low values mean a consistent descent direction.
+    // Only filled when TrainingConfig::track_sign_flips is set.
+    pub sign_flip_rates: Vec<f32>,
+    // (step, probe set loss) every probe_interval training steps (batches, counted from 1 over the whole run),
+    // only filled by fit_with_probe
+    pub probe_losses: Vec<(usize, f32)>,
+    // Why the last write of TrainingConfig::metrics_output failed, None when the file is up to date
+    pub metrics_output_error: Option<String>,
+}
+
+// Everything about a training run, as written to TrainingConfig::metrics_output
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TrainingLog {
+    pub architecture: Option<NetworkConfig>, // None for networks with layers a NetworkConfig can't describe
+    pub hyperparameters: TrainingConfig,
+    pub epochs: Vec<EpochMetrics>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EpochMetrics {
+    pub epoch: usize, // Starting at 1
+    pub train_loss: f32,
+    pub train_accuracy: f32,
+    pub validation_loss: Option<f32>,
+    pub validation_accuracy: Option<f32>,
+    pub learning_rate: f32,
+    pub duration_secs: f64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct EpochTimings {
+    pub phases: PhaseTimings, // Summed over all batches of the epoch
+    pub total: Duration,      // Wall clock time of the whole epoch, including batch preparation
+}
+
+impl NeuralNetwork {
+    // Mini-batch gradient descent over the whole dataset, reshuffled every epoch.
+    // inputs and targets hold one sample per row.
+    pub fn fit(&mut self, inputs: &DMatrix<f32>, targets: &DMatrix<f32>, config: &TrainingConfig) -> TrainingHistory {
+        self.fit_with_validation(inputs, targets, None, config)
+    }
+
+    // Same as fit, but also evaluates loss and accuracy on (validation_inputs, validation_targets) after every epoch
+    pub fn fit_with_validation(
+        &mut self,
+        inputs: &DMatrix<f32>,
+        targets: &DMatrix<f32>,
+        validation: Option<(&DMatrix<f32>, &DMatrix<f32>)>,
+        config: &TrainingConfig,
+    ) -> TrainingHistory {
+        self.fit_monitored(inputs, targets, validation, None, config)
+    }
+
+    // Same as fit, but evaluates on a small fixed probe set (probe_inputs, probe_targets) every probe_interval
+    // training steps, see TrainingHistory::probe_losses. Gives a loss curve with a much finer resolution
+    // than one point per epoch, e.g. to see what happens within an epoch.
+    pub fn fit_with_probe(
+        &mut self,
+        inputs: &DMatrix<f32>,
+        targets: &DMatrix<f32>,
+        probe: (&DMatrix<f32>, &DMatrix<f32>),
+        probe_interval: usize,
+        config: &TrainingConfig,
+    ) -> TrainingHistory {
+        assert!(probe_interval > 0, "probe_interval must be at least 1");
+        self.fit_monitored(inputs, targets, None, Some((probe.0, probe.1, probe_interval)), config)
+    }
+
+    fn fit_monitored(
+        &mut self,
+        inputs: &DMatrix<f32>,
+        targets: &DMatrix<f32>,
+        validation: Option<(&DMatrix<f32>, &DMatrix<f32>)>,
+        probe: Option<(&DMatrix<f32>, &DMatrix<f32>, usize)>,
+        config: &TrainingConfig,
+    ) -> TrainingHistory {
+        assert_eq!(inputs.nrows(), targets.nrows(), "Inputs ({}) and targets ({}) must have the same number of samples", inputs.nrows(), targets.nrows());
+
+        let mut history = TrainingHistory::default();
+        let num_samples = config.subset_size.map_or(inputs.nrows(), |subset_size| subset_size.min(inputs.nrows()));
+        let mut indices: Vec<usize> = Vec::with_capacity(num_samples);
+        let mut seeded_rng = config.seed.map(StableRng::new);
+        let mut thread_rng = rand::rng();
+        let swa_start_epoch = config.swa_epochs.map(|swa_epochs| config.epochs.saturating_sub(swa_epochs));
+        let mut swa_average: Option<WeightAverage> = None;
+        let mut sign_flips = config.track_sign_flips.then(|| SignFlipTracker::new(self.get_layers().len()));
+        let mut num_steps: usize = 0;
+        let mut training_log = config.metrics_output.as_ref().map(|_| TrainingLog {
+            architecture: NetworkConfig::try_from(&*self).ok(),
+            hyperparameters: config.clone(),
+            epochs: Vec::new(),
+        });
+
+        for epoch in 0..config.epochs {
+            let epoch_start = (config.profile || training_log.is_some()).then(Instant::now);
+            let mut phases = PhaseTimings::default();
+            // Every epoch starts from the same order, so the RNG state is all that's needed to resume
+            if config.curriculum {
+                indices = self.curriculum_order(&inputs.rows(0, num_samples).into_owned(), &targets.rows(0, num_samples).into_owned());
+            } else {
+                indices.clear();
+                indices.extend(0..num_samples);
+                match seeded_rng.as_mut() {
+                    Some(rng) => rng.shuffle(&mut indices),
+                    None => indices.shuffle(&mut thread_rng),
+                }
+            }
+
+            self.set_lr_step(epoch);
+            let learning_rate = self.current_lr(epoch).unwrap_or(config.learning_rate);
+            let mut epoch_loss = 0.0;
+            let mut num_batches_processed = 0;
+            for batch_indices in indices.chunks(config.batch_size.max(1)) {
+                let batch_inputs = select_rows(inputs, batch_indices);
+                let batch_targets = select_rows(targets, batch_indices);
+                let batch_loss = if let Some(tracker) = sign_flips.as_mut() {
+                    let (loss, gradients) = self.compute_gradients(&batch_inputs, &batch_targets);
+                    tracker.observe(&gradients);
+                    self.apply_gradients(&gradients, learning_rate);
+                    loss
+                } else if config.profile {
+                    let (loss, batch_phases) = self.train_batch_profiled(&batch_inputs, &batch_targets, learning_rate);
+                    phases += batch_phases;
+                    loss
+                } else {
+                    self.train_batch(&batch_inputs, &batch_targets, learning_rate)
+                };
+                epoch_loss += batch_loss;
+                num_batches_processed += 1;
+                num_steps += 1;
+                if let Some((probe_inputs, probe_targets, _)) = probe.filter(|&(_, _, interval)| num_steps.is_multiple_of(interval)) {
+                    history.probe_losses.push((num_steps, self.evaluate(probe_inputs, probe_targets).loss));
+                }
+            }
+
+            let avg_epoch_loss = if num_batches_processed > 0 { epoch_loss / num_batches_processed as f32 } else { 0.0 };
+            history.epoch_losses.push(avg_epoch_loss);
+            let epoch_duration = epoch_start.map(|start| start.elapsed());
+            if config.profile {
+                history.epoch_timings.push(EpochTimings { phases, total: epoch_duration.unwrap_or_default() });
+            }
+
+            if let Some((validation_inputs, validation_targets)) = validation {
+                let validation_predictions = self.infer(validation_inputs);
+                history.validation_losses.push(self.get_loss_fn().calculate(&validation_predictions, validation_targets));
+                history.validation_accuracies.push(accuracy(self, validation_inputs, validation_targets));
+            }
+
+            if let (Some(log), Some(path)) = (training_log.as_mut(), config.metrics_output.as_ref()) {
+                log.epochs.push(EpochMetrics {
+                    epoch: epoch + 1,
+                    train_loss: avg_epoch_loss,
+                    train_accuracy: accuracy(self, &select_rows(inputs, &indices), &select_rows(targets, &indices)),
+                    validation_loss: history.validation_losses.last().copied(),
+                    validation_accuracy: history.validation_accuracies.last().copied(),
+                    learning_rate,
+                    duration_secs: epoch_duration.unwrap_or_default().as_secs_f64(),
+                });
+                // Rewritten every epoch so the file is usable while training is still running.
+                // A failed write shouldn't throw away the training, so it's only recorded in the history.
+                history.metrics_output_error = write_training_log(log, path).err()
+                    .map(|e| format!("Failed to write training metrics to {}: {}", path.display(), e));
+            }
+
+            if swa_start_epoch.is_some_and(|start_epoch| epoch >= start_epoch) {
+                swa_average.get_or_insert_with(WeightAverage::new).add(self);
+            }
+        }
+
+        // There are no batchnorm layers, so the averaged weights can be installed as is
+        if let Some(average) = swa_average {
+            average.install(self);
+        }
+        history.rng_state = seeded_rng.map(|rng| rng.state());
+        if let Some(tracker) = sign_flips {
+            history.sign_flip_rates = tracker.rates();
+        }
+        history
+    }
+
+    // Sample indices from easiest to hardest, i.e. by increasing loss under the current weights
+    fn curriculum_order(&self, inputs: &DMatrix<f32>, targets: &DMatrix<f32>) -> Vec<usize> {
+        let sample_losses = self.per_sample_losses(inputs, targets);
+        let mut indices: Vec<usize> = (0..sample_losses.len()).collect();
+        indices.sort_by(|&a, &b| sample_losses[a].total_cmp(&sample_losses[b]));
+        indices
+    }
+
+    // Sets the last layer's biases to log(class frequency), so before any training the Softmax output
+    // for a zero input matches the class prior instead of being uniform. labels_raw holds one class index per row.
+    pub fn init_output_bias_from_labels(&mut self, labels_raw: &DMatrix<f32>, num_classes: usize) {
+        let output_layer = self.get_layers_mut().last_mut().expect("Network has no layers");
+        assert_eq!(output_layer.output_size(), num_classes, "Output layer has {} neurons but num_classes is {}", output_layer.output_size(), num_classes);
+        if labels_raw.nrows() == 0 {
+            return;
+        }
+
+        let mut class_counts = vec![0usize; num_classes];
+        for &label in labels_raw.column(0).iter() {
+            class_counts[label as usize] += 1;
+        }
+        let num_labels = labels_raw.nrows() as f32;
+        // Classes that never appear get a tiny frequency instead of log(0) = -inf
+        let (_, biases) = output_layer.parameters_mut().expect("The output layer has no biases");
+        *biases = DVector::from_iterator(num_classes, class_counts.iter()
+            .map(|&count| (count as f32 / num_labels).max(f32::EPSILON).ln()));
+    }
+
+    // Times a few forward + backward passes at every candidate batch size and returns the one with the highest
+    // samples per second on this machine. The batches are copies of sample_input (one row) with the
+    // network's own output as targets. No update is applied and the passes run in eval mode, so the weights,
+    // the optimizer state and the stochastic depth RNG are left as they were.
+    pub fn find_best_batch_size(&mut self, sample_input: &DMatrix<f32>, candidates: &[usize]) -> usize {
+        const TIMED_STEPS: usize = 3;
+        assert!(!candidates.is_empty(), "find_best_batch_size needs at least one candidate");
+
+        let training = self.is_training();
+        self.set_training(false);
+        let sample_target = self.infer(sample_input);
+
+        let mut best = (candidates[0], 0.0);
+        for &candidate in candidates {
+            let batch_size = candidate.max(1);
+            let batch_inputs = select_rows(sample_input, &vec![0; batch_size]);
+            let batch_targets = select_rows(&sample_target, &vec![0; batch_size]);
+
+            // One untimed warm-up pass so allocation of the caches isn't measured
+            self.compute_gradients(&batch_inputs, &batch_targets);
+            let start = Instant::now();
+            for _ in 0..TIMED_STEPS {
+                self.compute_gradients(&batch_inputs, &batch_targets);
+            }
+            let samples_per_second = (TIMED_STEPS * batch_size) as f64 / start.elapsed().as_secs_f64();
+            if samples_per_second > best.1 {
+                best = (candidate, samples_per_second);
+            }
+        }
+        self.set_training(training);
+        best.0
+    }
+}
+
+// K-fold cross-validation: the (shuffled) samples are split into k folds, and for every fold a fresh
+// network from `build` is trained on the other k-1 folds and scored on the held-out one.
+// Returns the k held-out accuracies (targets are one-hot).
+pub fn cross_validate<F: Fn() -> NeuralNetwork>(
+    build: F,
+    data: &DMatrix<f32>,
+    targets: &DMatrix<f32>,
+    k: usize,
+    config: &TrainingConfig,
+) -> Vec<f32> {
+    assert!(k >= 2, "Cross-validation needs at least 2 folds, got {}", k);
+    assert!(data.nrows() >= k, "Cannot split {} samples into {} folds", data.nrows(), k);
+    assert_eq!(data.nrows(), targets.nrows(), "Data ({}) and targets ({}) must have the same number of samples", data.nrows(), targets.nrows());
+
+    let num_samples = data.nrows();
+    let mut indices: Vec<usize> = (0..num_samples).collect();
+    indices.shuffle(&mut rand::rng());
+
+    (0..k).map(|fold| {
+        let fold_start = fold * num_samples / k;
+        let fold_end = (fold + 1) * num_samples / k;
+        let validation_indices = &indices[fold_start..fold_end];
+        let train_indices: Vec<usize> = indices[..fold_start].iter().chain(indices[fold_end..].iter()).copied().collect();
+
+        let mut network = build();
+        network.fit(&select_rows(data, &train_indices), &select_rows(targets, &train_indices), config);
+        accuracy(&network, &select_rows(data, validation_indices), &select_rows(targets, validation_indices))
+    }).collect()
+}
+
+// Learning curve, to see whether more data would still help: the (shuffled) samples are split into a
+// fixed 20% validation set and a training pool, and for every fraction a fresh network from `build` is
+// trained on that fraction of the pool (via config.subset_size). Returns (fraction, train accuracy,
+// validation accuracy) per fraction, in the given order (targets are one-hot). A validation accuracy that
+// is still rising at 1.0 means more data should help, a flat one with a large train/validation gap means
+// the model overfits regardless.
+pub fn learning_curve<F: Fn() -> NeuralNetwork>(
+    build: F,
+    data: &DMatrix<f32>,
+    targets: &DMatrix<f32>,
+    fractions: &[f32],
+    config: &TrainingConfig,
+) -> Vec<(f32, f32, f32)> {
+    assert_eq!(data.nrows(), targets.nrows(), "Data ({}) and targets ({}) must have the same number of samples", data.nrows(), targets.nrows());
+    assert!(data.nrows() >= 5, "Need at least 5 samples to hold out a validation set, got {}", data.nrows());
+
+    let num_samples = data.nrows();
+    let mut indices: Vec<usize> = (0..num_samples).collect();
+    indices.shuffle(&mut rand::rng());
+    let (validation_indices, pool_indices) = indices.split_at(num_samples / 5);
+    let (validation_data, validation_targets) = (select_rows(data, validation_indices), select_rows(targets, validation_indices));
+    let (pool_data, pool_targets) = (select_rows(data, pool_indices), select_rows(targets, pool_indices));
+
+    fractions.iter().map(|&fraction| {
+        assert!(fraction > 0.0 && fraction <= 1.0, "Fractions must be in (0, 1], got {}", fraction);
+        let subset_size = ((fraction * pool_indices.len() as f32).round() as usize).max(1);
+        let subset_config = TrainingConfig { subset_size: Some(subset_size), ..config.clone() };
+
+        let mut network = build();
+        network.fit(&pool_data, &pool_targets, &subset_config);
+        // fit trains on the first subset_size rows, so that's what the train accuracy is measured on
+        let train_accuracy = accuracy(&network, &pool_data.rows(0, subset_size).into_owned(), &pool_targets.rows(0, subset_size).into_owned());
+        let validation_accuracy = accuracy(&network, &validation_data, &validation_targets);
+        (fraction, train_accuracy, validation_accuracy)
+    }).collect()
+}
+
+fn write_training_log(log: &TrainingLog, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(&mut writer, log)?;
+    // Flushed explicitly, dropping the BufWriter would silently ignore a failed final write
+    writer.flush()?;
+    Ok(())
+}
+
+// Running (equally weighted) average of a network's weights and biases.
+// Layers without parameters get empty (0x0) entries.
+struct WeightAverage {
+    weights: Vec<DMatrix<f32>>,
+    biases: Vec<DVector<f32>>,
+    count: usize,
+}
+
+impl WeightAverage {
+    fn new() -> Self {
+        WeightAverage { weights: Vec::new(), biases: Vec::new(), count: 0 }
+    }
+
+    fn add(&mut self, network: &NeuralNetwork) {
+        self.count += 1;
+        if self.count == 1 {
+            self.weights = network.weight_matrices();
+            self.biases = network.get_layers().iter()
+                .map(|layer| layer.parameters().map_or_else(|| DVector::zeros(0), |(_, biases)| biases.clone()))
+                .collect();
+            return;
+        }
+        // avg_n = avg_(n-1) + (x - avg_(n-1)) / n
+        let n = self.count as f32;
+        for ((layer, avg_weights), avg_biases) in network.get_layers().iter().zip(self.weights.iter_mut()).zip(self.biases.iter_mut()) {
+            if let Some((weights, biases)) = layer.parameters() {
+                let weights_step = (weights - &*avg_weights) / n;
+                let biases_step = (biases - &*avg_biases) / n;
+                *avg_weights += weights_step;
+                *avg_biases += biases_step;
+            }
+        }
+    }
+
+    fn install(self, network: &mut NeuralNetwork) {
+        for ((layer, weights), biases) in network.get_layers_mut().iter_mut().zip(self.weights).zip(self.biases) {
+            if let Some((layer_weights, layer_biases)) = layer.parameters_mut() {
+                *layer_weights = weights;
+                *layer_biases = biases;
+            }
+        }
+    }
+}
+
+// Counts, per layer, how often a parameter's gradient has the opposite sign of the previous step's.
+// Parameters with a zero gradient in either step (e.g. behind a dead ReLU) aren't counted.
+struct SignFlipTracker {
+    previous: Option<Vec<LayerGradients>>,
+    flips: Vec<usize>,
+    comparisons: Vec<usize>,
+}
+
+impl SignFlipTracker {
+    fn new(num_layers: usize) -> Self {
+        SignFlipTracker { previous: None, flips: vec![0; num_layers], comparisons: vec![0; num_layers] }
+    }
+
+    fn observe(&mut self, gradients: &[LayerGradients]) {
+        if let Some(previous) = &self.previous {
+            for (layer_idx, (current, previous)) in gradients.iter().zip(previous.iter()).enumerate() {
+                let current_values = current.weights.iter().chain(current.biases.iter());
+                let previous_values = previous.weights.iter().chain(previous.biases.iter());
+                for (&g, &g_previous) in current_values.zip(previous_values) {
+                    let product = g * g_previous;
+                    if product != 0.0 {
+                        self.comparisons[layer_idx] += 1;
+                        if product < 0.0 {
+                            self.flips[layer_idx] += 1;
+                        }
+                    }
+                }
+            }
+        }
+        self.previous = Some(gradients.to_vec());
+    }
+
+    fn rates(&self) -> Vec<f32> {
+        self.flips.iter().zip(self.comparisons.iter())
+            .map(|(&flips, &comparisons)| if comparisons > 0 { flips as f32 / comparisons as f32 } else { 0.0 })
+            .collect()
+    }
+}
+
+// Slope of a least squares line fitted to the last `k` entries of a per-epoch loss history.
+// Negative means the loss is still going down, ~0 means training has plateaued.
+// Returns 0.0 when there are fewer than 2 points to fit.
+pub fn loss_slope(loss_history: &[f32], k: usize) -> f32 {
+    let window = &loss_history[loss_history.len().saturating_sub(k)..];
+    let n = window.len();
+    if n < 2 {
+        return 0.0;
+    }
+
+    // Epoch indices are 0..n, so their mean is (n - 1) / 2
+    let mean_x = (n - 1) as f32 / 2.0;
+    let mean_y = window.iter().sum::<f32>() / n as f32;
+
+    // slope = sum((x - mean_x) * (y - mean_y)) / sum((x - mean_x)^2)
+    let mut covariance = 0.0;
+    let mut variance_x = 0.0;
+    for (x, &y) in window.iter().enumerate() {
+        let dx = x as f32 - mean_x;
+        covariance += dx * (y - mean_y);
+        variance_x += dx * dx;
+    }
+    covariance / variance_x
+}
+
+// Early stopping decision on a finished validation loss history: the loss plateaued once `patience`
+// epochs in a row failed to improve on the best loss so far by more than min_delta. Returns the index
+// (into val_losses) of the last improving epoch before that, i.e. where training should have stopped,
+// or the last index when the loss never plateaued.
+pub fn suggest_stopping_epoch(val_losses: &[f32], patience: usize, min_delta: f32) -> usize {
+    if val_losses.is_empty() {
+        return 0;
+    }
+    let mut best = (0, val_losses[0]);
+    for (epoch, &loss) in val_losses.iter().enumerate().skip(1) {
+        if loss < best.1 - min_delta {
+            best = (epoch, loss);
+        } else if epoch - best.0 >= patience {
+            return best.0;
+        }
+    }
+    val_losses.len() - 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activation::ActivationFunction;
+    use crate::layer::DenseLayer;
+    use crate::loss::LossFunction;
+
+    // Two-class problem: class 1 when the features sum to more than 0, one-hot targets
+    fn two_class_data(num_samples: usize, num_features: usize) -> (DMatrix<f32>, DMatrix<f32>) {
+        let inputs = DMatrix::from_fn(num_samples, num_features, |r, c| ((r * num_features + c) as f32 * 0.37).sin());
+        let targets = DMatrix::from_fn(num_samples, 2, |r, c| {
+            let positive = inputs.row(r).sum() > 0.0;
+            if positive == (c == 1) { 1.0 } else { 0.0 }
+        });
+        (inputs, targets)
+    }
+
+    fn seeded_mlp(sizes: &[usize], seed: u64) -> NeuralNetwork {
+        let mut network = NeuralNetwork::new_seeded(LossFunction::CrossEntropy, seed);
+        for (i, pair) in sizes.windows(2).enumerate() {
+            let activation_fn = if i + 2 == sizes.len() { ActivationFunction::Softmax } else { ActivationFunction::ReLU };
+            network.add_dense_layer(pair[0], pair[1], activation_fn);
+        }
+        network
+    }
+
+    #[test]
+    fn loss_slope_is_negative_while_improving_and_zero_on_a_plateau() {
+        let decreasing = [1.0, 0.8, 0.6, 0.4, 0.2];
+        assert!((loss_slope(&decreasing, 5) + 0.2).abs() < 1e-6);
+
+        let plateaued = [1.0, 0.5, 0.3, 0.3, 0.3, 0.3];
+        assert!(loss_slope(&plateaued, 4).abs() < 1e-6);
+        assert_eq!(loss_slope(&[0.5], 3), 0.0);
+    }
+
+    #[test]
+    fn profiled_phases_add_up_to_roughly_the_epoch_time() {
+        let (inputs, targets) = two_class_data(512, 64);
+        let mut network = seeded_mlp(&[64, 128, 2], 1);
+        let mut config = TrainingConfig::new(2, 0.01, 32);
+        config.profile = true;
+        let history = network.fit(&inputs, &targets, &config);
+
+        assert_eq!(history.epoch_timings.len(), 2);
+        for timings in history.epoch_timings {
+            let phases = timings.phases.total();
+            assert!(phases <= timings.total, "Phases {:?} took longer than the epoch {:?}", phases, timings.total);
+            // The rest is batch preparation, which is cheap next to the matrix products
+            assert!(phases * 2 >= timings.total, "Phases {:?} are far from the epoch time {:?}", phases, timings.total);
+        }
+    }
+
+    #[test]
+    fn subset_training_only_touches_the_first_samples() {
+        // Feature 0 is only non-zero beyond the first 100 samples, so its weights only
+        // get a gradient if one of those samples is trained on
+        let (mut inputs, targets) = two_class_data(300, 4);
+        for r in 0..300 {
+            inputs[(r, 0)] = if r < 100 { 0.0 } else { 1.0 };
+        }
+        let mut network = seeded_mlp(&[4, 8, 2], 2);
+        let initial_weights = network.dense_layer(0).unwrap().weights.row(0).into_owned();
+        let mut config = TrainingConfig::new(3, 0.1, 16);
+        config.subset_size = Some(100);
+        network.fit(&inputs, &targets, &config);
+        assert_eq!(network.dense_layer(0).unwrap().weights.row(0), initial_weights);
+
+        config.subset_size = None;
+        network.fit(&inputs, &targets, &config);
+        assert_ne!(network.dense_layer(0).unwrap().weights.row(0), initial_weights);
+    }
+
+    #[test]
+    fn swa_installs_the_mean_of_the_last_epochs_weights() {
+        let (inputs, targets) = two_class_data(64, 4);
+        let mut config = TrainingConfig::new(3, 0.1, 16);
+        config.seed = Some(7);
+
+        let mut averaged = seeded_mlp(&[4, 6, 2], 3);
+        config.swa_epochs = Some(2);
+        averaged.fit(&inputs, &targets, &config);
+
+        // The same run one epoch at a time, keeping the weights after epochs 2 and 3
+        let mut network = seeded_mlp(&[4, 6, 2], 3);
+        config.swa_epochs = None;
+        config.epochs = 1;
+        let mut snapshots = Vec::new();
+        for epoch in 0..3 {
+            let history = network.fit(&inputs, &targets, &config);
+            config.seed = history.rng_state;
+            if epoch > 0 {
+                snapshots.push(network.weight_matrices());
+            }
+        }
+        for (i, averaged_weights) in averaged.weight_matrices().iter().enumerate() {
+            let mean = (&snapshots[0][i] + &snapshots[1][i]) / 2.0;
+            assert!((averaged_weights - mean).amax() < 1e-6, "Layer {} isn't the average", i);
+        }
+    }
+
+    #[test]
+    fn output_bias_init_matches_the_class_prior_on_a_zero_input() {
+        let mut network = seeded_mlp(&[3, 5, 3], 4);
+        // 6 of class 0, 3 of class 1, 1 of class 2
+        let labels = DMatrix::from_column_slice(10, 1, &[0.0, 0.0, 1.0, 0.0, 2.0, 0.0, 1.0, 0.0, 1.0, 0.0]);
+        network.init_output_bias_from_labels(&labels, 3);
+        let output = network.infer(&DMatrix::zeros(1, 3));
+        for (probability, expected) in output.iter().zip([0.6, 0.3, 0.1]) {
+            assert!((probability - expected).abs() < 1e-5, "Got {}, expected {}", output, expected);
+        }
+    }
+
+    #[test]
+    fn best_batch_size_is_one_of_the_candidates() {
+        let mut network = seeded_mlp(&[8, 16, 2], 5);
+        let weights_before = network.snapshot();
+        let candidates = [1, 4, 16];
+        let best = network.find_best_batch_size(&DMatrix::from_element(1, 8, 0.5), &candidates);
+        assert!(candidates.contains(&best));
+        assert_eq!(network.snapshot(), weights_before);
+    }
+
+    #[test]
+    fn the_first_curriculum_batch_holds_the_lowest_loss_samples() {
+        let (inputs, targets) = two_class_data(20, 3);
+        let network = seeded_mlp(&[3, 4, 2], 10);
+        let batch_size = 5;
+
+        let order = network.curriculum_order(&inputs, &targets);
+        let losses = network.per_sample_losses(&inputs, &targets);
+        let first_batch_worst = order[..batch_size].iter().map(|&i| losses[i]).fold(f32::NEG_INFINITY, f32::max);
+        assert!(order[batch_size..].iter().all(|&i| losses[i] >= first_batch_worst));
+    }
+
+    #[test]
+    fn three_fold_cross_validation_returns_three_scores() {
+        let (inputs, targets) = two_class_data(30, 3);
+        let scores = cross_validate(|| seeded_mlp(&[3, 4, 2], 6), &inputs, &targets, 3, &TrainingConfig::new(2, 0.1, 8));
+        assert_eq!(scores.len(), 3);
+        assert!(scores.iter().all(|score| (0.0..=1.0).contains(score)));
+    }
+
+    #[test]
+    fn learning_curve_has_one_entry_per_fraction() {
+        let (inputs, targets) = two_class_data(40, 3);
+        let fractions = [0.25, 0.5, 1.0];
+        let curve = learning_curve(|| seeded_mlp(&[3, 4, 2], 7), &inputs, &targets, &fractions, &TrainingConfig::new(2, 0.1, 8));
+        assert_eq!(curve.len(), fractions.len());
+        for (&(fraction, train_accuracy, validation_accuracy), &expected) in curve.iter().zip(&fractions) {
+            assert_eq!(fraction, expected);
+            assert!((0.0..=1.0).contains(&train_accuracy) && (0.0..=1.0).contains(&validation_accuracy));
+        }
+    }
+
+    #[test]
+    fn metrics_log_has_one_entry_per_epoch() {
+        let (inputs, targets) = two_class_data(32, 3);
+        let path = std::env::temp_dir().join(format!("genius-hour-metrics-{}.json", std::process::id()));
+        let mut config = TrainingConfig::new(3, 0.1, 8);
+        config.metrics_output = Some(path.clone());
+        let history = seeded_mlp(&[3, 4, 2], 8).fit(&inputs, &targets, &config);
+        assert_eq!(history.metrics_output_error, None);
+
+        let log: TrainingLog = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(log.hyperparameters, config);
+        assert_eq!(log.architecture.unwrap().layers.len(), 2);
+        assert_eq!(log.epochs.iter().map(|metrics| metrics.epoch).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(log.epochs.iter().map(|metrics| metrics.train_loss).collect::<Vec<_>>(), history.epoch_losses);
+    }
+
+    #[test]
+    fn resuming_from_a_saved_model_and_rng_state_repeats_the_same_losses() {
+        let (inputs, targets) = two_class_data(40, 3);
+        let mut config = TrainingConfig::new(4, 0.1, 8);
+        config.seed = Some(11);
+        let straight = seeded_mlp(&[3, 5, 2], 9).fit(&inputs, &targets, &config);
+
+        config.epochs = 2;
+        let mut network = seeded_mlp(&[3, 5, 2], 9);
+        let first_half = network.fit(&inputs, &targets, &config);
+        let saved = network.to_bytes().unwrap();
+        let mut resumed = NeuralNetwork::from_bytes(&saved, LossFunction::CrossEntropy).unwrap();
+        config.seed = first_half.rng_state;
+        let second_half = resumed.fit(&inputs, &targets, &config);
+
+        assert_eq!([first_half.epoch_losses, second_half.epoch_losses].concat(), straight.epoch_losses);
+        assert_eq!(second_half.rng_state, straight.rng_state);
+    }
+
+    #[test]
+    fn sign_flips_are_rare_when_converging_smoothly_and_common_when_oscillating() {
+        // With every input 1 and a batch of 4, the error is multiplied by 1 - lr / 2 per step: 0.8 at lr 0.4, -0.9 at lr 3.8
+        let inputs = DMatrix::from_element(4, 1, 1.0);
+        let targets = DMatrix::from_element(4, 1, 3.0);
+        let flip_rate = |learning_rate: f32| {
+            let mut network = NeuralNetwork::new(LossFunction::MeanSquaredError);
+            network.add_layer(DenseLayer::from_parameters(DMatrix::zeros(1, 1), DVector::zeros(1), ActivationFunction::Linear));
+            let mut config = TrainingConfig::new(20, learning_rate, 4);
+            config.track_sign_flips = true;
+            network.fit(&inputs, &targets, &config).sign_flip_rates
+        };
+
+        let smooth = flip_rate(0.4);
+        let oscillating = flip_rate(3.8);
+        assert_eq!(smooth.len(), 1);
+        assert!(smooth[0] < 0.1, "Flip rate {} while converging smoothly", smooth[0]);
+        assert!(oscillating[0] > 0.9, "Flip rate {} while oscillating", oscillating[0]);
+    }
+
+    #[test]
+    fn probe_losses_are_recorded_every_interval_steps() {
+        let (inputs, targets) = two_class_data(100, 4);
+        let (probe_inputs, probe_targets) = (inputs.rows(0, 20).into_owned(), targets.rows(0, 20).into_owned());
+        let mut network = seeded_mlp(&[4, 8, 2], 2);
+        let mut config = TrainingConfig::new(3, 0.1, 10);
+        config.seed = Some(3);
+        // 10 steps per epoch, so 30 steps in total
+        let history = network.fit_with_probe(&inputs, &targets, (&probe_inputs, &probe_targets), 4, &config);
+
+        let steps: Vec<usize> = history.probe_losses.iter().map(|&(step, _)| step).collect();
+        assert_eq!(steps, vec![4, 8, 12, 16, 20, 24, 28]);
+        assert!(history.probe_losses.iter().all(|&(_, loss)| loss.is_finite() && loss > 0.0));
+        assert!(history.probe_losses[6].1 < history.probe_losses[0].1, "Probe losses {:?}", history.probe_losses);
+        assert!(network.fit(&inputs, &targets, &config).probe_losses.is_empty());
+    }
+
+    #[test]
+    fn stopping_epoch_is_the_last_improvement_before_the_plateau() {
+        // Best at epoch 3, then 3 epochs without improving
+        let plateaued = [1.0, 0.7, 0.5, 0.4, 0.45, 0.41, 0.42, 0.1];
+        assert_eq!(suggest_stopping_epoch(&plateaued, 3, 0.0), 3);
+        // With more patience the late improvement at epoch 7 counts
+        assert_eq!(suggest_stopping_epoch(&plateaued, 4, 0.0), 7);
+        // Improvements of less than min_delta don't reset the patience
+        assert_eq!(suggest_stopping_epoch(&[1.0, 0.5, 0.49, 0.48, 0.47], 2, 0.05), 1);
+
+        assert_eq!(suggest_stopping_epoch(&[1.0, 0.9, 0.8, 0.7], 2, 0.0), 3);
+        assert_eq!(suggest_stopping_epoch(&[], 2, 0.0), 0);
+    }
+}